@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Duration, Utc};
+use crate::server::helpers;
+
+/*
+ * When the browser sends the cookie along with cross-site requests:
+ *  -- Strict: never
+ *  -- Lax: top-level navigations (links), not cross-site POSTs, frames or fetches
+ *  -- None: always - browsers only accept it together with Secure
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl SameSite {
+    pub fn parse(value: &str) -> Option<SameSite> {
+        match value.trim().to_lowercase().as_str() {
+            "strict" => Some(SameSite::Strict),
+            "lax" => Some(SameSite::Lax),
+            "none" => Some(SameSite::None),
+            _ => None
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        }
+    }
+}
+
+/*
+ * Cookie set by the server - value must consist of cookie-octets (no spaces, quotes, commas, semicolons or backslashes)
+ */
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: String,
+    /* seconds, None => session cookie (gone when the browser closes), 0 => browser drops the cookie */
+    max_age: Option<i64>,
+    http_only: bool,
+    secure: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: String::from("/"),
+            max_age: None,
+            http_only: false,
+            secure: false,
+            same_site: None,
+        }
+    }
+
+    pub fn max_age(mut self, seconds: i64) -> Cookie {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    /* not readable from scripts (document.cookie) */
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    /* only sent over TLS */
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    /*
+     * set-cookie value: {name}={value}; Path=/; Max-Age=..; Expires=..; HttpOnly; Secure; SameSite=..
+     * Expires is sent next to Max-Age for clients that only know the former.
+     */
+    pub fn to_header(&self) -> String {
+        let mut header: String = format!("{}={}; Path={}", self.name, self.value, self.path);
+        if let Some(max_age) = self.max_age {
+            let expires: DateTime<Utc> = if max_age > 0 { Utc::now() + Duration::seconds(max_age) } else { Utc::now() - Duration::days(365) };
+            header.push_str(&format!("; Max-Age={}; Expires={}", max_age.max(0), helpers::http_date(expires)));
+        }
+        if self.http_only {
+            header.push_str("; HttpOnly");
+        }
+        if self.secure {
+            header.push_str("; Secure");
+        }
+        if let Some(same_site) = self.same_site {
+            header.push_str(&format!("; SameSite={}", same_site.as_str()));
+        }
+        header
+    }
+}
+
+/*
+ * cookie request header: "a=1; b=2" => {a: 1, b: 2}
+ * Repeated cookie headers were joined with ", " - commas separate pairs as well (cookie values can't contain them).
+ * First occurrence of a name wins (browsers send the most specific path first), quotes around values are removed.
+ */
+pub fn parse(header: &str) -> HashMap<String, String> {
+    let mut cookies: HashMap<String, String> = HashMap::new();
+    for pair in header.split([';', ',']) {
+        if let Some((name, value)) = pair.split_once('=') {
+            let name: &str = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let value: &str = value.trim();
+            let value: &str = value.strip_prefix('"').and_then(|v: &str| v.strip_suffix('"')).unwrap_or(value);
+            cookies.entry(name.to_string()).or_insert(value.to_string());
+        }
+    }
+    cookies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pairs() {
+        let cookies: HashMap<String, String> = parse("a=1; b=two ;c=\"quoted\"; d=; =nameless; flag");
+        assert_eq!(cookies.get("a").map(String::as_str), Some("1"));
+        assert_eq!(cookies.get("b").map(String::as_str), Some("two"));
+        assert_eq!(cookies.get("c").map(String::as_str), Some("quoted"));
+        assert_eq!(cookies.get("d").map(String::as_str), Some(""));
+        assert_eq!(cookies.len(), 4);
+    }
+
+    #[test]
+    fn first_occurrence_wins_across_joined_headers() {
+        let cookies: HashMap<String, String> = parse("session=specific; theme=dark, session=generic; lang=en");
+        assert_eq!(cookies.get("session").map(String::as_str), Some("specific"));
+        assert_eq!(cookies.get("theme").map(String::as_str), Some("dark"));
+        assert_eq!(cookies.get("lang").map(String::as_str), Some("en"));
+    }
+
+    #[test]
+    fn value_keeps_equal_signs() {
+        assert_eq!(parse("token=abc==").get("token").map(String::as_str), Some("abc=="));
+    }
+
+    #[test]
+    fn builds_set_cookie_header() {
+        let header: String = Cookie::new("session", "abc").http_only(true).secure(true).same_site(SameSite::Lax).to_header();
+        assert_eq!(header, "session=abc; Path=/; HttpOnly; Secure; SameSite=Lax");
+        let removal: String = Cookie::new("session", "").max_age(0).to_header();
+        assert!(removal.starts_with("session=; Path=/; Max-Age=0; Expires="));
+        assert_eq!(SameSite::parse(" STRICT "), Some(SameSite::Strict));
+        assert_eq!(SameSite::parse("sometimes"), None);
+    }
+}
@@ -1,7 +1,9 @@
 use std::{env, path::PathBuf};
+use std::sync::OnceLock;
 
 use local_ip_address::list_afinet_netifas;
 
+use crate::server::cookies::SameSite;
 use crate::server::helpers;
 
 #[derive(Debug)]
@@ -10,6 +12,10 @@ pub struct ServerConfig {
     pub port1: u16,
     pub port2: u16,
     pub request_methods: Vec<String>,
+    /* ports served over TLS (subset of port1 & port2), empty => plain only */
+    pub tls_ports: Vec<u16>,
+    pub tls_cert: String,
+    pub tls_key: String,
 }
 
 pub struct DbConfig {
@@ -28,6 +34,162 @@ pub struct AssetsConfig {
     pub html_base_path: String,
     pub log_dir: String,
     pub log_path: String,
+    pub error_page: String,
+    pub upload_dir: String,
+    /* HTTP access log under log_dir, one line per answered request */
+    pub access_log_path: String,
+    /* common, combined or json */
+    pub access_log_format: String,
+}
+
+/*
+ * Settings of the built-in HTTP middlewares
+ */
+pub struct MiddlewareConfig {
+    /* path prefixes requiring a session or a bearer token from the tokens table, empty => no auth */
+    pub auth_paths: Vec<String>,
+    /* path prefixes only users of admin_role_id may use - they need a session or bearer token as well */
+    pub admin_paths: Vec<String>,
+    pub admin_role_id: u64,
+    /* seconds a request may spend in the sessions, auth & the handler (SERVER.HANDLER_TIMEOUT), 0 => no limit */
+    pub handler_timeout: u64,
+}
+
+/*
+ * SERVER.AUTH_PATHS when it's not set - data, admin & live endpoints are protected unless explicitly opened (SERVER.AUTH_PATHS=)
+ */
+static DEFAULT_AUTH_PATHS: &str = "/users,/tokens,/roles,/connected,/tables,/events,/ws";
+
+/*
+ * SERVER.ADMIN_PATHS & SERVER.ADMIN_ROLE_ID when they're not set - data & admin endpoints are
+ * for the 'Admin' role create_tables() inserts first
+ */
+static DEFAULT_ADMIN_PATHS: &str = "/users,/tokens,/roles,/connected,/tables";
+static DEFAULT_ADMIN_ROLE_ID: u64 = 1;
+
+/*
+ * Cross-origin policy, empty origins => no CORS headers
+ */
+pub struct CorsConfig {
+    /* exact origins ("https://app.example.com") or "*" */
+    pub origins: Vec<String>,
+    /* empty => methods of the requested path */
+    pub methods: Vec<String>,
+    pub headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age: u64,
+}
+
+/*
+ * Security headers sent with every response: SECURITY.{KEY} replaces the default, empty => header is not sent.
+ * Per-route profiles override single headers for a path prefix (longest prefix wins):
+ *   SECURITY.ROUTES=LEGACY
+ *   SECURITY.LEGACY.PATH=/legacy
+ *   SECURITY.LEGACY.CSP="script-src 'self' 'unsafe-inline'"
+ * {nonce} in the CSP is replaced with a fresh 'nonce-..' per request.
+ */
+pub struct SecurityConfig {
+    /* header name => value */
+    pub headers: Vec<(String, String)>,
+    /* path prefix => headers replacing the global ones */
+    pub routes: Vec<(String, Vec<(String, String)>)>,
+}
+
+/*
+ * Key => header => default; strict-transport-security is only sent over TLS
+ */
+static SECURITY_HEADERS: [(&str, &str, &str); 8] = [
+    ("CSP", "content-security-policy", "connect-src 'self'; font-src 'self'; frame-src 'self'; img-src 'self'; manifest-src 'self'; media-src 'self'; object-src 'self'; script-src 'self' {nonce}; style-src 'self' {nonce}; worker-src 'self'"),
+    ("COEP", "cross-origin-embedder-policy", "require-corp"),
+    ("COOP", "cross-origin-opener-policy", "same-origin"),
+    ("CORP", "cross-origin-resource-policy", "same-origin"),
+    ("CONTENT_TYPE_OPTIONS", "x-content-type-options", "nosniff"),
+    ("FRAME_OPTIONS", "x-frame-options", "deny"),
+    ("REFERRER_POLICY", "referrer-policy", "no-referrer"),
+    ("HSTS", "strict-transport-security", ""),
+];
+
+/*
+ * Token buckets per client IP: rate per second (0 => unlimited) & burst
+ */
+pub struct RateLimitConfig {
+    pub http_rate: f64,
+    pub http_burst: f64,
+    pub connection_rate: f64,
+    pub connection_burst: f64,
+    pub tcp_rate: f64,
+    pub tcp_burst: f64,
+    /* TCP client over the message limit: false => messages are dropped with a notice, true => disconnected */
+    pub tcp_disconnect: bool,
+}
+
+/*
+ * Path prefixes forwarded to upstream HTTP servers (longest prefix wins):
+ *   PROXY.ROUTES=API
+ *   PROXY.API.PATH=/api
+ *   PROXY.API.UPSTREAM=http://127.0.0.1:8080/
+ * Upstream URL with a path => the prefix is replaced with it (/api/users => /users),
+ * without => the target is forwarded unchanged.
+ */
+pub struct ProxyConfig {
+    pub routes: Vec<ProxyRoute>,
+    /* seconds to connect to an upstream */
+    pub connect_timeout: u64,
+    /* seconds an upstream may stay silent while sending the response */
+    pub read_timeout: u64,
+}
+
+pub struct ProxyRoute {
+    pub path: String,
+    /* host:port */
+    pub upstream: String,
+    pub upstream_path: Option<String>,
+    /* true => host header of the client, false => upstream host:port */
+    pub preserve_host: bool,
+}
+
+/*
+ * Sites served side by side, picked by the host header:
+ *   VHOST.HOSTS=DOCS
+ *   VHOST.DOCS.NAMES=docs.internal,*.docs.internal
+ *   VHOST.DOCS.HTML_BASE_PATH=/srv/docs/
+ * Unknown hosts get VHOST.DEFAULT or, if it's not set, the APP.* site.
+ */
+pub struct VirtualHostConfig {
+    pub hosts: Vec<VirtualHost>,
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    pub name: String,
+    /* lowercase host names without port, "*.example.com" => any subdomain */
+    pub server_names: Vec<String>,
+    /* document root with static files & views in views/ (trailing slash like APP.HTML_BASE_PATH) */
+    pub html_base_path: String,
+    pub error_page: String,
+    /* path prefixes of the built-in routes served on the host, "/" => all, empty => views & static files only */
+    pub routes: Vec<String>,
+}
+
+/*
+ * Cookie sessions of browsers - kept in memory, with SESSION.STORE=mysql also in the sessions table
+ * so they survive restarts & are shared between instances
+ */
+pub struct SessionConfig {
+    pub cookie_name: String,
+    /* true => sessions table is read & written next to the in-memory store */
+    pub mysql: bool,
+    /* seconds without a request after which the session expires */
+    pub idle_timeout: i64,
+    /* seconds after which the session expires even if it's used */
+    pub lifetime: i64,
+    /* days the "remember me" cookie logs the user back in */
+    pub remember_days: i64,
+    /* Secure attribute on plain HTTP too (TLS terminated by a proxy) - always set on TLS connections */
+    pub secure: bool,
+    pub same_site: SameSite,
 }
 
 pub struct AppConfig {
@@ -45,6 +207,46 @@ fn get_env_path() -> PathBuf {
     env_path
 }
 
+/*
+ * .env is read into the process environment once - without it the variables have to be set in the environment
+ */
+fn load_env() {
+    static LOADED: OnceLock<()> = OnceLock::new();
+    LOADED.get_or_init(|| {
+        if let Err(e) = dotenv::from_path(get_env_path()) {
+            println!("cstmconfig: Error loading env vars from .env: {}", e);
+        }
+    });
+}
+
+/*
+ * Configs needed while serving requests - built on first use (at startup) & shared by all workers
+ */
+pub fn server() -> &'static ServerConfig {
+    static CFG: OnceLock<ServerConfig> = OnceLock::new();
+    CFG.get_or_init(ServerConfig::new_cfg)
+}
+
+pub fn base() -> &'static BaseConfig {
+    static CFG: OnceLock<BaseConfig> = OnceLock::new();
+    CFG.get_or_init(BaseConfig::new_cfg)
+}
+
+pub fn assets() -> &'static AssetsConfig {
+    static CFG: OnceLock<AssetsConfig> = OnceLock::new();
+    CFG.get_or_init(AssetsConfig::new_cfg)
+}
+
+pub fn database() -> &'static DbConfig {
+    static CFG: OnceLock<DbConfig> = OnceLock::new();
+    CFG.get_or_init(DbConfig::new_cfg)
+}
+
+pub fn security() -> &'static SecurityConfig {
+    static CFG: OnceLock<SecurityConfig> = OnceLock::new();
+    CFG.get_or_init(SecurityConfig::new_cfg)
+}
+
 
 #[allow(dead_code)]
 impl AppConfig {
@@ -60,15 +262,7 @@ impl AppConfig {
 
 impl BaseConfig {
     pub fn new_cfg() -> BaseConfig {
-        match dotenv::from_path(get_env_path()).ok() {
-            Some(_envpath) => {},
-            None => {
-                println!("BaseConfig: Error loading env vars: loading default");
-                let _base_cfg : BaseConfig = BaseConfig {
-                    http_protocol: String::from("HTTP/1.1").to_string()
-                };
-            }
-        }
+        load_env();
         let _base_cfg : BaseConfig = BaseConfig {
             http_protocol: dotenv::var("SERVER.HTTP_PROTOCOL").unwrap()
         };
@@ -78,21 +272,15 @@ impl BaseConfig {
 
 impl AssetsConfig {
     pub fn new_cfg() -> AssetsConfig {
-        match dotenv::from_path(get_env_path()).ok() {
-            Some(_envpath) => {},
-            None => {
-                println!("AssetsConfig: Error loading env vars: loading default");
-                let _assets_cfg : AssetsConfig = AssetsConfig {
-                    html_base_path: String::from("src/bin/server/html"),
-                    log_dir: String::from("log"),
-                    log_path: String::from("logs.log"),
-                };
-            }
-        }
+        load_env();
         let _assets_cfg : AssetsConfig = AssetsConfig {
             html_base_path: dotenv::var("APP.HTML_BASE_PATH").unwrap(),
             log_dir: dotenv::var("APP.LOG_DIR").unwrap(),
             log_path: dotenv::var("APP.LOG_PATH").unwrap(),
+            error_page: dotenv::var("APP.ERROR_PAGE").unwrap_or(String::from("error.html")),
+            upload_dir: dotenv::var("APP.UPLOAD_DIR").unwrap_or(String::from("uploads")),
+            access_log_path: dotenv::var("APP.ACCESS_LOG_PATH").unwrap_or(String::from("access.log")),
+            access_log_format: dotenv::var("APP.ACCESS_LOG_FORMAT").unwrap_or(String::from("combined")).to_lowercase(),
         };
         _assets_cfg
     }
@@ -102,20 +290,7 @@ impl ServerConfig {
     pub fn new_cfg() -> ServerConfig {
         let network_interfaces: Result<Vec<(String, std::net::IpAddr)>, local_ip_address::Error> = list_afinet_netifas();
         let mut host_ip: String = String::new();
-        match dotenv::from_path(get_env_path()).ok() {
-            Some(_envpath) => {},
-            None => {
-                println!("ServerConfig: Error loading env vars - loading default");
-                let request_methods: String = String::from("GET,POST,OPTIONS,HEAD");
-                let req_meth: Vec<String> = request_methods.split(",").map(str::to_string).collect();
-                let _server_cfg : ServerConfig = ServerConfig {
-                    host: String::from("127.0.0.1").to_string(),
-                    port1: 31500_u16,
-                    port2: 31501_u16,
-                    request_methods: req_meth
-                };
-            }
-        }
+        load_env();
         if let Ok(network_interfaces) = network_interfaces {
             for (name, ip) in network_interfaces.iter() {
                 if name == "enp0s3" || name == "eth0" {
@@ -135,12 +310,18 @@ impl ServerConfig {
         let req_meth: Vec<String> = request_methods.split(",")
                                       .map(str::to_string)
                                       .collect();
+        let tls_ports: String = dotenv::var("SERVER.TLS_PORTS").unwrap_or_default();
         // host: dotenv::var("SERVER.HOST").unwrap()
         let _server_cfg: ServerConfig = ServerConfig {
             host: host_ip,
-            port1: port1,
-            port2: port2,
-            request_methods: req_meth
+            port1,
+            port2,
+            request_methods: req_meth,
+            tls_ports: tls_ports.split(",")
+                                .filter_map(|port: &str| port.trim().parse::<u16>().ok())
+                                .collect(),
+            tls_cert: dotenv::var("SERVER.TLS_CERT").unwrap_or_default(),
+            tls_key: dotenv::var("SERVER.TLS_KEY").unwrap_or_default(),
         };
         _server_cfg
     }
@@ -148,19 +329,7 @@ impl ServerConfig {
 
 impl DbConfig {
     pub fn new_cfg() -> DbConfig {
-        match dotenv::from_path(get_env_path()).ok() {
-            Some(_envpath) => {},
-            None => {
-                println!("DbConfig: Error loading env vars: loading default");
-                let _db_cfg : DbConfig = DbConfig {
-                    host: String::from("127.0.0.1").to_string(),
-                    port: 3306_u16,
-                    user: String::from("kubeuser").to_string(),
-                    password: String::from("kubepass").to_string(),
-                    database: String::from("basic").to_string(),
-                };
-            }
-        }
+        load_env();
         let db_port_str : String = dotenv::var("DATABASE.PORT").unwrap();
         let db_port : u16 = db_port_str.trim().parse::<u16>().unwrap();
         let _db_cfg : DbConfig = DbConfig {
@@ -172,4 +341,238 @@ impl DbConfig {
         };
         _db_cfg
     }
-}
\ No newline at end of file
+}
+impl MiddlewareConfig {
+    pub fn new_cfg() -> MiddlewareConfig {
+        load_env();
+        let auth_paths: String = dotenv::var("SERVER.AUTH_PATHS").unwrap_or(String::from(DEFAULT_AUTH_PATHS));
+        let admin_paths: String = dotenv::var("SERVER.ADMIN_PATHS").unwrap_or(String::from(DEFAULT_ADMIN_PATHS));
+        let _middleware_cfg: MiddlewareConfig = MiddlewareConfig {
+            auth_paths: auth_paths.split(",")
+                                  .map(|path: &str| path.trim().to_string())
+                                  .filter(|path: &String| !path.is_empty())
+                                  .collect(),
+            admin_paths: admin_paths.split(",")
+                                    .map(|path: &str| path.trim().to_string())
+                                    .filter(|path: &String| !path.is_empty())
+                                    .collect(),
+            admin_role_id: dotenv::var("SERVER.ADMIN_ROLE_ID").ok()
+                                .and_then(|role_id: String| role_id.trim().parse::<u64>().ok())
+                                .unwrap_or(DEFAULT_ADMIN_ROLE_ID),
+            handler_timeout: dotenv::var("SERVER.HANDLER_TIMEOUT").ok()
+                                .and_then(|timeout: String| timeout.trim().parse::<u64>().ok())
+                                .unwrap_or(30),
+        };
+        _middleware_cfg
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(",")
+        .map(|item: &str| item.trim().to_string())
+        .filter(|item: &String| !item.is_empty())
+        .collect()
+}
+
+impl CorsConfig {
+    pub fn new_cfg() -> CorsConfig {
+        load_env();
+        let _cors_cfg: CorsConfig = CorsConfig {
+            origins: split_list(&dotenv::var("SERVER.CORS_ORIGINS").unwrap_or_default()),
+            methods: split_list(&dotenv::var("SERVER.CORS_METHODS").unwrap_or_default().to_uppercase()),
+            headers: split_list(&dotenv::var("SERVER.CORS_HEADERS")
+                                    .unwrap_or(String::from("content-type,authorization,x-request-id")).to_lowercase()),
+            expose_headers: split_list(&dotenv::var("SERVER.CORS_EXPOSE_HEADERS").unwrap_or_default().to_lowercase()),
+            credentials: dotenv::var("SERVER.CORS_CREDENTIALS").is_ok_and(|credentials: String| credentials.trim() == "true"),
+            max_age: dotenv::var("SERVER.CORS_MAX_AGE").ok()
+                        .and_then(|max_age: String| max_age.trim().parse::<u64>().ok())
+                        .unwrap_or(600),
+        };
+        _cors_cfg
+    }
+}
+
+impl SecurityConfig {
+    pub fn new_cfg() -> SecurityConfig {
+        load_env();
+        let headers: Vec<(String, String)> = SECURITY_HEADERS.iter()
+            .map(|(key, header, default): &(&str, &str, &str)| {
+                (header.to_string(), dotenv::var(format!("SECURITY.{}", key)).unwrap_or(default.to_string()).trim().to_string())
+            })
+            .collect();
+        let routes: Vec<(String, Vec<(String, String)>)> = split_list(&dotenv::var("SECURITY.ROUTES").unwrap_or_default())
+            .iter()
+            .filter_map(|name: &String| {
+                let path: String = match dotenv::var(format!("SECURITY.{}.PATH", name)) {
+                    Ok(path) if !path.trim().is_empty() => path.trim().to_string(),
+                    _ => {
+                        println!("SecurityConfig: SECURITY.{}.PATH is not set - ignoring route", name);
+                        return None;
+                    }
+                };
+                let overrides: Vec<(String, String)> = SECURITY_HEADERS.iter()
+                    .filter_map(|(key, header, _): &(&str, &str, &str)| {
+                        dotenv::var(format!("SECURITY.{}.{}", name, key)).ok()
+                            .map(|value: String| (header.to_string(), value.trim().to_string()))
+                    })
+                    .collect();
+                Some((path, overrides))
+            })
+            .collect();
+        let _security_cfg: SecurityConfig = SecurityConfig { headers, routes };
+        _security_cfg
+    }
+}
+
+fn parse_rate(key: &str, default: f64) -> f64 {
+    dotenv::var(key).ok()
+        .and_then(|value: String| value.trim().parse::<f64>().ok())
+        .filter(|value: &f64| *value >= 0.0)
+        .unwrap_or(default)
+}
+
+impl RateLimitConfig {
+    pub fn new_cfg() -> RateLimitConfig {
+        load_env();
+        let _ratelimit_cfg: RateLimitConfig = RateLimitConfig {
+            http_rate: parse_rate("RATELIMIT.HTTP_RATE", 20.0),
+            http_burst: parse_rate("RATELIMIT.HTTP_BURST", 40.0),
+            connection_rate: parse_rate("RATELIMIT.CONNECTION_RATE", 5.0),
+            connection_burst: parse_rate("RATELIMIT.CONNECTION_BURST", 20.0),
+            tcp_rate: parse_rate("RATELIMIT.TCP_RATE", 10.0),
+            tcp_burst: parse_rate("RATELIMIT.TCP_BURST", 20.0),
+            tcp_disconnect: dotenv::var("RATELIMIT.TCP_ACTION").is_ok_and(|action: String| action.trim() == "disconnect"),
+        };
+        _ratelimit_cfg
+    }
+}
+
+/*
+ * http://host[:port][/path] => (host:port, path)
+ */
+fn parse_upstream(url: &str) -> Option<(String, Option<String>)> {
+    let url: &str = url.trim();
+    let rest: &str = match url.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("http://") => &url[7..],
+        _ => return None
+    };
+    let (authority, path): (&str, Option<String>) = match rest.find('/') {
+        Some(index) => (&rest[..index], Some(rest[index..].to_string())),
+        None => (rest, None)
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    let has_port: bool = authority.rsplit_once(':').is_some_and(|(_, port): (&str, &str)| port.parse::<u16>().is_ok());
+    let authority: String = if has_port { authority.to_string() } else { format!("{}:80", authority) };
+    Some((authority, path))
+}
+
+impl ProxyConfig {
+    pub fn new_cfg() -> ProxyConfig {
+        load_env();
+        let routes: Vec<ProxyRoute> = split_list(&dotenv::var("PROXY.ROUTES").unwrap_or_default())
+            .iter()
+            .filter_map(|name: &String| {
+                let path: String = match dotenv::var(format!("PROXY.{}.PATH", name)) {
+                    Ok(path) if !path.trim().is_empty() => path.trim().to_string(),
+                    _ => {
+                        println!("ProxyConfig: PROXY.{}.PATH is not set - ignoring route", name);
+                        return None;
+                    }
+                };
+                let upstream: String = dotenv::var(format!("PROXY.{}.UPSTREAM", name)).unwrap_or_default();
+                let (upstream, upstream_path): (String, Option<String>) = match parse_upstream(&upstream) {
+                    Some(upstream) => upstream,
+                    None => {
+                        println!("ProxyConfig: PROXY.{}.UPSTREAM is not an http:// URL ({:?}) - ignoring route", name, upstream);
+                        return None;
+                    }
+                };
+                Some(ProxyRoute {
+                    path,
+                    upstream,
+                    upstream_path,
+                    preserve_host: dotenv::var(format!("PROXY.{}.PRESERVE_HOST", name)).is_ok_and(|preserve: String| preserve.trim() == "true"),
+                })
+            })
+            .collect();
+        let _proxy_cfg: ProxyConfig = ProxyConfig {
+            routes,
+            connect_timeout: dotenv::var("PROXY.CONNECT_TIMEOUT").ok()
+                                .and_then(|timeout: String| timeout.trim().parse::<u64>().ok())
+                                .filter(|timeout: &u64| *timeout > 0)
+                                .unwrap_or(5),
+            read_timeout: dotenv::var("PROXY.READ_TIMEOUT").ok()
+                                .and_then(|timeout: String| timeout.trim().parse::<u64>().ok())
+                                .filter(|timeout: &u64| *timeout > 0)
+                                .unwrap_or(30),
+        };
+        _proxy_cfg
+    }
+}
+
+impl VirtualHostConfig {
+    pub fn new_cfg() -> VirtualHostConfig {
+        load_env();
+        let hosts: Vec<VirtualHost> = split_list(&dotenv::var("VHOST.HOSTS").unwrap_or_default())
+            .iter()
+            .filter_map(|name: &String| {
+                let server_names: Vec<String> = split_list(&dotenv::var(format!("VHOST.{}.NAMES", name)).unwrap_or_default().to_lowercase());
+                let html_base_path: String = dotenv::var(format!("VHOST.{}.HTML_BASE_PATH", name)).unwrap_or_default().trim().to_string();
+                if server_names.is_empty() || html_base_path.is_empty() {
+                    println!("VirtualHostConfig: VHOST.{}.NAMES or VHOST.{}.HTML_BASE_PATH is not set - ignoring host", name, name);
+                    return None;
+                }
+                Some(VirtualHost {
+                    name: name.clone(),
+                    server_names,
+                    html_base_path,
+                    error_page: dotenv::var(format!("VHOST.{}.ERROR_PAGE", name)).unwrap_or(String::from("error.html")),
+                    routes: split_list(&dotenv::var(format!("VHOST.{}.ROUTES", name)).unwrap_or_default()),
+                })
+            })
+            .collect();
+        let _vhost_cfg: VirtualHostConfig = VirtualHostConfig {
+            hosts,
+            default: dotenv::var("VHOST.DEFAULT").ok()
+                        .map(|default: String| default.trim().to_string())
+                        .filter(|default: &String| !default.is_empty()),
+        };
+        _vhost_cfg
+    }
+}
+
+fn parse_seconds(key: &str, default: i64) -> i64 {
+    dotenv::var(key).ok()
+        .and_then(|value: String| value.trim().parse::<i64>().ok())
+        .filter(|value: &i64| *value > 0)
+        .unwrap_or(default)
+}
+
+impl SessionConfig {
+    pub fn new_cfg() -> SessionConfig {
+        load_env();
+        let same_site: String = dotenv::var("SESSION.SAME_SITE").unwrap_or(String::from("Lax"));
+        let same_site: SameSite = match SameSite::parse(&same_site) {
+            Some(same_site) => same_site,
+            None => {
+                println!("SessionConfig: unknown SESSION.SAME_SITE {:?} - using Lax", same_site);
+                SameSite::Lax
+            }
+        };
+        let _session_cfg: SessionConfig = SessionConfig {
+            cookie_name: dotenv::var("SESSION.COOKIE_NAME").ok()
+                            .map(|name: String| name.trim().to_string())
+                            .filter(|name: &String| !name.is_empty())
+                            .unwrap_or(String::from("sid")),
+            mysql: dotenv::var("SESSION.STORE").is_ok_and(|store: String| store.trim() == "mysql"),
+            idle_timeout: parse_seconds("SESSION.IDLE_TIMEOUT", 1800),
+            lifetime: parse_seconds("SESSION.LIFETIME", 43200),
+            remember_days: parse_seconds("SESSION.REMEMBER_DAYS", 30),
+            secure: dotenv::var("SESSION.SECURE").is_ok_and(|secure: String| secure.trim() == "true"),
+            same_site,
+        };
+        _session_cfg
+    }
+}
@@ -1,36 +1,103 @@
+use std::collections::HashMap;
+use std::fmt;
 use mysql::*;
 use mysql::prelude::*;
 #[allow(unused_imports)]
 use chrono::{Local, NaiveDateTime, NaiveTime, DateTime, NaiveDate};
+use serde_json::{json, Value};
 use crate::server::cstmconfig;
+use crate::server::helpers;
+use crate::server::middleware;
+use crate::server::passwords;
 
 //Serialize, Queryable
 #[derive(Debug, Clone)]
 pub struct User {
+    id: u64,
     role_id: u64,
     username: String,
     email: String,
+    /* secrets are selected but never rendered */
     password: String,
     config: String,
     active: bool,
+    #[allow(dead_code)]
     remember_token: String,
     avatar: String,
     created_at: String,
     updated_at: String
 }
 
+/*
+ * Row of the sessions table (SESSION.STORE=mysql) - token is the SHA-256 of the session id
+ * so the table alone can't be used to take over sessions
+ */
+#[derive(Debug, Clone)]
+pub struct StoredSession {
+    pub user_id: Option<u64>,
+    /* JSON object of the session values */
+    pub data: String,
+    /* unix timestamps */
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/*
+ * Row of the remember_tokens table - one per "remember me" login, so every device keeps its own.
+ * Only the SHA-256 of the cookie is stored.
+ */
+#[derive(Debug, Clone)]
+pub struct RememberToken {
+    pub user_id: u64,
+    /* unix timestamp */
+    pub expires_at: i64,
+}
+
 //Serialize, Queryable
 #[derive(Debug, Clone)]
 pub struct Token {
+    id: u64,
     user_id: u64,
     token_type: String,
-    access_token: String,
-    refresh_token: String,
+    /* access_token & refresh_token are stored hashed - never selected */
     token_expire: String,
     created_at: String,
     updated_at: String
 }
 
+//Serialize, Queryable
+#[derive(Debug, Clone)]
+pub struct Role {
+    id: u64,
+    role_type: String,
+    config: Option<String>,
+    created_at: String,
+    updated_at: String
+}
+
+//Serialize, Queryable
+#[derive(Debug, Clone)]
+pub struct Connected {
+    id: u64,
+    user_id: u64,
+    ip: String,
+    port: u32,
+    proxy: Option<String>,
+    note: Option<String>,
+    blacklist: bool,
+    created_at: String,
+    updated_at: String,
+    test: Option<String>
+}
+
+/*
+ * Columns as selected by select_all() / select_first() / select_by_id()
+ */
+type UserRow = (u64, u64, String, String, String, String, bool, String, String, String, String);
+type TokenRow = (u64, u64, String, String, String, String);
+type ConnectedRow = (u64, u64, String, u32, Option<String>, Option<String>, bool, String, String, Option<String>);
+type RoleRow = (u64, String, Option<String>, String, String);
+
 pub struct Database {
     host: String,
     port: u16,
@@ -98,6 +165,14 @@ pub fn create_tables() -> Result<(), > {
         "created_at".to_string(),
         "updated_at".to_string(),        
     ];
+    let columns_sessions: Vec<String> = vec![
+        "id".to_string(),
+        "user_id".to_string(),
+        "token".to_string(),
+        "data".to_string(),
+        "created_at".to_string(),
+        "updated_at".to_string(),
+    ];
     match create_table(String::from("users"), columns_users) {
         Ok(()) => {
             println!("SQL Table users created successfuly.");
@@ -131,6 +206,19 @@ pub fn create_tables() -> Result<(), > {
         }
     }
 
+    match create_table(String::from("sessions"), columns_sessions) {
+        Ok(()) => {
+            println!("SQL Table sessions created successfuly.");
+        },
+        Err(e) => {
+            println!("SQL Error creating table: {}", e);
+        }
+    }
+
+    if let Err(e) = migrate() {
+        println!("{}", e);
+    }
+
     // custom db query - initialization:
     let mut conn: Conn;
     match init_db() {
@@ -159,6 +247,39 @@ pub fn create_tables() -> Result<(), > {
 
 }
 
+/* seconds the startup migration may wait for the database */
+static MIGRATION_TIMEOUT: u64 = 10;
+/*
+ * Schema changes for tables created by older versions - every statement can run again on an up-to-date database
+ */
+static MIGRATIONS: [&str; 2] = [
+    "ALTER TABLE users MODIFY remember_token VARCHAR(255) NULL",
+    "CREATE TABLE IF NOT EXISTS remember_tokens (
+        id BIGINT AUTO_INCREMENT PRIMARY KEY,
+        user_id BIGINT NOT NULL,
+        token VARCHAR(64) NOT NULL UNIQUE,
+        expires_at TIMESTAMP NOT NULL,
+        created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP(),
+        INDEX (user_id)
+    )",
+];
+
+/*
+ * Run at startup - a database that is down or not created yet only gets logged
+ */
+pub fn migrate() -> std::result::Result<(), String> {
+    let mut conn: Conn = match connect(Some(std::time::Duration::from_secs(MIGRATION_TIMEOUT))) {
+        Ok(connection) => connection,
+        Err(e) => return Err(format!("SQL: Error connecting to db: {}", e))
+    };
+    for query in MIGRATIONS.iter() {
+        if let Err(e) = conn.query_drop(query) {
+            return Err(format!("SQL: Error running migration {:?}: {}", query, e));
+        }
+    }
+    Ok(())
+}
+
 pub fn create_table(table: String, columns: Vec<String>) -> Result<(), > {
 
     let mut conn: Conn;
@@ -186,6 +307,13 @@ pub fn create_table(table: String, columns: Vec<String>) -> Result<(), > {
         }        
         else if column == "created_at" || column == "updated_at" {
             column_meta = format!("{} TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP()", column);
+        }
+        else if column == "data" {
+            column_meta = format!("{} TEXT NOT NULL", column);
+        }
+        /* legacy "remember me" column - tokens live in remember_tokens now */
+        else if column == "remember_token" {
+            column_meta = format!("{} VARCHAR(255) NULL", column);
         } else {
             column_meta = format!("{} VARCHAR(255) NOT NULL", column);
         }
@@ -214,14 +342,36 @@ pub fn create_table(table: String, columns: Vec<String>) -> Result<(), > {
     
 }
 
+/*
+ * JSON columns are stored as text - embed them as JSON if they parse, as string otherwise
+ */
+fn json_column(value: &str) -> Value {
+    match serde_json::from_str::<Value>(value) {
+        Ok(v) => v,
+        Err(_) => Value::String(value.to_string())
+    }
+}
+
+/*
+ * A request stuck on the database gets an error instead of holding the worker - once its Timeout is over it doesn't even connect
+ */
 fn init_db() -> Result<Conn> {
-    let db_config : cstmconfig::DbConfig = cstmconfig::DbConfig::new_cfg();
+    let timeout: Option<std::time::Duration> = middleware::time_left();
+    if timeout == Some(std::time::Duration::ZERO) {
+        return Err(Error::IoError(std::io::Error::new(std::io::ErrorKind::TimedOut, "Request timeout exceeded")));
+    }
+    connect(timeout)
+}
+
+/* connect, read & write timeout - None => no limit */
+fn connect(timeout: Option<std::time::Duration>) -> Result<Conn> {
+    let db_config : &cstmconfig::DbConfig = cstmconfig::database();
     let db: Database = Database::init(
-        db_config.host,
+        db_config.host.clone(),
         db_config.port,
-        db_config.user,
-        db_config.password,
-        db_config.database
+        db_config.user.clone(),
+        db_config.password.clone(),
+        db_config.database.clone()
     );
     let opts: OptsBuilder = OptsBuilder::new()
                 .ip_or_hostname(Some(db.host))
@@ -229,6 +379,7 @@ fn init_db() -> Result<Conn> {
                 .user(Some(db.user))
                 .pass(Some(db.password))
                 .db_name(Some(db.database));
+    let opts: OptsBuilder = opts.tcp_connect_timeout(timeout).read_timeout(timeout).write_timeout(timeout);
     match Conn::new(opts) {
         Ok(connection) => {
             Ok(connection)
@@ -242,339 +393,788 @@ fn init_db() -> Result<Conn> {
 }
 
 
+/*
+ * Single row writes fail either because of the request (=> 400/404/409)
+ * or because of the database itself (=> 500)
+ */
+#[derive(Debug)]
+pub enum RowError {
+    NotFound,
+    Invalid(String),
+    Conflict(String),
+    Internal(String),
+    Sql(Error),
+}
 
-impl User {
-    pub fn user_to_string(user: &User) -> String {
-        format!(
-            "\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
-            user.role_id,
-            user.username,
-            user.email,
-            user.password,
-            user.config,
-            user.active,
-            user.remember_token,
-            user.avatar,
-            user.created_at,
-            user.updated_at,
-        )
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RowError::NotFound => write!(f, "Row not found"),
+            RowError::Invalid(e) => write!(f, "Invalid row: {}", e),
+            RowError::Conflict(e) => write!(f, "Conflict: {}", e),
+            RowError::Internal(e) => write!(f, "Internal error: {}", e),
+            RowError::Sql(e) => write!(f, "SQL: {}", e),
+        }
     }
+}
+
+/* duplicate key => 409, anything else is a server error */
+impl From<Error> for RowError {
+    fn from(e: Error) -> RowError {
+        match e {
+            Error::MySqlError(ref server_error) if server_error.code == 1062 => RowError::Conflict(server_error.message.clone()),
+            e => RowError::Sql(e)
+        }
+    }
+}
 
-    pub fn select_all() -> Result<Vec<User>>{
-        let mut conn : Conn;
-        let selected_users : Vec<User>;
+pub type RowResult<T> = std::result::Result<T, RowError>;
 
-        match init_db() {
-            Ok(connection) => {
-                conn = connection;
-            },
+/*
+ * Page of a listing prepared on a connection of its own (=> bad SQL or a missing table fails the request) -
+ * the query runs once the rows are read, one row at a time from the result set
+ */
+pub struct Rows<T> {
+    conn: Conn,
+    stmt: Statement,
+    values: Vec<mysql::Value>,
+    map: fn(Row) -> std::result::Result<T, FromRowError>,
+}
+
+impl<T> Rows<T> {
+    fn prepare(stmt: String, values: Vec<mysql::Value>, map: fn(Row) -> std::result::Result<T, FromRowError>) -> RowResult<Rows<T>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: Statement = match conn.prep(stmt) {
+            Ok(stmt) => stmt,
             Err(e) => {
-                let errmsg = format!("SQL: Error connecting to db: {}", e);
-                println!("{}", errmsg);
-                return Err(e);
+                println!("SQL: Error selecting from db: {}", e);
+                return Err(e.into());
             }
+        };
+        Ok(Rows { conn, stmt, values, map })
+    }
+
+    /*
+     * Passes every row to write() as soon as it's read - used while the response is written,
+     * so a database error can only cut the body short
+     */
+    pub fn for_each<F: FnMut(T) -> std::io::Result<()>>(self, mut write: F) -> std::io::Result<()> {
+        let Rows { mut conn, stmt, values, map } = self;
+        let result = conn.exec_iter(&stmt, values).map_err(sql_io_error)?;
+        for row in result {
+            let row: Row = row.map_err(sql_io_error)?;
+            write(map(row).map_err(|e: FromRowError| sql_io_error(e.into()))?)?;
         }
-        let stmt = "SELECT
-                     role_id,
-                     username,
-                     email,
-                     password,
-                     config,
-                     active,
-                     remember_token,
-                     avatar,
-                     created_at,
-                     updated_at
-                    FROM users
-                    ORDER BY created_at
-                    DESC";
-        let select_res = conn.query_map(
-            stmt,
-            |(role_id,
-                username, 
-                email, 
-                password, 
-                config, 
-                active, 
-                remember_token, 
-                avatar, 
-                created_at, 
-                updated_at)|
-            -> User {
-                User {
-                    role_id,
-                    username,
-                    email,
-                    password,
-                    config,
-                    active,
-                    remember_token,
-                    avatar,
-                    created_at,
-                    updated_at
-                }
+        Ok(())
+    }
+
+    /*
+     * All rows at once - for views, which render from a complete context
+     */
+    pub fn collect(self) -> Result<Vec<T>> {
+        let Rows { mut conn, stmt, values, map } = self;
+        let mut rows: Vec<T> = Vec::new();
+        for row in conn.exec_iter(&stmt, values)? {
+            rows.push(map(row?)?);
+        }
+        Ok(rows)
+    }
+}
+
+fn sql_io_error(e: Error) -> std::io::Error {
+    println!("SQL: Error selecting from db: {}", e);
+    std::io::Error::other(format!("SQL: {}", e))
+}
+
+/*
+ * Writable columns of tables created by create_tables() & relations between them:
+ *  -- references    => (column, table) - referenced row must exist on insert/update
+ *  -- referenced_by => (table, column) - row can't be deleted while it's referenced
+ *  -- unique        => value can't be shared by two rows
+ *  -- filterable    => columns usable in list filters & sorting (never secrets)
+ *  -- keep_on_put   => columns a PUT may leave out - they keep their current value
+ * Columns only the server writes (users.remember_token) aren't listed at all.
+ */
+struct Table {
+    name: &'static str,
+    columns: &'static [&'static str],
+    filterable: &'static [&'static str],
+    references: &'static [(&'static str, &'static str)],
+    referenced_by: &'static [(&'static str, &'static str)],
+    unique: &'static [&'static str],
+    keep_on_put: &'static [&'static str],
+}
+
+static USERS: Table = Table {
+    name: "users",
+    columns: &["role_id", "username", "email", "password", "config", "active", "avatar", "created_at", "updated_at"],
+    filterable: &["id", "role_id", "username", "email", "active", "created_at", "updated_at"],
+    references: &[("role_id", "roles")],
+    referenced_by: &[("tokens", "user_id"), ("connected", "user_id")],
+    unique: &["username", "email"],
+    keep_on_put: &["password"],
+};
+static TOKENS: Table = Table {
+    name: "tokens",
+    columns: &["user_id", "token_type", "access_token", "refresh_token", "token_expire", "created_at", "updated_at"],
+    filterable: &["id", "user_id", "token_type", "token_expire", "created_at", "updated_at"],
+    references: &[("user_id", "users")],
+    referenced_by: &[],
+    unique: &["access_token"],
+    keep_on_put: &[],
+};
+static ROLES: Table = Table {
+    name: "roles",
+    columns: &["type", "config", "created_at", "updated_at"],
+    filterable: &["id", "type", "created_at", "updated_at"],
+    references: &[],
+    referenced_by: &[("users", "role_id")],
+    unique: &["type"],
+    keep_on_put: &[],
+};
+static CONNECTED: Table = Table {
+    name: "connected",
+    columns: &["user_id", "ip", "port", "proxy", "note", "blacklist", "created_at", "updated_at", "test"],
+    filterable: &["id", "user_id", "ip", "port", "blacklist", "created_at", "updated_at"],
+    references: &[("user_id", "users")],
+    referenced_by: &[],
+    unique: &[],
+    keep_on_put: &[],
+};
+
+/* created_at & updated_at default to CURRENT_TIMESTAMP() */
+static TIMESTAMP_COLUMNS: [&str; 2] = ["created_at", "updated_at"];
+static BOOL_COLUMNS: [&str; 2] = ["active", "blacklist"];
+/* bearer secrets - only their hash is stored & they're never sent back */
+static SECRET_COLUMNS: [&str; 2] = ["access_token", "refresh_token"];
+/* rows per page of select_all() */
+static DEFAULT_LIMIT: u64 = 100;
+static MAX_LIMIT: u64 = 1000;
+
+/*
+ * password is stored as a salted PBKDF2 hash of the given value, token secrets as their SHA-256 - never as sent
+ */
+fn column_value(column: &str, value: &str) -> RowResult<mysql::Value> {
+    if column == "password" {
+        if value.is_empty() {
+            return Err(RowError::Invalid(String::from("password can't be empty")));
+        }
+        return passwords::hash(value).map(mysql::Value::from).map_err(RowError::Internal);
+    }
+    if SECRET_COLUMNS.contains(&column) {
+        return Ok(mysql::Value::from(helpers::sha256_hex(value)));
+    }
+    if BOOL_COLUMNS.contains(&column) {
+        return match value {
+            "true" => Ok(mysql::Value::from(true)),
+            "false" => Ok(mysql::Value::from(false)),
+            _ => Err(RowError::Invalid(format!("{} must be true or false", column)))
+        };
+    }
+    if column == "id" || column.ends_with("_id") || column == "port" {
+        return match value.parse::<u64>() {
+            Ok(number) => Ok(mysql::Value::from(number)),
+            Err(_) => Err(RowError::Invalid(format!("{} must be a number", column)))
+        };
+    }
+    Ok(mysql::Value::from(value))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum WriteMode {
+    /* POST */
+    Insert,
+    /* PUT */
+    Replace,
+    /* PATCH */
+    Partial,
+}
+
+/*
+ * Request parameters => (column, value) in table column order.
+ * Unknown columns are refused, all columns except timestamps are required
+ * unless partial (PATCH) or kept on replace (PUT without password).
+ */
+fn validate_params(table: &Table, params: &HashMap<String, String>, write: WriteMode) -> RowResult<Vec<(&'static str, mysql::Value)>> {
+    if let Some(unknown) = params.keys().find(|key: &&String| !table.columns.contains(&key.as_str())) {
+        return Err(RowError::Invalid(format!("Unknown field {}", unknown)));
+    }
+    let mut values: Vec<(&'static str, mysql::Value)> = Vec::new();
+    for column in table.columns.iter() {
+        match params.get(*column) {
+            Some(value) => values.push((column, column_value(column, value)?)),
+            None if write == WriteMode::Partial || TIMESTAMP_COLUMNS.contains(column) => {},
+            None if write == WriteMode::Replace && table.keep_on_put.contains(column) => {},
+            None => return Err(RowError::Invalid(format!("Missing field {}", column)))
+        }
+    }
+    if values.is_empty() {
+        return Err(RowError::Invalid(String::from("No fields to update")));
+    }
+    Ok(values)
+}
+
+/*
+ * List query parameters => " WHERE .. ORDER BY .. LIMIT ? OFFSET ?" & its values:
+ *  -- ?limit={n}&offset={n} or ?page={n} (1-based, overrides offset)
+ *  -- ?sort={column} ascending, ?sort=-{column} descending
+ *  -- ?{column}={value} equality filter (filterable columns only)
+ * Ties are broken by id so pages don't overlap.
+ */
+fn list_clause(table: &Table, query: &HashMap<String, String>, default_sort: &str) -> RowResult<(String, Vec<mysql::Value>)> {
+    let mut limit: u64 = DEFAULT_LIMIT;
+    let mut offset: u64 = 0;
+    let mut page: Option<u64> = None;
+    let mut order: String = String::from(default_sort);
+    let mut conditions: Vec<String> = Vec::new();
+    let mut values: Vec<mysql::Value> = Vec::new();
+    let mut params: Vec<(&String, &String)> = query.iter().collect();
+    params.sort();
+    for (name, value) in params {
+        match name.as_str() {
+            "limit" => limit = match value.parse::<u64>() {
+                Ok(n) if n > 0 && n <= MAX_LIMIT => n,
+                _ => return Err(RowError::Invalid(format!("limit must be between 1 and {}", MAX_LIMIT)))
             },
-        );
-        match select_res {
-            Ok(users) => {
-                selected_users = users;
+            "offset" => offset = match value.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => return Err(RowError::Invalid(String::from("offset must be a number")))
             },
-            Err(e) => {
-                let errmsg = format!("SQL: Error selecting from db: {}", e);
-                println!("{}", errmsg);
-                return Err(e);
-            }
-        }
-        Ok(selected_users)
-    }
-
-    pub fn create_users(params: std::collections::HashMap<&str, &str>) -> Result<()>{
-        let users = vec![
-            User { 
-                role_id: params["role_id"].parse::<u64>().unwrap(),
-                username: String::from(params["username"]),
-                email: String::from(params["email"]), 
-                password: String::from(params["password"]), 
-                config: String::from(params["config"]), 
-                active: params["active"].eq("true"),
-                remember_token: String::from(params["remember_token"]), 
-                avatar: String::from(params["avatar"]), 
-                created_at: String::from(params["created_at"]), 
-                updated_at: String::from(params["updated_at"]), 
+            "page" => page = match value.parse::<u64>() {
+                Ok(n) if n > 0 => Some(n),
+                _ => return Err(RowError::Invalid(String::from("page must be a number from 1")))
+            },
+            "sort" => {
+                let (column, direction): (&str, &str) = match value.strip_prefix('-') {
+                    Some(column) => (column, "DESC"),
+                    None => (value.as_str(), "ASC")
+                };
+                if !table.filterable.contains(&column) {
+                    return Err(RowError::Invalid(format!("Can't sort by {}", column)));
+                }
+                order = format!("{} {}", column, direction);
             },
-        ];
-        /********************************************/
-        match insert(users) {
-            Ok(()) => {},
-            Err(err) => {
-                let errmsg = format!("SQL: Error on insert(): {}", err);
-                println!("{}", &errmsg);
-                return Err(err);
+            column => {
+                if !table.filterable.contains(&column) {
+                    return Err(RowError::Invalid(format!("Can't filter by {}", column)));
+                }
+                conditions.push(format!("{} = ?", column));
+                values.push(column_value(column, value)?);
             }
         }
-        /********************************************/
-        fn insert(users: Vec<User>) -> Result<()> {
-            let mut conn : Conn;
-            match init_db() {
-                Ok(connection) => {
-                    conn = connection;
-                },
-                Err(e) => {
-                    let errmsg = format!("SQL: Error connecting to db: {}", e);
-                    println!("{}", errmsg);
-                    return Err(e);
-                }
+    }
+    if let Some(page) = page {
+        offset = (page - 1).saturating_mul(limit);
+    }
+    let mut clause: String = String::new();
+    if !conditions.is_empty() {
+        clause.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    let tie_break: &str = if order.ends_with("DESC") { "id DESC" } else { "id ASC" };
+    if order.starts_with("id ") {
+        clause.push_str(&format!(" ORDER BY {}", order));
+    } else {
+        clause.push_str(&format!(" ORDER BY {}, {}", order, tie_break));
+    }
+    clause.push_str(" LIMIT ? OFFSET ?");
+    values.push(mysql::Value::from(limit));
+    values.push(mysql::Value::from(offset));
+    Ok((clause, values))
+}
+
+fn count_rows<Q: Queryable>(conn: &mut Q, query: String, params: Vec<mysql::Value>) -> Result<u64> {
+    Ok(conn.exec_first::<u64, String, Vec<mysql::Value>>(query, params)?.unwrap_or(0))
+}
+
+fn row_exists<Q: Queryable>(conn: &mut Q, table: &str, id: u64) -> Result<bool> {
+    Ok(count_rows(conn, format!("SELECT COUNT(*) FROM {} WHERE id = ?", table), vec![mysql::Value::from(id)])? > 0)
+}
+
+/*
+ * Referenced rows must exist & unique values can't be taken by another row (=> 409)
+ */
+fn check_constraints<Q: Queryable>(conn: &mut Q, table: &Table, values: &[(&'static str, mysql::Value)], id: Option<u64>) -> RowResult<()> {
+    for (column, value) in values.iter() {
+        if let Some((_, referenced)) = table.references.iter().find(|(c, _)| c == column) {
+            let query: String = format!("SELECT COUNT(*) FROM {} WHERE id = ?", referenced);
+            if count_rows(conn, query, vec![value.clone()])? == 0 {
+                return Err(RowError::Conflict(format!("{} {} does not exist", column, value.as_sql(true))));
             }
-            let stmt =
-            "INSERT INTO users
-                (role_id,
-                username,
-                email,
-                password,
-                config,
-                active,
-                remember_token,
-                avatar,
-                created_at,
-                updated_at)
-            VALUES
-                (:role_id,
-                 :username,
-                 :email,
-                 :password,
-                 :config,
-                 :active,
-                 :remember_token,
-                 :avatar,
-                 :created_at,
-                 :updated_at)";
-            
-            // Strings are passed by reference!
-            let __params = users.iter().map(|u| params!{
-                "role_id" => u.role_id,
-                "username" => &u.username,
-                "email" => &u.email,
-                "password" => &u.password,
-                "config" => &u.config,
-                "active" => u.active,
-                "remember_token" => &u.remember_token,
-                "avatar" => &u.avatar,
-                "created_at" => &u.created_at,
-                "updated_at" => &u.updated_at
-            });
-            let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
-            println!("SQL: Params to insert:\n{:?}\n", __params);
-            match tx.exec_batch(stmt, __params) {
-                Ok(()) => {
-                    println!("SQL: Successfuly inserted users!");
-                }
-                Err(err) => {
-                    tx.rollback().unwrap();
-                    let errmsg = format!("SQL: Error on insert(): {}", err);
-                    println!("{}", &errmsg);
-                    return Err(err);
-                }
+        }
+        if table.unique.contains(column) {
+            let query: String = format!("SELECT COUNT(*) FROM {} WHERE {} = ? AND id != ?", table.name, column);
+            if count_rows(conn, query, vec![value.clone(), mysql::Value::from(id.unwrap_or(0))])? > 0 {
+                return Err(RowError::Conflict(format!("{} {} already exists", column, value.as_sql(true))));
             }
-            tx.commit().unwrap();
-            Ok(())
         }
-        /********************************************/
+    }
+    Ok(())
+}
+
+/*
+ * Returns id of the inserted row
+ */
+fn insert_row(table: &Table, params: &HashMap<String, String>) -> RowResult<u64> {
+    let values: Vec<(&'static str, mysql::Value)> = validate_params(table, params, WriteMode::Insert)?;
+    let mut conn: Conn = init_db()?;
+    let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
+    check_constraints(&mut tx, table, &values, None)?;
+    let columns: Vec<&str> = values.iter().map(|(column, _)| *column).collect();
+    let query: String = format!(
+        "INSERT INTO {} ({}) VALUES ({})",
+        table.name,
+        columns.join(", "),
+        vec!["?"; columns.len()].join(", ")
+    );
+    tx.exec_drop(query, values.into_iter().map(|(_, value)| value).collect::<Vec<mysql::Value>>())?;
+    let id: u64 = tx.last_insert_id().unwrap_or(0);
+    tx.commit()?;
+    println!("SQL: Successfuly inserted into {} [id: {}]", table.name, id);
+    Ok(id)
+}
+
+/*
+ * PUT replaces all columns, PATCH (partial) only the given ones.
+ * updated_at is refreshed unless it's part of the request.
+ */
+fn update_row(table: &Table, id: u64, params: &HashMap<String, String>, partial: bool) -> RowResult<()> {
+    let values: Vec<(&'static str, mysql::Value)> = validate_params(table, params, if partial { WriteMode::Partial } else { WriteMode::Replace })?;
+    let mut conn: Conn = init_db()?;
+    let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
+    if !row_exists(&mut tx, table.name, id)? {
+        return Err(RowError::NotFound);
+    }
+    check_constraints(&mut tx, table, &values, Some(id))?;
+    let mut assignments: Vec<String> = values.iter().map(|(column, _)| format!("{} = ?", column)).collect();
+    if !values.iter().any(|(column, _)| *column == "updated_at") {
+        assignments.push(String::from("updated_at = CURRENT_TIMESTAMP()"));
+    }
+    let query: String = format!("UPDATE {} SET {} WHERE id = ?", table.name, assignments.join(", "));
+    let mut query_params: Vec<mysql::Value> = values.into_iter().map(|(_, value)| value).collect();
+    query_params.push(mysql::Value::from(id));
+    tx.exec_drop(query, query_params)?;
+    tx.commit()?;
+    println!("SQL: Successfuly updated {} [id: {}]", table.name, id);
+    Ok(())
+}
+
+/*
+ * Rows still referenced from other tables are kept (=> 409)
+ */
+fn delete_row(table: &Table, id: u64) -> RowResult<()> {
+    let mut conn: Conn = init_db()?;
+    let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
+    if !row_exists(&mut tx, table.name, id)? {
+        return Err(RowError::NotFound);
+    }
+    for (referencing, column) in table.referenced_by.iter() {
+        let query: String = format!("SELECT COUNT(*) FROM {} WHERE {} = ?", referencing, column);
+        if count_rows(&mut tx, query, vec![mysql::Value::from(id)])? > 0 {
+            return Err(RowError::Conflict(format!("{} {} is still referenced by {}", table.name, id, referencing)));
+        }
+    }
+    tx.exec_drop(format!("DELETE FROM {} WHERE id = ?", table.name), (id,))?;
+    tx.commit()?;
+    println!("SQL: Successfuly deleted from {} [id: {}]", table.name, id);
+    Ok(())
+}
+
+impl User {
+    /*
+     * password & remember_token are never exposed
+     */
+    pub fn user_to_json(user: &User) -> Value {
+        json!({
+            "id": user.id,
+            "role_id": user.role_id,
+            "username": user.username,
+            "email": user.email,
+            "config": json_column(&user.config),
+            "active": user.active,
+            "avatar": user.avatar,
+            "created_at": user.created_at,
+            "updated_at": user.updated_at,
+        })
+    }
+
+    pub fn select_all(query: &HashMap<String, String>) -> RowResult<Rows<User>> {
+        let (clause, values): (String, Vec<mysql::Value>) = list_clause(&USERS, query, "created_at DESC")?;
+        let stmt: String = format!("SELECT
+                           id,
+                           role_id,
+                           username,
+                           email,
+                           password,
+                           config,
+                           active,
+                           COALESCE(remember_token, ''),
+                           avatar,
+                           created_at,
+                           updated_at
+                          FROM users{}", clause);
+        Rows::prepare(stmt, values, |row: Row| mysql::from_row_opt::<UserRow>(row).map(|(id, role_id, username, email, password, config, active, remember_token, avatar, created_at, updated_at)| User {
+            id, role_id, username, email, password, config, active, remember_token, avatar, created_at, updated_at
+        }))
+    }
+
+    /*
+     * First user matching the condition ("column = ?" with the given values)
+     */
+    fn select_first(condition: &str, values: Vec<mysql::Value>) -> Result<Option<User>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: String = format!("SELECT
+                           id,
+                           role_id,
+                           username,
+                           email,
+                           password,
+                           config,
+                           active,
+                           COALESCE(remember_token, ''),
+                           avatar,
+                           created_at,
+                           updated_at
+                          FROM users
+                          WHERE {}
+                          LIMIT 1", condition);
+        let row: Option<UserRow> = conn.exec_first(stmt, values)?;
+        Ok(row.map(|(id, role_id, username, email, password, config, active, remember_token, avatar, created_at, updated_at)| User {
+            id, role_id, username, email, password, config, active, remember_token, avatar, created_at, updated_at
+        }))
+    }
+
+    pub fn select_by_id(id: u64) -> Result<Option<User>> {
+        User::select_first("id = ?", vec![mysql::Value::from(id)])
+    }
+
+    /*
+     * Login form accepts the username or the email
+     */
+    pub fn select_by_login(login: &str) -> Result<Option<User>> {
+        User::select_first("username = ? OR email = ?", vec![mysql::Value::from(login), mysql::Value::from(login)])
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn role_id(&self) -> u64 {
+        self.role_id
+    }
+
+    /*
+     * password against the stored PBKDF2 hash
+     */
+    pub fn verify_password(&self, password: &str) -> bool {
+        passwords::verify(password, Some(&self.password))
+    }
+
+    pub fn create_users(params: HashMap<String, String>) -> RowResult<u64> {
+        insert_row(&USERS, &params)
+    }
+
+    pub fn update_user(id: u64, params: HashMap<String, String>, partial: bool) -> RowResult<()> {
+        update_row(&USERS, id, &params, partial)
+    }
+
+    pub fn delete_user(id: u64) -> RowResult<()> {
+        delete_row(&USERS, id)?;
+        RememberToken::delete_user(id)?;
         Ok(())
     }
 }
 
 impl Token {
-    pub fn token_to_string(token: &Token) -> String {
-        format!(
-            "\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n{}\r\n",
-            token.user_id,
-            token.token_type,
-            token.access_token,
-            token.refresh_token,
-            token.token_expire,
-            token.created_at,
-            token.updated_at
-        )
+    pub fn token_to_json(token: &Token) -> Value {
+        json!({
+            "id": token.id,
+            "user_id": token.user_id,
+            "token_type": token.token_type,
+            "token_expire": token.token_expire,
+            "created_at": token.created_at,
+            "updated_at": token.updated_at,
+        })
     }
 
-    pub fn select_all() -> Result<Vec<Token>> {
-        let mut conn : Conn;
-        let selected_tokens : Vec<Token>;
+    pub fn select_all(query: &HashMap<String, String>) -> RowResult<Rows<Token>> {
+        let (clause, values): (String, Vec<mysql::Value>) = list_clause(&TOKENS, query, "created_at DESC")?;
+        let stmt: String = format!("SELECT
+                           id,
+                           user_id,
+                           token_type,
+                           token_expire,
+                           created_at,
+                           updated_at
+                          FROM tokens{}", clause);
+        Rows::prepare(stmt, values, |row: Row| mysql::from_row_opt::<TokenRow>(row).map(|(id, user_id, token_type, token_expire, created_at, updated_at)| Token {
+            id, user_id, token_type, token_expire, created_at, updated_at
+        }))
+    }
 
-        match init_db() {
-            Ok(connection) => {
-                conn = connection;
-            },
-            Err(e) => {
-                let errmsg: String = format!("SQL: Error connecting to db: {}", e);
-                println!("{}", errmsg);
-                return Err(e);
-            }
-        }
+    pub fn select_by_id(id: u64) -> Result<Option<Token>> {
+        let mut conn: Conn = init_db()?;
         let stmt: &str = "SELECT
+                           id,
                            user_id,
                            token_type,
-                           access_token,
-                           refresh_token,
                            token_expire,
                            created_at,
                            updated_at
                           FROM tokens
-                          ORDER BY created_at
-                          DESC";
-        let select_res: Result<Vec<Token>> = conn.query_map(
-            stmt,
-            |(user_id,
-                token_type,
-                access_token,
-                refresh_token,
-                token_expire,
-                created_at,
-                updated_at)| 
-            -> Token {
-                Token {
-                    user_id,
-                    token_type,
-                    access_token,
-                    refresh_token,
-                    token_expire,
-                    created_at,
-                    updated_at
-                }
-            },
-        );
-        match select_res {
-            Ok(tokens) => {
-                selected_tokens = tokens;
-            },
-            Err(e) => {
-                let errmsg = format!("Error selecting from db: {}", e);
-                println!("{}", errmsg);
-                return Err(e);
-            }
-        }
-        Ok(selected_tokens)
-    }
-
-    pub fn create_tokens(params: std::collections::HashMap<&str, &str>) -> Result<()>{
-        let tokens: Vec<Token> = vec![
-            Token { 
-                user_id: params["user_id"].parse::<u64>().unwrap(),
-                token_type: String::from(params["token_type"]),
-                access_token: String::from(params["access_token"]),
-                refresh_token: String::from(params["refresh_token"]),
-                token_expire: String::from(params["token_expire"]),
-                created_at: String::from(params["created_at"]),
-                updated_at: String::from(params["updated_at"]),
-            },
-        ];
-        /********************************************/
-        match insert(tokens) {
-            Ok(()) => {},
-            Err(err) => {
-                let errmsg: String = format!("error on insert(): {}", err);
-                println!("{}", &errmsg);
-                return Err(err);
-            }
-        }
-        /********************************************/
-        fn insert(tokens: Vec<Token>) -> Result<()> {
-            let mut conn : Conn;
-            match init_db() {
-                Ok(connection) => {
-                    conn = connection;
-                },
-                Err(e) => {
-                    let errmsg = format!("SQL: Error connecting to db: {}", e);
-                    println!("{}", errmsg);
-                    return Err(e);
-                }
-            }
-            let stmt: &str =
-            "INSERT INTO tokens
-                (user_id,
-                 token_type,
-                 access_token,
-                 refresh_token,
-                 token_expire,
-                 created_at,
-                 updated_at)
-            VALUES
-                (:user_id,
-                 :token_type,
-                 :access_token,
-                 :refresh_token,
-                 :token_expire,
-                 :created_at,
-                 :updated_at)";
-                
-            // Strings are passed by reference!
-            let __params = tokens.iter().map(|t| params! {
-                "user_id" => t.user_id,
-                "token_type" => &t.token_type,
-                "access_token" => &t.access_token,
-                "refresh_token" => &t.refresh_token,
-                "token_expire" => &t.token_expire,
-                "created_at" => &t.created_at,
-                "updated_at" => &t.updated_at
-            });
-            let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
-            println!("SQL: Params to insert:\n{:?}\n", __params);
-            match tx.exec_batch(stmt, __params) {
-                Ok(()) => {
-                    println!("SQL: Successfuly inserted tokens!");
-                }
-                Err(err) => {
-                    tx.rollback().unwrap();
-                    let errmsg: String = format!("SQL: Error on insert(): {}", err);
-                    println!("{}", &errmsg);
-                    return Err(err);
-                }
-            }
-            tx.commit().unwrap();
-            Ok(())
-        }
-        /********************************************/
-        Ok(())
+                          WHERE id = ?";
+        let row: Option<TokenRow> = conn.exec_first(stmt, (id,))?;
+        Ok(row.map(|(id, user_id, token_type, token_expire, created_at, updated_at)| Token {
+            id, user_id, token_type, token_expire, created_at, updated_at
+        }))
+    }
+
+    /*
+     * Owner of a bearer token that hasn't expired yet - looked up by the SHA-256 of the token
+     */
+    pub fn select_user_id(access_token_hash: &str) -> Result<Option<u64>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: &str = "SELECT user_id
+                          FROM tokens
+                          WHERE access_token = ? AND token_expire > NOW()
+                          LIMIT 1";
+        conn.exec_first(stmt, (access_token_hash,))
+    }
+
+    pub fn create_tokens(params: HashMap<String, String>) -> RowResult<u64> {
+        insert_row(&TOKENS, &params)
+    }
+
+    pub fn update_token(id: u64, params: HashMap<String, String>, partial: bool) -> RowResult<()> {
+        update_row(&TOKENS, id, &params, partial)
+    }
+
+    pub fn delete_token(id: u64) -> RowResult<()> {
+        delete_row(&TOKENS, id)
     }
-}
\ No newline at end of file
+}
+
+impl Role {
+    pub fn role_to_json(role: &Role) -> Value {
+        json!({
+            "id": role.id,
+            "type": role.role_type,
+            "config": role.config.as_deref().map(json_column),
+            "created_at": role.created_at,
+            "updated_at": role.updated_at,
+        })
+    }
+
+    pub fn select_all(query: &HashMap<String, String>) -> RowResult<Rows<Role>> {
+        let (clause, values): (String, Vec<mysql::Value>) = list_clause(&ROLES, query, "id ASC")?;
+        let stmt: String = format!("SELECT
+                           id,
+                           type,
+                           config,
+                           created_at,
+                           updated_at
+                          FROM roles{}", clause);
+        Rows::prepare(stmt, values, |row: Row| mysql::from_row_opt::<RoleRow>(row).map(|(id, role_type, config, created_at, updated_at)| Role {
+            id, role_type, config, created_at, updated_at
+        }))
+    }
+
+    pub fn select_by_id(id: u64) -> Result<Option<Role>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: &str = "SELECT
+                           id,
+                           type,
+                           config,
+                           created_at,
+                           updated_at
+                          FROM roles
+                          WHERE id = ?";
+        let row: Option<(u64, String, Option<String>, String, String)> = conn.exec_first(stmt, (id,))?;
+        Ok(row.map(|(id, role_type, config, created_at, updated_at)| Role { id, role_type, config, created_at, updated_at }))
+    }
+
+    pub fn create_roles(params: HashMap<String, String>) -> RowResult<u64> {
+        insert_row(&ROLES, &params)
+    }
+
+    pub fn update_role(id: u64, params: HashMap<String, String>, partial: bool) -> RowResult<()> {
+        update_row(&ROLES, id, &params, partial)
+    }
+
+    pub fn delete_role(id: u64) -> RowResult<()> {
+        delete_row(&ROLES, id)
+    }
+}
+
+impl Connected {
+    pub fn connected_to_json(connected: &Connected) -> Value {
+        json!({
+            "id": connected.id,
+            "user_id": connected.user_id,
+            "ip": connected.ip,
+            "port": connected.port,
+            "proxy": connected.proxy.as_deref().map(json_column),
+            "note": connected.note.as_deref().map(json_column),
+            "blacklist": connected.blacklist,
+            "created_at": connected.created_at,
+            "updated_at": connected.updated_at,
+            "test": connected.test.as_deref().map(json_column),
+        })
+    }
+
+    pub fn select_all(query: &HashMap<String, String>) -> RowResult<Rows<Connected>> {
+        let (clause, values): (String, Vec<mysql::Value>) = list_clause(&CONNECTED, query, "created_at DESC")?;
+        let stmt: String = format!("SELECT
+                           id,
+                           user_id,
+                           ip,
+                           port,
+                           proxy,
+                           note,
+                           blacklist,
+                           created_at,
+                           updated_at,
+                           test
+                          FROM connected{}", clause);
+        Rows::prepare(stmt, values, |row: Row| mysql::from_row_opt::<ConnectedRow>(row).map(|(id, user_id, ip, port, proxy, note, blacklist, created_at, updated_at, test)| Connected {
+            id, user_id, ip, port, proxy, note, blacklist, created_at, updated_at, test
+        }))
+    }
+
+    pub fn select_by_id(id: u64) -> Result<Option<Connected>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: &str = "SELECT
+                           id,
+                           user_id,
+                           ip,
+                           port,
+                           proxy,
+                           note,
+                           blacklist,
+                           created_at,
+                           updated_at,
+                           test
+                          FROM connected
+                          WHERE id = ?";
+        let row: Option<ConnectedRow> = conn.exec_first(stmt, (id,))?;
+        Ok(row.map(|(id, user_id, ip, port, proxy, note, blacklist, created_at, updated_at, test)| Connected {
+            id, user_id, ip, port, proxy, note, blacklist, created_at, updated_at, test
+        }))
+    }
+
+    pub fn create_connected(params: HashMap<String, String>) -> RowResult<u64> {
+        insert_row(&CONNECTED, &params)
+    }
+
+    pub fn update_connected(id: u64, params: HashMap<String, String>, partial: bool) -> RowResult<()> {
+        update_row(&CONNECTED, id, &params, partial)
+    }
+
+    pub fn delete_connected(id: u64) -> RowResult<()> {
+        delete_row(&CONNECTED, id)
+    }
+}
+
+impl RememberToken {
+    /*
+     * Token of a "remember me" cookie unless it expired
+     */
+    pub fn select_by_token(token_hash: &str, now: i64) -> Result<Option<RememberToken>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: &str = "SELECT
+                           user_id,
+                           UNIX_TIMESTAMP(expires_at)
+                          FROM remember_tokens
+                          WHERE token = ? AND expires_at > FROM_UNIXTIME(?)
+                          LIMIT 1";
+        let row: Option<(u64, i64)> = conn.exec_first(stmt, (token_hash, now))?;
+        Ok(row.map(|(user_id, expires_at)| RememberToken { user_id, expires_at }))
+    }
+
+    /*
+     * New token of the user - expired ones of the user are dropped on the way
+     */
+    pub fn insert(token_hash: &str, token: &RememberToken, now: i64) -> Result<()> {
+        let mut conn: Conn = init_db()?;
+        let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
+        tx.exec_drop("DELETE FROM remember_tokens WHERE user_id = ? AND expires_at <= FROM_UNIXTIME(?)", (token.user_id, now))?;
+        tx.exec_drop(
+            "INSERT INTO remember_tokens (user_id, token, expires_at) VALUES (?, ?, FROM_UNIXTIME(?))",
+            (token.user_id, token_hash, token.expires_at)
+        )?;
+        tx.commit()
+    }
+
+    /*
+     * Used token stops working at expires_at (unless it expires earlier)
+     */
+    pub fn expire(token_hash: &str, expires_at: i64) -> Result<()> {
+        let mut conn: Conn = init_db()?;
+        conn.exec_drop(
+            "UPDATE remember_tokens SET expires_at = LEAST(expires_at, FROM_UNIXTIME(?)) WHERE token = ?",
+            (expires_at, token_hash)
+        )
+    }
+
+    /*
+     * "Remember me" cookies of the user stop working on all devices
+     */
+    pub fn delete_user(user_id: u64) -> Result<()> {
+        let mut conn: Conn = init_db()?;
+        conn.exec_drop("DELETE FROM remember_tokens WHERE user_id = ?", (user_id,))
+    }
+}
+
+impl StoredSession {
+    pub fn select_by_token(token_hash: &str) -> Result<Option<StoredSession>> {
+        let mut conn: Conn = init_db()?;
+        let stmt: &str = "SELECT
+                           user_id,
+                           data,
+                           UNIX_TIMESTAMP(created_at),
+                           UNIX_TIMESTAMP(updated_at)
+                          FROM sessions
+                          WHERE token = ?
+                          LIMIT 1";
+        let row: Option<(Option<u64>, String, i64, i64)> = conn.exec_first(stmt, (token_hash,))?;
+        Ok(row.map(|(user_id, data, created_at, updated_at)| StoredSession { user_id, data, created_at, updated_at }))
+    }
+
+    /*
+     * Inserts or replaces the row of the session
+     */
+    pub fn save(token_hash: &str, session: &StoredSession) -> Result<()> {
+        let mut conn: Conn = init_db()?;
+        let mut tx: Transaction = conn.start_transaction(TxOpts::default())?;
+        tx.exec_drop("DELETE FROM sessions WHERE token = ?", (token_hash,))?;
+        tx.exec_drop(
+            "INSERT INTO sessions (user_id, token, data, created_at, updated_at) VALUES (?, ?, ?, FROM_UNIXTIME(?), FROM_UNIXTIME(?))",
+            (session.user_id, token_hash, &session.data, session.created_at, session.updated_at)
+        )?;
+        tx.commit()
+    }
+
+    pub fn delete(token_hash: &str) -> Result<()> {
+        let mut conn: Conn = init_db()?;
+        conn.exec_drop("DELETE FROM sessions WHERE token = ?", (token_hash,))
+    }
+
+    /*
+     * Rows idle since before idle_before or created before created_before (unix timestamps)
+     */
+    pub fn delete_expired(idle_before: i64, created_before: i64) -> Result<u64> {
+        let mut conn: Conn = init_db()?;
+        conn.exec_drop(
+            "DELETE FROM sessions WHERE updated_at < FROM_UNIXTIME(?) OR created_at < FROM_UNIXTIME(?)",
+            (idle_before, created_before)
+        )?;
+        Ok(conn.affected_rows())
+    }
+}
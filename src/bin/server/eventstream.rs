@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Condvar, Mutex, MutexGuard, OnceLock};
+use std::time::Duration;
+use serde_json::Value;
+use crate::server::threadpool::{self, StreamSlot};
+
+/*
+ * Number of past events kept for clients resuming with last-event-id
+ */
+static BACKLOG_SIZE: usize = 256;
+/*
+ * Seconds between keep-alive comments on an idle stream - a gone client is noticed on the next write
+ */
+static KEEP_ALIVE_INTERVAL: u64 = 15;
+/* reconnection delay suggested to EventSource (ms) */
+static RETRY_MS: u64 = 3000;
+const IDENTIFICATOR: &str = "eventstream";
+
+struct Event {
+    id: u64,
+    kind: &'static str,
+    /* single-line JSON */
+    data: String,
+}
+
+struct EventLog {
+    last_id: u64,
+    backlog: VecDeque<Event>,
+    subscribers: usize,
+}
+
+struct EventHub {
+    log: Mutex<EventLog>,
+    published: Condvar,
+}
+
+fn hub() -> &'static EventHub {
+    static HUB: OnceLock<EventHub> = OnceLock::new();
+    HUB.get_or_init(|| EventHub {
+        log: Mutex::new(EventLog { last_id: 0, backlog: VecDeque::with_capacity(BACKLOG_SIZE), subscribers: 0 }),
+        published: Condvar::new(),
+    })
+}
+
+fn lock() -> MutexGuard<'static, EventLog> {
+    match hub().log.lock() {
+        Ok(log) => log,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+/*
+ * Records server activity and wakes up all open streams. Kinds:
+ *  -- connect / disconnect: TCP or WebSocket client joined / left the message hub
+ *  -- message: data received from a hub client
+ *  -- broadcast: console message sent to the hub clients
+ *  -- http: request answered by the HTTP handler
+ */
+pub fn publish(kind: &'static str, data: Value) {
+    let mut log: MutexGuard<EventLog> = lock();
+    log.last_id += 1;
+    let id: u64 = log.last_id;
+    if log.backlog.len() == BACKLOG_SIZE {
+        log.backlog.pop_front();
+    }
+    log.backlog.push_back(Event { id, kind, data: data.to_string() });
+    drop(log);
+    hub().published.notify_all();
+}
+
+/*
+ * Open event stream - occupies a worker, the stream slot is released when the subscription is dropped
+ */
+pub struct Subscription {
+    last_id: u64,
+    _slot: StreamSlot,
+}
+
+/*
+ * last_event_id => events after it are replayed from the backlog (older ones are lost),
+ * an id from before a server restart (> last published) replays the whole backlog.
+ * Without it only new events are sent.
+ */
+pub fn subscribe(last_event_id: Option<u64>) -> Result<Subscription, String> {
+    let slot: StreamSlot = threadpool::acquire_stream()?;
+    let mut log: MutexGuard<EventLog> = lock();
+    log.subscribers += 1;
+    let last_id: u64 = match last_event_id {
+        Some(id) if id <= log.last_id => id,
+        Some(_) => 0,
+        None => log.last_id
+    };
+    println!("{}: New subscriber ({} open), resuming after event {}", IDENTIFICATOR, log.subscribers, last_id);
+    Ok(Subscription { last_id, _slot: slot })
+}
+
+impl Subscription {
+    /*
+     * text/event-stream format, blocks until the client goes away:
+     *
+     * id: {id}\n
+     * event: {kind}\n
+     * data: {json}\n
+     * \n
+     */
+    pub fn stream(mut self, writer: &mut dyn Write) -> io::Result<()> {
+        writer.write_all(format!("retry: {}\n\n", RETRY_MS).as_bytes())?;
+        writer.flush()?;
+        loop {
+            let events: Vec<String> = {
+                let mut log: MutexGuard<EventLog> = lock();
+                if log.last_id <= self.last_id {
+                    log = match hub().published.wait_timeout(log, Duration::from_secs(KEEP_ALIVE_INTERVAL)) {
+                        Ok((log, _timeout)) => log,
+                        Err(poisoned) => poisoned.into_inner().0
+                    };
+                }
+                let events: Vec<String> = log.backlog.iter()
+                    .filter(|event: &&Event| event.id > self.last_id)
+                    .map(|event: &Event| format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind, event.data))
+                    .collect();
+                self.last_id = self.last_id.max(log.last_id);
+                events
+            };
+            if events.is_empty() {
+                writer.write_all(b": keep-alive\n\n")?;
+            }
+            for event in events.iter() {
+                writer.write_all(event.as_bytes())?;
+            }
+            writer.flush()?;
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut log: MutexGuard<EventLog> = lock();
+        log.subscribers -= 1;
+        println!("{}: Subscriber left ({} open)", IDENTIFICATOR, log.subscribers);
+    }
+}
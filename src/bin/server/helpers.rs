@@ -1,4 +1,5 @@
 use std::{convert::TryInto, path::PathBuf};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 /*
  * converts vec to array by type annotation
  * "As of Rust 1.51 you can parameterize over an array's length."
@@ -12,4 +13,66 @@ pub fn append_to_path(p: PathBuf, s: &str) -> PathBuf {
     let mut p: std::ffi::OsString = p.into_os_string();
     p.push(s);
     p.into()
-}
\ No newline at end of file
+}
+
+/*
+ * HTTP-date (IMF-fixdate): Sun, 06 Nov 1994 08:49:37 GMT
+ */
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    match NaiveDateTime::parse_from_str(value.trim(), "%a, %d %b %Y %H:%M:%S GMT") {
+        Ok(naive) => Some(Utc.from_utc_datetime(&naive)),
+        Err(_) => None
+    }
+}
+
+/*
+ * Decodes %XX escapes - returns None on malformed escape or non-UTF-8 result
+ */
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes: &[u8] = input.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex: &str = input.get(i + 1..i + 3)?;
+            if !hex.bytes().all(|b: u8| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/*
+ * Escapes text inserted into HTML pages
+ */
+pub fn html_escape(input: &str) -> String {
+    let mut escaped: String = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/*
+ * SHA-256 as lowercase hex - how random tokens are stored (they're long enough not to need a salt)
+ */
+pub fn sha256_hex(input: &str) -> String {
+    openssl::sha::sha256(input.as_bytes()).iter().map(|byte: &u8| format!("{:02x}", byte)).collect()
+}
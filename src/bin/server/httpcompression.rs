@@ -0,0 +1,177 @@
+use std::io::{self, Write};
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::{HttpResponse, ResponseBody};
+
+/*
+ * Bodies smaller than this are sent as-is - compression overhead isn't worth it
+ */
+static MIN_COMPRESS_SIZE: usize = 1024;
+
+/*
+ * Supported content-codings in order of preference
+ */
+pub static ENCODINGS: [&str; 2] = ["gzip", "deflate"];
+
+/*
+ * Media types that are already compressed or must not be buffered ("{type}/" => whole type)
+ */
+static SKIP_MEDIA_TYPES: [&str; 18] = [
+    "image/",
+    "video/",
+    "audio/",
+    "font/woff",
+    "font/woff2",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-rar-compressed",
+    "application/x-7z-compressed",
+    "application/x-stuffit",
+    "application/java-archive",
+    "application/x-shockwave-flash",
+    "application/pdf",
+    "application/octet-stream",
+    "application/x-redhat-package-manager",
+    "multipart/byteranges",
+    /* events must reach the client as they happen */
+    "text/event-stream",
+];
+
+/*
+ * image/svg+xml is text
+ */
+fn is_compressible(content_type: &str) -> bool {
+    let media_type: String = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    if media_type.is_empty() {
+        return false;
+    }
+    if media_type == "image/svg+xml" {
+        return true;
+    }
+    !SKIP_MEDIA_TYPES.iter().any(|skip: &&str| {
+        if skip.ends_with('/') { media_type.starts_with(skip) } else { media_type == *skip }
+    })
+}
+
+/*
+ * q-value of the encoding in accept-encoding: gzip;q=1.0, deflate;q=0.5, *;q=0
+ * Not listed => value of "*" or 0.
+ */
+fn quality(accept_encoding: &str, encoding: &str) -> f32 {
+    let mut wildcard: f32 = 0.0;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name: String = params.next().unwrap_or("").trim().to_lowercase();
+        let q: f32 = params
+            .filter_map(|param: &str| param.trim().strip_prefix("q="))
+            .next()
+            .map(|q: &str| q.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        if name == encoding || (encoding == "gzip" && name == "x-gzip") {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+/*
+ * Best encoding accepted by the client, None => identity
+ */
+pub fn negotiate_encoding(request: &HttpRequest) -> Option<&'static str> {
+    let accept_encoding: &str = request.header("accept-encoding")?;
+    let mut best: Option<(&'static str, f32)> = None;
+    for encoding in ENCODINGS.iter() {
+        let q: f32 = quality(accept_encoding, encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/*
+ * Compressed representation has its own validator: "{etag}" => "{etag}-{encoding}"
+ */
+pub fn encoded_etag(etag: &str, encoding: &str) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), encoding)
+}
+
+fn encode<F: FnOnce(&mut dyn Write) -> io::Result<()>>(encoding: &str, writer: &mut dyn Write, write_body: F) -> io::Result<()> {
+    if encoding == "gzip" {
+        let mut encoder: GzEncoder<&mut dyn Write> = GzEncoder::new(writer, Compression::default());
+        write_body(&mut encoder)?;
+        encoder.finish()?;
+    } else {
+        /* HTTP "deflate" is the zlib format */
+        let mut encoder: ZlibEncoder<&mut dyn Write> = ZlibEncoder::new(writer, Compression::default());
+        write_body(&mut encoder)?;
+        encoder.finish()?;
+    }
+    Ok(())
+}
+
+/*
+ * Compresses the response body if the client accepts gzip/deflate:
+ *  -- only compressible content types (vary: accept-encoding is set for those even if not compressed)
+ *  -- buffered bodies & files under MIN_COMPRESS_SIZE are left alone
+ *  -- streamed bodies (incl. files) are compressed on the fly => transfer-encoding: chunked
+ *  -- partial content & responses with content-encoding already set are left alone
+ * Proxied responses never get here (their upstream picks the encoding), so the etag rewritten is always one of this server.
+ */
+pub fn compress_response(mut response: HttpResponse, request: &HttpRequest) -> HttpResponse {
+    if response.status < 200 || response.status == 204 || response.status == 206 || response.header("content-encoding").is_some() {
+        return response;
+    }
+    if !is_compressible(response.header("content-type").unwrap_or("")) {
+        return response;
+    }
+    response.add_vary("accept-encoding");
+    let encoding: &'static str = match negotiate_encoding(request) {
+        Some(encoding) => encoding,
+        None => return response
+    };
+    let body: ResponseBody = std::mem::replace(&mut response.body, ResponseBody::Bytes(Vec::new()));
+    response.body = match body {
+        /* 304 has no body, but validator must match the representation client has */
+        body if response.status == 304 => body,
+        ResponseBody::Bytes(data) if data.len() < MIN_COMPRESS_SIZE => {
+            response.body = ResponseBody::Bytes(data);
+            return response;
+        },
+        ResponseBody::SizedStream(len, body_writer) if len < MIN_COMPRESS_SIZE as u64 => {
+            response.body = ResponseBody::SizedStream(len, body_writer);
+            return response;
+        },
+        ResponseBody::Bytes(data) => {
+            let len: usize = data.len();
+            let mut compressed: Vec<u8> = Vec::new();
+            match encode(encoding, &mut compressed, |writer: &mut dyn Write| writer.write_all(&data)) {
+                Ok(()) => {
+                    println!("http-response: Compressed {} => {} bytes ({})", len, compressed.len(), encoding);
+                    ResponseBody::Bytes(compressed)
+                },
+                /* sent uncompressed instead */
+                Err(e) => {
+                    println!("http-response: Error compressing response: {}", e);
+                    response.body = ResponseBody::Bytes(data);
+                    return response;
+                }
+            }
+        },
+        ResponseBody::SizedStream(_, body_writer) | ResponseBody::Stream(body_writer) => {
+            ResponseBody::Stream(Box::new(move |writer: &mut dyn Write| encode(encoding, writer, body_writer)))
+        }
+    };
+    if let Some(etag) = response.header("etag").map(str::to_string) {
+        response.set_header("etag", &encoded_etag(&etag, encoding));
+    }
+    if response.status != 304 {
+        response.set_header("content-encoding", encoding);
+    }
+    response
+}
@@ -1,283 +1,314 @@
-use std::collections::HashMap;
-use std::fs;
-use std::net::TcpStream;
-use std::io::Write;
-use chrono::{DateTime, Local};
-use crate::server::cstmfiles;
-use crate::server::cstmconfig::{AssetsConfig, BaseConfig};
-use crate::server::database;
+use std::io::BufReader;
+use std::sync::OnceLock;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use crate::server::eventstream;
+use crate::server::cstmconfig;
+use crate::server::helpers;
+use crate::server::httpcompression;
+use crate::server::httphandlers;
+use crate::server::middleware::{self, Pipeline, RequestContext, SecurityHeaders};
+use crate::server::staticfiles;
+use crate::server::threadpool;
+use crate::server::router::{Router, RouteMatch};
 use crate::server::validator;
+use crate::server::virtualhost;
+use crate::server::httprequest::{HttpRequest, RequestError};
+use crate::server::httperror::HttpError;
+use crate::server::httpresponse::HttpResponse;
+use crate::server::tlsstream::ServerStream;
+use serde_json::json;
 
-fn fetch_get_routes() -> Vec<&'static str> {
-    vec![
-        "/",
-        "/users",
-        "/tokens",
-        "/tables"
-    ]
+/*
+ * Persistent connections hold a worker while idle:
+ *  -- seconds to wait for the next request before closing the connection
+ *  -- max number of requests served on a single connection
+ * Keep-alive isn't offered while every worker is taken (threadpool::is_busy).
+ */
+static KEEP_ALIVE_TIMEOUT: u64 = 2;
+static KEEP_ALIVE_MAX_REQUESTS: usize = 100;
+
+/*
+ * Handlers are registered per method and route pattern,
+ * params from {name} segments are passed to the handler.
+ * Built once (at startup) & shared by all workers.
+ */
+pub fn router() -> &'static Router {
+    static ROUTER: OnceLock<Router> = OnceLock::new();
+    ROUTER.get_or_init(build_router)
 }
-fn fetch_post_routes() -> Vec<&'static str> {
+
+fn build_router() -> Router {
+    let mut router: Router = Router::new();
+    router
+        .get("/", httphandlers::index)
+        .post("/", httphandlers::index)
+        .get("/users", httphandlers::list_users)
+        .post("/users", httphandlers::create_user)
+        .get("/users/{id}", httphandlers::show_user)
+        .put("/users/{id}", httphandlers::replace_user)
+        .patch("/users/{id}", httphandlers::modify_user)
+        .delete("/users/{id}", httphandlers::delete_user)
+        .get("/tokens", httphandlers::list_tokens)
+        .post("/tokens", httphandlers::create_token)
+        .get("/tokens/{id}", httphandlers::show_token)
+        .put("/tokens/{id}", httphandlers::replace_token)
+        .patch("/tokens/{id}", httphandlers::modify_token)
+        .delete("/tokens/{id}", httphandlers::delete_token)
+        .get("/roles", httphandlers::list_roles)
+        .post("/roles", httphandlers::create_role)
+        .get("/roles/{id}", httphandlers::show_role)
+        .put("/roles/{id}", httphandlers::replace_role)
+        .patch("/roles/{id}", httphandlers::modify_role)
+        .delete("/roles/{id}", httphandlers::delete_role)
+        .get("/connected", httphandlers::list_connected)
+        .post("/connected", httphandlers::create_connected)
+        .get("/connected/{id}", httphandlers::show_connected)
+        .put("/connected/{id}", httphandlers::replace_connected)
+        .patch("/connected/{id}", httphandlers::modify_connected)
+        .delete("/connected/{id}", httphandlers::delete_connected)
+        .post("/tables", httphandlers::create_tables)
+        .get("/login", httphandlers::login_form)
+        .post("/login", httphandlers::login)
+        .post("/logout", httphandlers::logout)
+        .get("/ws", httphandlers::websocket)
+        .get("/events", httphandlers::events);
+    router
+}
+/*
+ * Security headers, CORS etc. are added by the middlewares
+ */
+fn fetch_headers() -> Vec<(String, String)> {
+    let now: DateTime<Utc> = Utc::now();
     vec![
-        "/",
-        "/users",
-        "/tokens",
-        "/tables"
+        (String::from("content-type"), String::from("text/html; charset=utf-8")),
+        (String::from("date"), helpers::http_date(now)),
     ]
 }
-fn fetch_empty_routes() -> Vec<&'static str> {
-    return vec![]
-}
-fn fetch_headers(contents_len: usize) -> [String; 10] {
-    let now: DateTime<Local> = Local::now();
-    return [
-        String::from("content-type: text/html; charset=utf-8"),
-        format!("content-length: {}", contents_len),
-        format!("date: {}", now.to_rfc2822()),
-        String::from("cross-origin-embedder-policy: require-corp"),
-        String::from("cross-origin-opener-policy: cross-origin"),
-        String::from("cross-origin-resource-policy: same-origin"),
-        String::from("x-content-type-options: nosniff"),
-        String::from("x-frame-options: deny"),
-        String::from("referer-policy: no-referer"),
-        String::from("content-security-policy: connect-src 'self'; font-src 'self'; frame-src 'self'; img-src 'self'; manifest-src 'self'; media-src 'self'; object-src 'self'; script-src 'self'; style-src 'unsafe-inline'; worker-src 'self'"),
-    ];
+
+fn fetch_connection_headers(keep_alive: bool, requests_left: usize) -> Vec<(String, String)> {
+    if keep_alive {
+        vec![
+            (String::from("connection"), String::from("keep-alive")),
+            (String::from("keep-alive"), format!("timeout={}, max={}", KEEP_ALIVE_TIMEOUT, requests_left)),
+        ]
+    } else {
+        vec![(String::from("connection"), String::from("close"))]
+    }
 }
 
 
-fn parse_request_parameters(buffer: &str) -> HashMap<&str, &str> {
-    let lines: Vec<&str> = buffer.split("\r\n").collect::<Vec<&str>>();
-    match lines.last() {
-        Some(element) => {
-            let req_params: Vec<&str> = element.split('&').collect::<Vec<&str>>();
-            let mut params: HashMap<&str, &str> = std::collections::HashMap::new();
-            for param in req_params.iter() {
-                let k_v: (&str, &str) = param.split_once('=').unwrap();
-                params.insert(k_v.0, k_v.1);
-            }
-            params
-        },        
-        None => std::collections::HashMap::new()
+/*
+ * Methods allowed for the path: routes registered for it or static file (GET, HEAD),
+ * limited to methods enabled in SERVER.REQUEST_METHODS
+ */
+fn fetch_allowed_methods(router: &Router, request: &HttpRequest) -> Vec<String> {
+    let mut allowed: Vec<String> = router.allowed_methods(request.path());
+    if allowed.is_empty() && staticfiles::file_exists(request) {
+        allowed = vec![String::from("GET"), String::from("HEAD"), String::from("OPTIONS")];
     }
+    allowed.retain(|method: &String| validator::validate_request_method(method).is_ok());
+    allowed
 }
 
-fn build_http_response(buffer: &str) -> Result<(&str,&str,Vec<&str>,String,String), String> {
-    let get_routes = |req_method: &str| -> Vec<&str> {
-        match validator::validate_request_method(&req_method) {
-            Ok(()) => {
-                if req_method == "GET" {
-                    fetch_get_routes()
-                } else if req_method == "POST" {
-                    fetch_post_routes()
-                } else {
-                    fetch_empty_routes()
-                }
-            },
-            Err(_) => {
-                fetch_empty_routes()
-            }
-        }
-    };
-    let res_ok: String = format!("{} 200 OK", BaseConfig::new_cfg().http_protocol);
-    let assets_cfg: AssetsConfig = AssetsConfig::new_cfg();
-    /*
-     * use 1st tuple val of buffer, drop the rest as
-     * req_method|route|http_proto are always first in HTTP request
-     */
-    let http_req: Vec<&str> = match validator::validate_http_request(&buffer) {
-        Ok(http_request_parsed) => http_request_parsed,
-        Err(e) => return Err(format!("http-response: Error validating HTTP request: {}", e))
+/*
+ * OPTIONS {path} => 204 + allow header, 404 if nothing is served on the path
+ * OPTIONS *      => 204 + all methods enabled in SERVER.REQUEST_METHODS
+ * CORS preflight headers are added by the Cors middleware.
+ */
+fn build_options_response(request: &HttpRequest, router: &Router) -> Result<HttpResponse, HttpError> {
+    let allowed: Vec<String> = if request.target == "*" {
+        cstmconfig::server().request_methods.clone()
+    } else {
+        fetch_allowed_methods(router, request)
     };
-    let req_method: &str = http_req[0];
-    let req_route: &str = http_req[1];
-    let routes: Vec<&str> = get_routes(req_method);
+    if allowed.is_empty() {
+        return Err(HttpError::NotFound);
+    }
+    Ok(HttpResponse::new(204, Vec::new()).with_header("allow", &allowed.join(", ")))
+}
 
-    match validator::validate_route(&req_route, &routes) {
-        Ok(()) => {},
+/*
+ * Dispatches request to the handler registered for its method & path:
+ *  -- method not enabled in SERVER.REQUEST_METHODS => 501
+ *  -- OPTIONS => allowed methods for the path (+ CORS preflight)
+ *  -- HEAD => handled as GET, body is dropped when writing the response
+ *  -- no route for the path => static file from the site's document root (GET/HEAD only) or 404
+ *  -- paths outside VHOST.{NAME}.ROUTES of the virtual host have no routes
+ *  -- route exists for other methods only => 405 + allow header
+ *  -- handler failure => error page / JSON error for its HttpError
+ */
+fn build_http_response(request: &HttpRequest) -> HttpResponse {
+    let result: Result<HttpResponse, HttpError> = match validator::validate_request_method(&request.method) {
+        Ok(()) => route_request(request),
         Err(e) => {
-            return Err(format!("http-response: Error validating HTTP route: {}", e));
+            println!("{} [{}]", e, request.method);
+            Err(HttpError::NotImplemented(format!("Method {} is not implemented", request.method)))
         }
+    };
+    match result {
+        Ok(response) => response,
+        Err(e) => e.into_response(Some(request))
     }
+}
 
-    let (status_line, view_file) = 
-    if req_route == routes[0] {
-        (res_ok, format!("{}page.html", assets_cfg.html_base_path))
-    } else if req_route == routes[1] {
-        (res_ok, format!("{}users.html", assets_cfg.html_base_path))
-    } else if req_route == routes[2] {
-        (res_ok, format!("{}tokens.html", assets_cfg.html_base_path))
+fn route_request(request: &HttpRequest) -> Result<HttpResponse, HttpError> {
+    /* site without the built-in routes => views & static files only */
+    let no_routes: Router = Router::new();
+    let router: &Router = if virtualhost::serves_route(&virtualhost::site(Some(request)), request.path()) {
+        router()
     } else {
-        (res_ok, format!("{}notfound.html", assets_cfg.html_base_path))
+        &no_routes
     };
+    if request.method == "OPTIONS" {
+        return build_options_response(request, router);
+    }
+    match router.route(&request.method, request.path()) {
+        RouteMatch::Found(handler, params) => handler(request, &params),
+        RouteMatch::MethodNotAllowed(mut allowed) => {
+            allowed.retain(|method: &String| validator::validate_request_method(method).is_ok());
+            Err(HttpError::MethodNotAllowed(allowed))
+        },
+        RouteMatch::NotFound => {
+            let static_file: Option<HttpResponse> = if request.method == "GET" || request.method == "HEAD" {
+                staticfiles::serve_file(request)
+            } else {
+                None
+            };
+            static_file.ok_or(HttpError::NotFound)
+        },
+    }
+}
 
-    Ok((req_method, req_route, routes, status_line, view_file))
+/*
+ * How the connection ended - upgraded connection is handed over to the TCP message hub
+ * together with bytes the client sent right after the handshake
+ */
+pub enum HttpConnectionEnd {
+    Closed,
+    Upgraded(Vec<u8>),
 }
 
-pub fn write_http_response(mut stream: &TcpStream, buffer: &str) -> Result<(), String> {
-    let mut response_data: String = String::new();
-    let mut response: String = String::new();
-    let mut contents_all: String = String::new();
-    let assets_cfg: AssetsConfig = AssetsConfig::new_cfg();
-    let fpath: String = assets_cfg.log_dir+"/"+&assets_cfg.log_path;
-    let (req_method, route, routes, mut status_line, view_file): 
-        (&str, &str, Vec<&str>, String, String) = match build_http_response(&buffer) {
-            Ok((rm, rt, rts, sl, vf)) => (rm, rt, rts, sl, vf),
-            Err(e) => {
-                stream.shutdown(std::net::Shutdown::Write).unwrap();
-                return Err(format!("http-response: Error building response: {}", e));
-            }
-        };
+/*
+ * What happens with the connection after a response was written
+ */
+enum AfterResponse {
+    KeepAlive,
+    Close,
+    Upgrade,
+}
 
-    match process_request(&req_method, &route, &routes, &buffer) {
-        Ok(res_data) => {
-            response_data = res_data;
-        },
-        Err(e) => {
-            //stream.shutdown(std::net::Shutdown::Write).unwrap();
-            println!("http-response: Error processing request: {}", e);
-            status_line = format!("{} 500 Internal Server Error", BaseConfig::new_cfg().http_protocol);
-            contents_all = String::from("500 Custom Server Error");
-        }
+/*
+ * Serves requests from the stream until the client closes the connection:
+ *  -- HTTP/1.1 is persistent unless client sends "connection: close"
+ *  -- HTTP/1.0 is persistent only if client sends "connection: keep-alive"
+ *  -- pipelined requests are answered in order from the same buffered reader
+ *  -- connection is closed after KEEP_ALIVE_TIMEOUT idle seconds, KEEP_ALIVE_MAX_REQUESTS requests or when the pool is busy
+ *  -- 101 Switching Protocols (WebSocket) ends HTTP on the connection
+ * Malformed or oversized requests are answered with 400/413 and close the connection.
+ */
+pub fn handle_http_connection(stream: &ServerStream) -> Result<HttpConnectionEnd, String> {
+    match stream.set_read_timeout(Some(Duration::from_secs(KEEP_ALIVE_TIMEOUT))) {
+        Ok(()) => {},
+        Err(e) => return Err(format!("http-request: Error setting read timeout: {}", e))
     }
-
-    // if error is reached before => don't read html page
-    if contents_all.is_empty() {
-        match fs::read_to_string(view_file) {
-            Ok(contents) => {
-                contents_all = format!("{}{}", contents, response_data);
+    let mut reader: BufReader<&ServerStream> = BufReader::new(stream);
+    let mut served: usize = 0;
+    loop {
+        let request: HttpRequest = match HttpRequest::read_from(&mut reader) {
+            Ok(request) => request,
+            Err(RequestError::Closed) => {
+                println!("http-request: Connection closed by client after {} request(s).", served);
+                return Ok(HttpConnectionEnd::Closed);
+            },
+            Err(RequestError::Timeout) => {
+                println!("http-request: Connection idle for {}s after {} request(s) - closing.", KEEP_ALIVE_TIMEOUT, served);
+                return Ok(HttpConnectionEnd::Closed);
+            },
+            Err(RequestError::BadRequest(e)) => {
+                write_error_response(stream, HttpError::BadRequest(e.clone()))?;
+                return Err(format!("http-request: Bad request: {}", e));
             },
-            Err(e) => {
-                // file read error
-                println!("http-response: Error opening content file: {}", e);
-                contents_all = String::from("500 Custom Server Error");
-                status_line = format!("{} 500 Internal Server Error", BaseConfig::new_cfg().http_protocol);
-            }
+            Err(RequestError::PayloadTooLarge(e)) => {
+                write_error_response(stream, HttpError::PayloadTooLarge(e.clone()))?;
+                return Err(format!("http-request: Payload too large: {}", e));
+            },
+            Err(RequestError::NotImplemented(e)) => {
+                write_error_response(stream, HttpError::NotImplemented(e.clone()))?;
+                return Err(format!("http-request: Not implemented: {}", e));
+            },
+            Err(e) => return Err(format!("http-request: Error reading request: {}", e))
+        };
+        served += 1;
+        let keep_alive: bool = request.wants_keep_alive() && served < KEEP_ALIVE_MAX_REQUESTS && !threadpool::is_busy();
+        match write_http_response(stream, request, keep_alive, KEEP_ALIVE_MAX_REQUESTS - served)? {
+            AfterResponse::KeepAlive => {},
+            AfterResponse::Close => return Ok(HttpConnectionEnd::Closed),
+            AfterResponse::Upgrade => return Ok(HttpConnectionEnd::Upgraded(reader.buffer().to_vec()))
         }
     }
+}
 
-    let headers: [String; 10] = fetch_headers(contents_all.len());
-    /*
-     * HTTP text-based protocol response format:
-     * 
-     * {HTTP/1.1 200 OK}\r\n
-     * {HEADERS}\r\n
-     * {CONTENT}
-     */
-    response.push_str(format!(
-        "{}\r\n{}\r\n\r\n{}",
-        status_line,
-        headers.iter().map(|val| val.to_string() + "\r\n").collect::<String>(),
-        contents_all,
-    ).as_str());
-
-    match stream.write(response.as_bytes()) {
-        Ok(bytes) => {
-            let msg: String = format!(
-                "[{}]: http-response: Successfuly written {} bytes to stream.",
-                Local::now().to_rfc3339(),
-                bytes
-            );
-            println!("{}", &msg);
-            match cstmfiles::f_write(&fpath, msg) {
-                Ok(()) => {}
-                Err(e) => { println!("Oops! Error writing to log! {:?}", e); }
-            }
-        }, 
-        Err(e) => {
-            println!("http-response: Error writing to stream: {}", e);
-        }
+/*
+ * Request couldn't be read => error page & connection is closed
+ */
+fn write_error_response(mut stream: &ServerStream, error: HttpError) -> Result<(), String> {
+    let mut response: HttpResponse = error.into_response(None);
+    let error_headers: Vec<(String, String)> = std::mem::take(&mut response.headers);
+    response.headers = fetch_headers();
+    for (name, value) in error_headers.iter().chain(fetch_connection_headers(false, 0).iter()) {
+        response.set_header(name, value);
     }
-    // flush() ensures all data is written on the stream
-    match stream.flush() {
-        Ok(()) => {}, 
-        Err(e) => {
-            println!("http-response: Error flushing stream: {}", e);
-        }
+    SecurityHeaders::new(cstmconfig::security()).apply(&mut response, None, None, stream.is_tls());
+    match response.write_to(&mut stream, &cstmconfig::base().http_protocol, false, false) {
+        Ok(_bytes) => Ok(()),
+        Err(e) => Err(format!("http-response: Error writing to stream: {}", e))
     }
-    Ok(())
 }
 
-fn process_request(request_method: &str, route: &str, routes: &Vec<&str>, buffer: &str) -> Result<String, String> {
-    let mut response_data : String = String::new();
-    // SELECT on GET | INSERT on POST
-    if request_method == "POST" {
-        let params: HashMap<&str, &str> = parse_request_parameters(&buffer);
-        
-        if route == routes[1] {
-            match database::User::create_users(params) {
-                Ok(()) => {},
-                Err(e) => {
-                    let errmsg: String = format!("request: Error inserting users: {}", e);
-                    println!("{}", &errmsg);
-                    return Err(errmsg);
-                }
-            }
-        }
-        else if route == routes[2] {
-            match database::Token::create_tokens(params) {
-                Ok(()) => {},
-                Err(e) => {
-                    let errmsg: String = format!("request: Error inserting tokens: {}", e);
-                    println!("{}", &errmsg);
-                    return Err(errmsg);
-                }
-            }
-        }
-        else if route == routes[0] { /*** (default route '/') */
-            response_data = String::from("Default route - default response :3")
-        }
-        else if route == routes[3] {
-            match database::create_tables() {
-                Ok(()) => {
-                    response_data.push_str("Tables created successfuly!");
-                },
-                Err(e) => {
-                    println!("SQL Error creating table: {}", e)
-                }
-            }
-        }
-        else {
-            return Err(String::from(format!("request: Invalid route {}", route)))
-        }
+/*
+ * Answers a single request through the middleware pipeline. Returns whether the connection can stay open -
+ * a streamed response to an HTTP/1.0 client is terminated by closing the connection.
+ */
+fn write_http_response(mut stream: &ServerStream, request: HttpRequest, keep_alive: bool, requests_left: usize) -> Result<AfterResponse, String> {
+    let pipeline: &Pipeline = middleware::pipeline();
+    let mut ctx: RequestContext = RequestContext::new(stream.peer_addr().ok(), stream.is_tls());
+    let (request, mut response): (HttpRequest, HttpResponse) = pipeline.handle(request, &mut ctx, build_http_response);
+
+    /* headers set by the handler override the defaults, repeated ones (set-cookie from an upstream) are kept */
+    let handler_headers: Vec<(String, String)> = std::mem::take(&mut response.headers);
+    response.headers = fetch_headers();
+    response.headers.retain(|(name, _)| !handler_headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)));
+    response.headers.extend(handler_headers);
+    let mut response: HttpResponse = if ctx.proxied { response } else { httpcompression::compress_response(response, &request) };
+
+    let chunked: bool = request.version == "HTTP/1.1";
+    let head_only: bool = request.method == "HEAD";
+    let keep_alive: bool = keep_alive && (chunked || head_only || !response.is_streamed());
+    let upgrade: bool = response.status == 101;
+    if let Some(saddr) = ctx.peer {
+        eventstream::publish("http", json!({
+            "ip": saddr.ip().to_string(), "port": saddr.port(),
+            "method": request.method, "target": request.target, "status": response.status
+        }));
     }
-    else if request_method == "GET" {
-        if route == routes[1] {
-            match database::User::select_all() {
-                Ok(u) => {
-                    let mut usersstr: String = String::new();
-                    for user in u.iter() {
-                        usersstr.push_str(database::User::user_to_string(&user).as_str());
-                    }
-                    response_data.push_str(usersstr.as_str());
-                },
-                Err(e) => {
-                    println!("request: Error selecting users: {:?}", e);
-                }
-            }
+    if !upgrade {
+        for (name, value) in fetch_connection_headers(keep_alive, requests_left).iter() {
+            response.set_header(name, value);
         }
-        else if route == routes[2] {
-            match database::Token::select_all() {
-                Ok(t) => {
-                    let mut tokensstr = String::new();
-                    for token in t.iter() {
-                        tokensstr.push_str(database::Token::token_to_string(&token).as_str());
-                    }
-                    response_data.push_str(tokensstr.as_str());
-                },
-                Err(e) => {
-                    println!("request: Error selecting tokens: {:?}", e);
-                }
-            }
-        }
-        else if route == routes[0] { /*** (default route '/') */
-            response_data = String::from("Default route - default response :3")
-        }
-        else {
-            return Err(String::from(format!("request: Invalid route {}", route)))
+    }
+
+    let status: u16 = response.status;
+    match response.write_to(&mut stream, &cstmconfig::base().http_protocol, chunked, head_only) {
+        Ok(bytes) => pipeline.complete(&request, &ctx, status, bytes),
+        Err(e) => {
+            println!("http-response: Error writing to stream: {}", e);
+            return Ok(AfterResponse::Close);
         }
     }
-    else {
-        return Err(String::from(format!("request: Invalid request method [{}]: supporting only GET|POST", request_method)))
+    match (upgrade, keep_alive) {
+        (true, _) => Ok(AfterResponse::Upgrade),
+        (false, true) => Ok(AfterResponse::KeepAlive),
+        (false, false) => Ok(AfterResponse::Close)
     }
-
-    Ok(response_data)
 }
@@ -0,0 +1,212 @@
+use std::fmt;
+use serde_json::{json, Value};
+use crate::server::cstmconfig::VirtualHost;
+use crate::server::database::RowError;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::{self, HttpResponse};
+use crate::server::templates;
+use crate::server::virtualhost;
+
+/*
+ * Failures answered to the client - each maps to a status and a machine-readable code:
+ *  -- JSON clients get {"error": {"status": .., "code": .., "message": ..}}
+ *  -- browsers get APP.ERROR_PAGE from the views of APP.HTML_BASE_PATH (notfound.html for 404) - or the virtual host's error page & document root
+ * Details of 500, 502, 503 & 504 are only logged, never sent.
+ */
+#[derive(Debug)]
+pub enum HttpError {
+    BadRequest(String),
+    /* missing or invalid bearer token => www-authenticate header */
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound,
+    /* carries methods allowed for the path => allow header */
+    MethodNotAllowed(Vec<String>),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    UnprocessableEntity(String),
+    /* plain request on a WebSocket endpoint => upgrade header */
+    UpgradeRequired(String),
+    /* seconds until the client may retry => retry-after header */
+    TooManyRequests(u64),
+    Internal(String),
+    NotImplemented(String),
+    /* upstream of a proxied route failed or sent garbage */
+    BadGateway(String),
+    ServiceUnavailable(String),
+    /* upstream of a proxied route didn't answer in time */
+    GatewayTimeout(String),
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::BadRequest(_) => 400,
+            HttpError::Unauthorized(_) => 401,
+            HttpError::Forbidden(_) => 403,
+            HttpError::NotFound => 404,
+            HttpError::MethodNotAllowed(_) => 405,
+            HttpError::Conflict(_) => 409,
+            HttpError::PayloadTooLarge(_) => 413,
+            HttpError::UnsupportedMediaType(_) => 415,
+            HttpError::UnprocessableEntity(_) => 422,
+            HttpError::UpgradeRequired(_) => 426,
+            HttpError::TooManyRequests(_) => 429,
+            HttpError::Internal(_) => 500,
+            HttpError::NotImplemented(_) => 501,
+            HttpError::BadGateway(_) => 502,
+            HttpError::ServiceUnavailable(_) => 503,
+            HttpError::GatewayTimeout(_) => 504,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            HttpError::BadRequest(_) => "bad_request",
+            HttpError::Unauthorized(_) => "unauthorized",
+            HttpError::Forbidden(_) => "forbidden",
+            HttpError::NotFound => "not_found",
+            HttpError::MethodNotAllowed(_) => "method_not_allowed",
+            HttpError::Conflict(_) => "conflict",
+            HttpError::PayloadTooLarge(_) => "payload_too_large",
+            HttpError::UnsupportedMediaType(_) => "unsupported_media_type",
+            HttpError::UnprocessableEntity(_) => "unprocessable_entity",
+            HttpError::UpgradeRequired(_) => "upgrade_required",
+            HttpError::TooManyRequests(_) => "too_many_requests",
+            HttpError::Internal(_) => "internal_error",
+            HttpError::NotImplemented(_) => "not_implemented",
+            HttpError::BadGateway(_) => "bad_gateway",
+            HttpError::ServiceUnavailable(_) => "service_unavailable",
+            HttpError::GatewayTimeout(_) => "gateway_timeout",
+        }
+    }
+
+    /*
+     * Message safe to show to the client
+     */
+    pub fn message(&self) -> String {
+        match self {
+            HttpError::BadRequest(e)
+            | HttpError::Unauthorized(e)
+            | HttpError::Forbidden(e)
+            | HttpError::Conflict(e)
+            | HttpError::PayloadTooLarge(e)
+            | HttpError::UnsupportedMediaType(e)
+            | HttpError::UnprocessableEntity(e)
+            | HttpError::UpgradeRequired(e)
+            | HttpError::NotImplemented(e) => e.clone(),
+            HttpError::MethodNotAllowed(allowed) => format!("Allowed methods: {}", allowed.join(", ")),
+            HttpError::TooManyRequests(retry_after) => format!("Rate limit exceeded - retry in {}s", retry_after),
+            HttpError::NotFound
+            | HttpError::Internal(_)
+            | HttpError::BadGateway(_)
+            | HttpError::ServiceUnavailable(_)
+            | HttpError::GatewayTimeout(_) => httpresponse::reason_phrase(self.status()).to_string(),
+        }
+    }
+
+    /*
+     * request is None if the request itself couldn't be read (=> HTML page)
+     */
+    pub fn into_response(self, request: Option<&HttpRequest>) -> HttpResponse {
+        println!("http-response: {} [{}]", self, self.code());
+        let status: u16 = self.status();
+        let mut response: HttpResponse = if request.is_some_and(|request: &HttpRequest| request.wants_json()) {
+            let body: String = json!({
+                "error": { "status": status, "code": self.code(), "message": self.message() }
+            }).to_string();
+            HttpResponse::new(status, body.into_bytes()).with_header("content-type", "application/json")
+        } else {
+            match self.render_page(request) {
+                Some(page) => HttpResponse::new(status, page),
+                None => HttpResponse::new(status, format!("{} {}", status, httpresponse::reason_phrase(status)).into_bytes())
+                    .with_header("content-type", "text/plain; charset=utf-8")
+            }
+        };
+        response.set_header("vary", "accept");
+        if let HttpError::MethodNotAllowed(allowed) = &self {
+            response.set_header("allow", &allowed.join(", "));
+        }
+        if let HttpError::TooManyRequests(retry_after) = &self {
+            response.set_header("retry-after", &retry_after.to_string());
+        }
+        if let HttpError::Unauthorized(_) = &self {
+            response.set_header("www-authenticate", "Bearer");
+        }
+        if let HttpError::UpgradeRequired(_) = &self {
+            response.set_header("upgrade", "websocket");
+            response.set_header("sec-websocket-version", "13");
+        }
+        response
+    }
+
+    /*
+     * Page template context: {{ status }}, {{ reason }}, {{ code }}, {{ message }}, {{ csp_nonce }}
+     */
+    fn render_page(&self, request: Option<&HttpRequest>) -> Option<Vec<u8>> {
+        let site: VirtualHost = virtualhost::site(request);
+        let mut pages: Vec<&str> = Vec::new();
+        if self.status() == 404 {
+            pages.push("notfound.html");
+        }
+        pages.push(&site.error_page);
+        let context: Value = json!({
+            "status": self.status(),
+            "reason": httpresponse::reason_phrase(self.status()),
+            "code": self.code(),
+            "message": self.message(),
+            "csp_nonce": request.and_then(|request: &HttpRequest| request.csp_nonce.as_deref()),
+        });
+        for page in pages {
+            match templates::load(&site.html_base_path, page) {
+                Ok(template) => return Some(template.render_to_vec(&context)),
+                Err(e) => println!("http-response: {}", e)
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Internal(e)
+            | HttpError::BadGateway(e)
+            | HttpError::ServiceUnavailable(e)
+            | HttpError::GatewayTimeout(e) => {
+                write!(f, "{} {}: {}", self.status(), httpresponse::reason_phrase(self.status()), e)
+            },
+            _ => write!(f, "{} {}: {}", self.status(), httpresponse::reason_phrase(self.status()), self.message())
+        }
+    }
+}
+
+/* handler failures not mapped to a specific status */
+impl From<String> for HttpError {
+    fn from(e: String) -> HttpError {
+        HttpError::Internal(e)
+    }
+}
+
+/* database unreachable or too slow for the request's Timeout => 503 */
+impl From<mysql::Error> for HttpError {
+    fn from(e: mysql::Error) -> HttpError {
+        match e {
+            mysql::Error::DriverError(_) | mysql::Error::IoError(_) | mysql::Error::CodecError(_) => HttpError::ServiceUnavailable(format!("SQL: {}", e)),
+            e => HttpError::Internal(format!("SQL: {}", e))
+        }
+    }
+}
+
+impl From<RowError> for HttpError {
+    fn from(e: RowError) -> HttpError {
+        match e {
+            RowError::NotFound => HttpError::NotFound,
+            RowError::Invalid(e) => HttpError::UnprocessableEntity(e),
+            RowError::Conflict(e) => HttpError::Conflict(e),
+            RowError::Internal(e) => HttpError::Internal(e),
+            RowError::Sql(e) => HttpError::from(e)
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use crate::server::database::{self, RowResult};
+use crate::server::eventstream::{self, Subscription};
+use crate::server::httperror::HttpError;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::{HttpResponse, ResponseBody};
+use crate::server::passwords;
+use crate::server::router::Params;
+use crate::server::sessions;
+use crate::server::templates::{self, Template};
+use crate::server::threadpool;
+use crate::server::virtualhost;
+use crate::server::websocket;
+use serde_json::{json, Value};
+
+/*
+ * Request body => parameters, depending on content-type:
+ *  -- application/x-www-form-urlencoded (default): a=b&c=d, percent-decoded ("+" => space)
+ *  -- application/json: object - strings as-is, other values as JSON text, null is skipped
+ *  -- multipart/form-data: text fields + file fields as path of the stored upload
+ * Other content types => 415
+ */
+fn parse_request_parameters(request: &HttpRequest) -> Result<HashMap<String, String>, HttpError> {
+    if let Some(multipart) = &request.multipart {
+        let mut params: HashMap<String, String> = multipart.fields.clone();
+        for file in multipart.files.iter() {
+            params.insert(file.field.clone(), file.path.to_string_lossy().to_string());
+        }
+        return Ok(params);
+    }
+    let media_type: Option<String> = request.header("content-type")
+        .map(|content_type: &str| content_type.split(';').next().unwrap_or("").trim().to_lowercase());
+    match media_type.as_deref() {
+        None | Some("application/x-www-form-urlencoded") => {
+            let body: &str = match std::str::from_utf8(&request.body) {
+                Ok(body) => body,
+                Err(e) => return Err(HttpError::BadRequest(format!("Request body is not valid UTF-8: {}", e)))
+            };
+            Ok(url::form_urlencoded::parse(body.as_bytes()).into_owned().collect())
+        },
+        Some("application/json") => parse_json_parameters(&request.body),
+        Some("multipart/form-data") => Err(HttpError::BadRequest(String::from("Missing or invalid multipart boundary"))),
+        Some(media_type) => Err(HttpError::UnsupportedMediaType(format!("Unsupported content type {}", media_type)))
+    }
+}
+
+fn parse_json_parameters(body: &[u8]) -> Result<HashMap<String, String>, HttpError> {
+    let object: serde_json::Map<String, Value> = match serde_json::from_slice::<Value>(body) {
+        Ok(Value::Object(object)) => object,
+        Ok(_) => return Err(HttpError::UnprocessableEntity(String::from("JSON body must be an object"))),
+        Err(e) => return Err(HttpError::BadRequest(format!("Invalid JSON body: {}", e)))
+    };
+    let mut params: HashMap<String, String> = HashMap::new();
+    for (key, value) in object {
+        match value {
+            Value::Null => {},
+            Value::String(value) => { params.insert(key, value); },
+            value => { params.insert(key, value.to_string()); }
+        }
+    }
+    Ok(params)
+}
+
+/*
+ * Uploads are removed with the request unless the row referencing them was written
+ */
+fn keep_uploads(request: &HttpRequest) {
+    if let Some(multipart) = &request.multipart {
+        for file in multipart.files.iter() {
+            file.keep();
+        }
+    }
+}
+
+/*
+ * Every view gets {{ csp_nonce }} for inline scripts & styles
+ */
+fn view_context(request: &HttpRequest, mut context: Value) -> Value {
+    if let Value::Object(object) = &mut context {
+        object.insert(String::from("csp_nonce"), json!(request.csp_nonce));
+    }
+    context
+}
+
+/*
+ * Renders the view template from the document root of the request's site with the context
+ */
+fn render_view(request: &HttpRequest, status: u16, view: &str, context: Value) -> Result<HttpResponse, HttpError> {
+    let template: Arc<Template> = templates::load(&virtualhost::site(Some(request)).html_base_path, view)?;
+    let context: Value = view_context(request, context);
+    Ok(HttpResponse::new(status, template.render_to_vec(&context)).with_header("vary", "accept"))
+}
+
+fn render_json(status: u16, body: ResponseBody) -> HttpResponse {
+    HttpResponse { status, headers: Vec::new(), body }
+        .with_header("content-type", "application/json")
+        .with_header("vary", "accept")
+}
+
+/*
+ * View context: { "message": .. }
+ */
+fn render_message(request: &HttpRequest, status: u16, view: &str, message: &str) -> Result<HttpResponse, HttpError> {
+    if request.wants_json() {
+        return Ok(render_json(status, ResponseBody::Bytes(json!({ "message": message }).to_string().into_bytes())));
+    }
+    render_view(request, status, view, json!({ "message": message }))
+}
+
+/*
+ * One page of rows (at most database::MAX_LIMIT), the query is prepared but not run yet:
+ *  -- JSON array for clients asking for application/json - every row is serialized to the socket (chunked)
+ *     as it's read from the result set, a database error mid-way cuts the response short
+ *  -- view template with context { "rows": [..] } for browsers - templates render from a complete context,
+ *     so the page is read into it before the response starts
+ */
+fn render_rows<T: 'static>(
+    request: &HttpRequest,
+    view: &str,
+    rows: database::Rows<T>,
+    to_json: fn(&T) -> Value
+) -> Result<HttpResponse, HttpError> {
+    if request.wants_json() {
+        return Ok(render_json(200, ResponseBody::Stream(Box::new(move |writer: &mut dyn Write| {
+            writer.write_all(b"[")?;
+            let mut first: bool = true;
+            rows.for_each(|row: T| {
+                if !first {
+                    writer.write_all(b",")?;
+                }
+                first = false;
+                serde_json::to_writer(&mut *writer, &to_json(&row))?;
+                Ok(())
+            })?;
+            writer.write_all(b"]")
+        }))));
+    }
+    let template: Arc<Template> = templates::load(&virtualhost::site(Some(request)).html_base_path, view)?;
+    let rows: Vec<Value> = rows.collect()?.iter().map(to_json).collect();
+    let context: Value = view_context(request, json!({ "rows": rows }));
+    Ok(HttpResponse {
+        status: 200,
+        headers: Vec::new(),
+        body: ResponseBody::Stream(Box::new(move |writer: &mut dyn Write| template.render(&context, writer))),
+    }.with_header("vary", "accept"))
+}
+
+/*
+ * View context: { "rows": [row] } - same view as the listing
+ */
+fn render_row<T>(request: &HttpRequest, status: u16, view: &str, row: &T, to_json: fn(&T) -> Value) -> Result<HttpResponse, HttpError> {
+    if request.wants_json() {
+        return Ok(render_json(status, ResponseBody::Bytes(to_json(row).to_string().into_bytes())));
+    }
+    render_view(request, status, view, json!({ "rows": [to_json(row)] }))
+}
+
+/*
+ * {id} that is not a number can't match any row => 404
+ */
+fn parse_id(params: &Params) -> Result<u64, HttpError> {
+    match params.get("id").map(|id: &String| id.parse::<u64>()) {
+        Some(Ok(id)) => Ok(id),
+        _ => Err(HttpError::NotFound)
+    }
+}
+
+fn show_row<T>(
+    request: &HttpRequest,
+    params: &Params,
+    view: &str,
+    select: fn(u64) -> mysql::Result<Option<T>>,
+    to_json: fn(&T) -> Value
+) -> Result<HttpResponse, HttpError> {
+    match select(parse_id(params)?)? {
+        Some(row) => render_row(request, 200, view, &row, to_json),
+        None => Err(HttpError::NotFound)
+    }
+}
+
+fn create_row(
+    request: &HttpRequest,
+    view: &str,
+    path: &str,
+    message: &str,
+    create: fn(HashMap<String, String>) -> RowResult<u64>
+) -> Result<HttpResponse, HttpError> {
+    let id: u64 = create(parse_request_parameters(request)?)?;
+    keep_uploads(request);
+    Ok(render_message(request, 201, view, message)?.with_header("location", &format!("{}/{}", path, id)))
+}
+
+/*
+ * PUT (partial = false) / PATCH (partial = true) - responds with the updated row
+ */
+fn update_row<T>(
+    request: &HttpRequest,
+    params: &Params,
+    view: &str,
+    partial: bool,
+    update: fn(u64, HashMap<String, String>, bool) -> RowResult<()>,
+    select: fn(u64) -> mysql::Result<Option<T>>,
+    to_json: fn(&T) -> Value
+) -> Result<HttpResponse, HttpError> {
+    update(parse_id(params)?, parse_request_parameters(request)?, partial)?;
+    keep_uploads(request);
+    show_row(request, params, view, select, to_json)
+}
+
+fn delete_row(params: &Params, delete: fn(u64) -> RowResult<()>) -> Result<HttpResponse, HttpError> {
+    delete(parse_id(params)?)?;
+    Ok(HttpResponse::new(204, Vec::new()))
+}
+
+/*** (default route '/') */
+pub fn index(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    render_message(request, 200, "page.html", "Default route - default response :3")
+}
+
+pub fn list_users(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    let users: database::Rows<database::User> = database::User::select_all(&request.query())?;
+    render_rows(request, "users.html", users, database::User::user_to_json)
+}
+
+pub fn create_user(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    create_row(request, "users.html", "/users", "User created successfuly!", database::User::create_users)
+}
+
+pub fn show_user(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    show_row(request, params, "users.html", database::User::select_by_id, database::User::user_to_json)
+}
+
+pub fn replace_user(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "users.html", false, database::User::update_user,
+        database::User::select_by_id, database::User::user_to_json)
+}
+
+pub fn modify_user(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "users.html", true, database::User::update_user,
+        database::User::select_by_id, database::User::user_to_json)
+}
+
+pub fn delete_user(_request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    delete_row(params, database::User::delete_user)
+}
+
+pub fn list_tokens(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    let tokens: database::Rows<database::Token> = database::Token::select_all(&request.query())?;
+    render_rows(request, "tokens.html", tokens, database::Token::token_to_json)
+}
+
+pub fn create_token(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    create_row(request, "tokens.html", "/tokens", "Token created successfuly!", database::Token::create_tokens)
+}
+
+pub fn show_token(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    show_row(request, params, "tokens.html", database::Token::select_by_id, database::Token::token_to_json)
+}
+
+pub fn replace_token(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "tokens.html", false, database::Token::update_token,
+        database::Token::select_by_id, database::Token::token_to_json)
+}
+
+pub fn modify_token(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "tokens.html", true, database::Token::update_token,
+        database::Token::select_by_id, database::Token::token_to_json)
+}
+
+pub fn delete_token(_request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    delete_row(params, database::Token::delete_token)
+}
+
+pub fn list_roles(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    let roles: database::Rows<database::Role> = database::Role::select_all(&request.query())?;
+    render_rows(request, "page.html", roles, database::Role::role_to_json)
+}
+
+pub fn create_role(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    create_row(request, "page.html", "/roles", "Role created successfuly!", database::Role::create_roles)
+}
+
+pub fn show_role(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    show_row(request, params, "page.html", database::Role::select_by_id, database::Role::role_to_json)
+}
+
+pub fn replace_role(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "page.html", false, database::Role::update_role,
+        database::Role::select_by_id, database::Role::role_to_json)
+}
+
+pub fn modify_role(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "page.html", true, database::Role::update_role,
+        database::Role::select_by_id, database::Role::role_to_json)
+}
+
+pub fn delete_role(_request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    delete_row(params, database::Role::delete_role)
+}
+
+pub fn list_connected(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    let connected: database::Rows<database::Connected> = database::Connected::select_all(&request.query())?;
+    render_rows(request, "page.html", connected, database::Connected::connected_to_json)
+}
+
+pub fn create_connected(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    create_row(request, "page.html", "/connected", "Connected row created successfuly!", database::Connected::create_connected)
+}
+
+pub fn show_connected(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    show_row(request, params, "page.html", database::Connected::select_by_id,
+        database::Connected::connected_to_json)
+}
+
+pub fn replace_connected(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "page.html", false, database::Connected::update_connected,
+        database::Connected::select_by_id, database::Connected::connected_to_json)
+}
+
+pub fn modify_connected(request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    update_row(request, params, "page.html", true, database::Connected::update_connected,
+        database::Connected::select_by_id, database::Connected::connected_to_json)
+}
+
+pub fn delete_connected(_request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+    delete_row(params, database::Connected::delete_connected)
+}
+
+pub fn create_tables(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    match database::create_tables() {
+        Ok(()) => render_message(request, 200, "page.html", "Tables created successfuly!"),
+        Err(e) => {
+            println!("SQL Error creating table: {}", e);
+            Err(HttpError::from(e))
+        }
+    }
+}
+
+/*
+ * View context: { "message": .., "username": logged in user }
+ */
+fn render_login(request: &HttpRequest, status: u16, message: Option<&str>) -> Result<HttpResponse, HttpError> {
+    render_view(request, status, "login.html", json!({
+        "message": message,
+        "username": sessions::user_id(request).and(sessions::get(request, "username")),
+    }))
+}
+
+pub fn login_form(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    render_login(request, 200, None)
+}
+
+/*
+ * username (or email) & password of an active user => logged in session,
+ * remember=true|on => "remember me" cookie. Browsers are redirected to /, JSON clients get the user.
+ */
+pub fn login(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    let params: HashMap<String, String> = parse_request_parameters(request)?;
+    let (login, password): (&String, &String) = match (params.get("username"), params.get("password")) {
+        (Some(login), Some(password)) if !login.is_empty() && !password.is_empty() => (login, password),
+        _ => return Err(HttpError::UnprocessableEntity(String::from("username and password are required")))
+    };
+    /* unknown login => the password is still derived so the answer takes as long as for a wrong password */
+    let verified: Option<database::User> = match database::User::select_by_login(login)? {
+        Some(user) => if user.verify_password(password) { Some(user) } else { None },
+        None => {
+            passwords::verify(password, None);
+            None
+        }
+    };
+    let user: database::User = match verified {
+        Some(user) if user.is_active() => user,
+        _ => {
+            println!("Login failed for {:?}", login);
+            if request.wants_json() {
+                return Err(HttpError::Unauthorized(String::from("Invalid username or password")));
+            }
+            return render_login(request, 401, Some("Invalid username or password"));
+        }
+    };
+    let remember: bool = params.get("remember").is_some_and(|remember: &String| remember == "true" || remember == "on");
+    sessions::login(request, user.id(), remember).map_err(HttpError::Internal)?;
+    sessions::set(request, "username", user.username()).map_err(HttpError::Internal)?;
+    if request.wants_json() {
+        return Ok(render_json(200, ResponseBody::Bytes(database::User::user_to_json(&user).to_string().into_bytes())));
+    }
+    Ok(HttpResponse::new(303, Vec::new()).with_header("location", "/"))
+}
+
+/*
+ * Ends the session & revokes the user's "remember me" cookies - browsers are redirected to the login form
+ */
+pub fn logout(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    sessions::logout(request);
+    if request.wants_json() {
+        return Ok(HttpResponse::new(204, Vec::new()));
+    }
+    Ok(HttpResponse::new(303, Vec::new()).with_header("location", "/login"))
+}
+
+/*
+ * WebSocket handshake - the session joins the TCP message hub after 101
+ */
+pub fn websocket(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    if !threadpool::streams_available() {
+        return Err(HttpError::ServiceUnavailable(String::from("Too many open streams")));
+    }
+    websocket::handshake(request)
+}
+
+/*
+ * Live server activity as Server-Sent Events - last-event-id resumes from the backlog
+ */
+pub fn events(request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+    let last_event_id: Option<u64> = request.header("last-event-id").and_then(|id: &str| id.trim().parse::<u64>().ok());
+    let subscription: Subscription = eventstream::subscribe(last_event_id).map_err(HttpError::ServiceUnavailable)?;
+    Ok(HttpResponse {
+        status: 200,
+        headers: Vec::new(),
+        body: ResponseBody::Stream(Box::new(move |writer: &mut dyn Write| subscription.stream(writer))),
+    }
+        .with_header("content-type", "text/event-stream")
+        .with_header("cache-control", "no-cache"))
+}
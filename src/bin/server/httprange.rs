@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use crate::server::helpers;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::ResponseBody;
+
+/*
+ * More ranges than this in a single request => range header is ignored and full file is sent
+ */
+static MAX_RANGES: usize = 16;
+
+/*
+ * Inclusive byte range: bytes={start}-{end}
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+pub enum RangeRequest {
+    /* no (usable) range header => 200 with the whole file */
+    Full,
+    /* => 206 */
+    Partial(Vec<ByteRange>),
+    /* => 416 */
+    Unsatisfiable,
+}
+
+/*
+ * Range: bytes=0-499, 500-, -200
+ *  -- {first}-{last} => last is clamped to the end of file
+ *  -- {first}-       => until end of file
+ *  -- -{suffix}      => last {suffix} bytes
+ * Malformed headers and other units are ignored (=> Full),
+ * ranges starting past the end of file are dropped - if none is left => Unsatisfiable.
+ * Overlapping & adjacent ranges are coalesced (in ascending order) so no byte is sent twice.
+ */
+pub fn parse_range(header: &str, total: u64) -> RangeRequest {
+    let specs: &str = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full
+    };
+    let specs: Vec<&str> = specs.split(',').map(|s: &str| s.trim()).filter(|s: &&str| !s.is_empty()).collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    let mut ranges: Vec<ByteRange> = Vec::new();
+    for spec in specs {
+        let (first, last): (&str, &str) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Full
+        };
+        let range: Option<ByteRange> = if first.is_empty() {
+            let suffix: u64 = match last.parse::<u64>() {
+                Ok(suffix) => suffix,
+                Err(_) => return RangeRequest::Full
+            };
+            if suffix == 0 || total == 0 {
+                None
+            } else {
+                Some(ByteRange { start: total.saturating_sub(suffix), end: total - 1 })
+            }
+        } else {
+            let start: u64 = match first.parse::<u64>() {
+                Ok(start) => start,
+                Err(_) => return RangeRequest::Full
+            };
+            let end: u64 = if last.is_empty() {
+                total.saturating_sub(1)
+            } else {
+                match last.parse::<u64>() {
+                    Ok(end) if end >= start => end.min(total.saturating_sub(1)),
+                    _ => return RangeRequest::Full
+                }
+            };
+            if start >= total {
+                None
+            } else {
+                Some(ByteRange { start, end })
+            }
+        };
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+    ranges.sort_by_key(|range: &ByteRange| range.start);
+    let mut coalesced: Vec<ByteRange> = Vec::new();
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start <= last.end + 1 => last.end = last.end.max(range.end),
+            _ => coalesced.push(range)
+        }
+    }
+    RangeRequest::Partial(coalesced)
+}
+
+/*
+ * If-Range: {etag} | {http-date}
+ * Range is honoured only if the file is unchanged, otherwise the whole file is sent.
+ */
+pub fn if_range_matches(request: &HttpRequest, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    let if_range: &str = match request.header("if-range") {
+        Some(if_range) => if_range.trim(),
+        None => return true
+    };
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    match (helpers::parse_http_date(if_range), last_modified) {
+        (Some(date), Some(modified)) => date.timestamp() == modified.timestamp(),
+        _ => false
+    }
+}
+
+fn copy_range(file: &mut File, range: &ByteRange, writer: &mut dyn Write) -> io::Result<()> {
+    file.seek(SeekFrom::Start(range.start))?;
+    let copied: u64 = io::copy(&mut Read::by_ref(file).take(range.size()), writer)?;
+    if copied != range.size() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrunk while sending range"));
+    }
+    Ok(())
+}
+
+/*
+ * Single range => body is the range itself.
+ * Multiple ranges => multipart/byteranges:
+ *
+ * \r\n--{boundary}\r\n
+ * content-type: {type}\r\n
+ * content-range: bytes {start}-{end}/{total}\r\n
+ * \r\n
+ * {data}
+ * ...
+ * \r\n--{boundary}--\r\n
+ *
+ * Returns content-type of the response and its body.
+ */
+pub fn partial_body(file_path: PathBuf, ranges: Vec<ByteRange>, total: u64, content_type: &str) -> io::Result<(String, ResponseBody)> {
+    let mut file: File = File::open(&file_path)?;
+    if ranges.len() == 1 {
+        let range: ByteRange = ranges[0];
+        return Ok((content_type.to_string(), ResponseBody::SizedStream(range.size(), Box::new(move |writer: &mut dyn Write| {
+            copy_range(&mut file, &range, writer)
+        }))));
+    }
+    let boundary: String = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let part_headers: Vec<String> = ranges.iter().map(|range: &ByteRange| format!(
+        "\r\n--{}\r\ncontent-type: {}\r\ncontent-range: {}\r\n\r\n",
+        boundary, content_type, range.content_range(total)
+    )).collect();
+    let closing: String = format!("\r\n--{}--\r\n", boundary);
+    let len: u64 = ranges.iter().zip(part_headers.iter())
+        .map(|(range, part_header)| range.size() + part_header.len() as u64)
+        .sum::<u64>() + closing.len() as u64;
+    Ok((format!("multipart/byteranges; boundary={}", boundary), ResponseBody::SizedStream(len, Box::new(move |writer: &mut dyn Write| {
+        for (range, part_header) in ranges.iter().zip(part_headers.iter()) {
+            writer.write_all(part_header.as_bytes())?;
+            copy_range(&mut file, range, writer)?;
+        }
+        writer.write_all(closing.as_bytes())
+    }))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(header: &str, total: u64) -> Vec<(u64, u64)> {
+        match parse_range(header, total) {
+            RangeRequest::Partial(ranges) => ranges.iter().map(|range: &ByteRange| (range.start, range.end)).collect(),
+            RangeRequest::Full => panic!("{:?} => Full", header),
+            RangeRequest::Unsatisfiable => panic!("{:?} => Unsatisfiable", header),
+        }
+    }
+
+    #[test]
+    fn parses_bounded_and_open_ranges() {
+        assert_eq!(partial("bytes=0-499", 1000), vec![(0, 499)]);
+        assert_eq!(partial("bytes=500-", 1000), vec![(500, 999)]);
+        assert_eq!(partial("bytes=900-5000", 1000), vec![(900, 999)]);
+        assert_eq!(partial("bytes=0-0, 10-19", 1000), vec![(0, 0), (10, 19)]);
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(partial("bytes=-200", 1000), vec![(800, 999)]);
+        assert_eq!(partial("bytes=-5000", 1000), vec![(0, 999)]);
+        assert!(matches!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable));
+        assert!(matches!(parse_range("bytes=-10", 0), RangeRequest::Unsatisfiable));
+    }
+
+    #[test]
+    fn coalesces_overlapping_and_adjacent_ranges() {
+        assert_eq!(partial("bytes=0-499, 200-699", 1000), vec![(0, 699)]);
+        assert_eq!(partial("bytes=500-599, 0-99, 100-199", 1000), vec![(0, 199), (500, 599)]);
+        assert_eq!(partial("bytes=0-, -100", 1000), vec![(0, 999)]);
+        assert_eq!(partial("bytes=10-20, 10-20, 12-15", 1000), vec![(10, 20)]);
+    }
+
+    #[test]
+    fn ranges_past_the_end_are_dropped() {
+        assert_eq!(partial("bytes=2000-3000, 0-9", 1000), vec![(0, 9)]);
+        assert!(matches!(parse_range("bytes=1000-", 1000), RangeRequest::Unsatisfiable));
+        assert!(matches!(parse_range("bytes=0-10", 0), RangeRequest::Unsatisfiable));
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for header in ["items=0-9", "bytes=", "bytes=abc", "bytes=5-1", "bytes=1-x", "bytes=--5", "0-9"] {
+            assert!(matches!(parse_range(header, 1000), RangeRequest::Full), "{:?}", header);
+        }
+        let too_many: String = format!("bytes={}", (0..=MAX_RANGES).map(|i: usize| format!("{}-{}", i * 10, i * 10 + 1)).collect::<Vec<String>>().join(","));
+        assert!(matches!(parse_range(&too_many, 1000), RangeRequest::Full));
+    }
+}
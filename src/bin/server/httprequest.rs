@@ -0,0 +1,605 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, ErrorKind, Read};
+use crate::server::cookies;
+use crate::server::cstmconfig::VirtualHost;
+use crate::server::multipart::{self, Multipart};
+use crate::server::sessions::Session;
+
+/*
+ * Upper bounds for a single request:
+ *  -- request line + all header lines
+ *  -- body announced by content-length
+ */
+static MAX_HEADERS_SIZE: usize = 8 * 1024;
+static MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+const IDENTIFICATOR: &str = "httprequest";
+
+#[derive(Debug)]
+pub enum RequestError {
+    /* peer closed the connection before sending a request line */
+    Closed,
+    /* malformed request => 400 */
+    BadRequest(String),
+    /* headers or body over the limits => 413 */
+    PayloadTooLarge(String),
+    /* unsupported transfer-encoding => 501 */
+    NotImplemented(String),
+    /* no data within the stream read timeout */
+    Timeout,
+    Io(String),
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Closed => write!(f, "{}: Connection closed by peer", IDENTIFICATOR),
+            RequestError::BadRequest(e) => write!(f, "{}: Bad request: {}", IDENTIFICATOR, e),
+            RequestError::PayloadTooLarge(e) => write!(f, "{}: Payload too large: {}", IDENTIFICATOR, e),
+            RequestError::NotImplemented(e) => write!(f, "{}: Not implemented: {}", IDENTIFICATOR, e),
+            RequestError::Timeout => write!(f, "{}: Timed out waiting for data", IDENTIFICATOR),
+            RequestError::Io(e) => write!(f, "{}: Error reading from stream: {}", IDENTIFICATOR, e),
+        }
+    }
+}
+
+/*
+ * Parsed HTTP/1.x request:
+ *  -- header names are stored lowercase, repeated headers are joined with ", "
+ *  -- body holds the decoded body (empty if request has no body)
+ *  -- multipart/form-data bodies are parsed while reading => multipart (body stays empty)
+ */
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub multipart: Option<Multipart>,
+    /* set by the SecurityHeaders middleware => {{ csp_nonce }} in views */
+    pub csp_nonce: Option<String>,
+    /* set by the VirtualHosts middleware, None => APP.* site */
+    pub vhost: Option<VirtualHost>,
+    /* set by the Sessions middleware - read & changed through the sessions module */
+    pub session: Option<Session>,
+}
+
+impl HttpRequest {
+    /*
+     * Reads one request from the reader:
+     *  1. request line (leading empty lines are skipped)
+     *  2. header lines until the empty line
+     *  3. body - chunked if transfer-encoding is set, otherwise exactly content-length bytes,
+     *     multipart/form-data body is parsed on the fly so uploads never sit in memory
+     */
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<HttpRequest, RequestError> {
+        let mut headers_size: usize = 0;
+        let mut request_line: String = String::new();
+        while request_line.is_empty() {
+            match read_line(reader, &mut headers_size)? {
+                Some(line) => request_line = line,
+                None => return Err(RequestError::Closed),
+            }
+        }
+        let (method, target, version): (String, String, String) = parse_request_line(&request_line)?;
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        loop {
+            let line: String = match read_line(reader, &mut headers_size)? {
+                Some(line) => line,
+                None => return Err(RequestError::BadRequest(String::from("Connection closed inside headers"))),
+            };
+            if line.is_empty() {
+                break;
+            }
+            let (name, value): (String, String) = parse_header_line(&line)?;
+            match headers.get_mut(&name) {
+                Some(existing) => {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                },
+                None => { headers.insert(name, value); }
+            }
+        }
+
+        let mut request: HttpRequest = HttpRequest { method, target, version, headers, body: Vec::new(), multipart: None, csp_nonce: None, vhost: None, session: None };
+        let boundary: Option<String> = request.header("content-type").and_then(multipart::boundary);
+        if let Some(transfer_encoding) = request.header("transfer-encoding") {
+            /* both headers at once is a request smuggling vector - refuse instead of picking one */
+            if request.header("content-length").is_some() {
+                return Err(RequestError::BadRequest(String::from("Both transfer-encoding and content-length are set")));
+            }
+            if !transfer_encoding.trim().eq_ignore_ascii_case("chunked") {
+                return Err(RequestError::NotImplemented(format!("Unsupported transfer-encoding: {}", transfer_encoding)));
+            }
+            let mut chunked: ChunkedReader<R> = ChunkedReader::new(reader);
+            match boundary {
+                Some(boundary) => {
+                    request.multipart = Some(multipart::read_multipart(&mut chunked, &boundary, MAX_BODY_SIZE)?);
+                    /* multipart epilogue is drained, only the terminating chunk & trailers can be left */
+                    read_to_end(&mut chunked, &mut Vec::new(), 0)?;
+                },
+                None => read_to_end(&mut chunked, &mut request.body, MAX_BODY_SIZE)?
+            }
+            return Ok(request);
+        }
+        let content_length: usize = request.content_length()?;
+        if let Some(boundary) = boundary {
+            if content_length as u64 > MAX_BODY_SIZE as u64 + multipart::MAX_UPLOAD_SIZE {
+                return Err(RequestError::PayloadTooLarge(
+                    format!("Multipart body of {} bytes exceeds limit of {} bytes", content_length, MAX_BODY_SIZE as u64 + multipart::MAX_UPLOAD_SIZE)
+                ));
+            }
+            request.multipart = Some(multipart::read_multipart(&mut reader.take(content_length as u64), &boundary, MAX_BODY_SIZE)?);
+            return Ok(request);
+        }
+        if content_length > MAX_BODY_SIZE {
+            return Err(RequestError::PayloadTooLarge(
+                format!("Body of {} bytes exceeds limit of {} bytes", content_length, MAX_BODY_SIZE)
+            ));
+        }
+        if content_length > 0 {
+            let mut body: Vec<u8> = vec![0; content_length];
+            read_exact(reader, &mut body)?;
+            request.body = body;
+        }
+        Ok(request)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_lowercase()).map(|v: &String| v.as_str())
+    }
+
+    /*
+     * Value of a cookie sent by the client
+     */
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.header("cookie").and_then(|header: &str| cookies::parse(header).remove(name))
+    }
+
+    /*
+     * Request target without the query string
+     */
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _query)) => path,
+            None => self.target.as_str()
+        }
+    }
+
+    /*
+     * Percent-decoded query string parameters ("+" => space), last occurrence wins
+     */
+    pub fn query(&self) -> HashMap<String, String> {
+        match self.target.split_once('?') {
+            Some((_path, query)) => url::form_urlencoded::parse(query.as_bytes()).into_owned().collect(),
+            None => HashMap::new()
+        }
+    }
+
+    /*
+     * Picks the best of offered media types according to the accept header:
+     *  -- more specific range decides the q-value (text/html > text/* > */*)
+     *  -- highest q-value wins, ties go to the type offered first
+     *  -- missing accept header => first offered type
+     * Returns None if the client refuses all offered types (q=0).
+     */
+    pub fn preferred_media_type<'a>(&self, offered: &[&'a str]) -> Option<&'a str> {
+        let accept: &str = match self.header("accept") {
+            Some(accept) => accept,
+            None => return offered.first().copied()
+        };
+        let ranges: Vec<(String, f32)> = accept.split(',').filter_map(|range: &str| {
+            let mut parts = range.split(';');
+            let media_range: String = parts.next()?.trim().to_lowercase();
+            let mut q: f32 = 1.0;
+            for param in parts {
+                if let Some(("q", value)) = param.trim().split_once('=') {
+                    q = value.trim().parse::<f32>().unwrap_or(0.0);
+                }
+            }
+            Some((media_range, q))
+        }).collect();
+
+        let mut best: Option<(&str, f32)> = None;
+        for media_type in offered.iter() {
+            let type_wildcard: String = match media_type.split_once('/') {
+                Some((main_type, _)) => format!("{}/*", main_type),
+                None => String::from("*/*")
+            };
+            /* (specificity, q) of the most specific matching range */
+            let mut matched: Option<(u8, f32)> = None;
+            for (range, q) in ranges.iter() {
+                let specificity: u8 = if range == media_type {
+                    3
+                } else if *range == type_wildcard {
+                    2
+                } else if range == "*/*" {
+                    1
+                } else {
+                    continue
+                };
+                if matched.is_none_or(|(s, _)| specificity > s) {
+                    matched = Some((specificity, *q));
+                }
+            }
+            if let Some((_, q)) = matched {
+                if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                    best = Some((media_type, q));
+                }
+            }
+        }
+        best.map(|(media_type, _)| media_type)
+    }
+
+    /*
+     * Browsers get HTML, clients asking for application/json get JSON
+     */
+    pub fn wants_json(&self) -> bool {
+        self.preferred_media_type(&["text/html", "application/json"]) == Some("application/json")
+    }
+
+    /*
+     * HTTP/1.1 defaults to persistent connections, HTTP/1.0 has to opt in
+     */
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection: Vec<String> = match self.header("connection") {
+            Some(v) => v.split(',').map(|t: &str| t.trim().to_lowercase()).collect(),
+            None => Vec::new()
+        };
+        if connection.iter().any(|t: &String| t == "close") {
+            return false;
+        }
+        if self.version == "HTTP/1.0" {
+            return connection.iter().any(|t: &String| t == "keep-alive");
+        }
+        true
+    }
+
+    fn content_length(&self) -> Result<usize, RequestError> {
+        let value: &str = match self.header("content-length") {
+            Some(v) => v,
+            None => return Ok(0)
+        };
+        /* repeated headers were joined - all of them must agree */
+        let mut lengths = value.split(',').map(|v: &str| v.trim().parse::<usize>());
+        let first: usize = match lengths.next() {
+            Some(Ok(len)) => len,
+            _ => return Err(RequestError::BadRequest(format!("Invalid content-length: {}", value)))
+        };
+        for len in lengths {
+            match len {
+                Ok(len) if len == first => {},
+                _ => return Err(RequestError::BadRequest(format!("Conflicting content-length: {}", value)))
+            }
+        }
+        Ok(first)
+    }
+}
+
+/*
+ * Chunked body format:
+ *
+ * {size in hex}[;extensions]\r\n
+ * {data}\r\n
+ * ...
+ * 0\r\n
+ * [trailer headers]\r\n
+ * \r\n
+ *
+ * Decoded while reading, chunk extensions and trailers are read and discarded.
+ * Malformed framing is reported as ErrorKind::InvalidData.
+ * Also decodes chunked responses of proxied upstreams.
+ */
+pub struct ChunkedReader<'a, R: BufRead> {
+    inner: &'a mut R,
+    /* bytes left in the current chunk */
+    remaining: usize,
+    done: bool,
+}
+
+impl<'a, R: BufRead> ChunkedReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> ChunkedReader<'a, R> {
+        ChunkedReader { inner, remaining: 0, done: false }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let mut line_size: usize = 0;
+        let size_line: String = match read_line(self.inner, &mut line_size) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(io::Error::new(ErrorKind::InvalidData, "Connection closed inside chunked body")),
+            Err(e) => return Err(to_io_error(e))
+        };
+        let size_hex: &str = size_line.split(';').next().unwrap_or("").trim();
+        self.remaining = match usize::from_str_radix(size_hex, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(io::Error::new(ErrorKind::InvalidData, format!("Invalid chunk size: {:?}", size_line)))
+        };
+        if self.remaining > 0 {
+            return Ok(());
+        }
+        self.done = true;
+        let mut trailers_size: usize = 0;
+        loop {
+            match read_line(self.inner, &mut trailers_size) {
+                Ok(Some(line)) if line.is_empty() => return Ok(()),
+                Ok(Some(_trailer)) => {},
+                Ok(None) => return Err(io::Error::new(ErrorKind::InvalidData, "Connection closed inside chunked trailers")),
+                Err(e) => return Err(to_io_error(e))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            self.next_chunk()?;
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        let max: usize = buf.len().min(self.remaining);
+        let bytes: usize = self.inner.read(&mut buf[..max])?;
+        if bytes == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Connection closed inside chunk data"));
+        }
+        self.remaining -= bytes;
+        if self.remaining == 0 {
+            let mut line_size: usize = 0;
+            match read_line(self.inner, &mut line_size) {
+                Ok(Some(line)) if line.is_empty() => {},
+                Ok(_) => return Err(io::Error::new(ErrorKind::InvalidData, "Chunk data is not followed by CRLF")),
+                Err(e) => return Err(to_io_error(e))
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+fn to_io_error(e: RequestError) -> io::Error {
+    match e {
+        RequestError::Timeout => io::Error::new(ErrorKind::TimedOut, e.to_string()),
+        RequestError::Io(e) => io::Error::other(e),
+        e => io::Error::new(ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+/*
+ * Reads decoded body into buf, more than limit bytes => PayloadTooLarge
+ */
+fn read_to_end<R: Read>(reader: &mut R, buf: &mut Vec<u8>, limit: usize) -> Result<(), RequestError> {
+    match reader.take(limit as u64 + 1).read_to_end(buf) {
+        Ok(bytes) if bytes > limit => Err(RequestError::PayloadTooLarge(format!("Chunked body exceeds limit of {} bytes", limit))),
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Err(RequestError::Timeout),
+        Err(e) if e.kind() == ErrorKind::InvalidData => Err(RequestError::BadRequest(e.to_string())),
+        Err(e) => Err(RequestError::Io(e.to_string()))
+    }
+}
+
+fn read_exact<R: BufRead>(reader: &mut R, buf: &mut [u8]) -> Result<(), RequestError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            Err(RequestError::BadRequest(format!("Body shorter than announced {} bytes", buf.len())))
+        },
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            Err(RequestError::Timeout)
+        },
+        Err(e) => Err(RequestError::Io(e.to_string()))
+    }
+}
+
+/*
+ * Reads a single CRLF (or bare LF) terminated line without the terminator.
+ * Returns None on EOF before any byte was read.
+ * headers_size accumulates bytes read so far - shared by all lines of one head (request or upstream response).
+ */
+pub fn read_line<R: BufRead>(reader: &mut R, headers_size: &mut usize) -> Result<Option<String>, RequestError> {
+    let mut line: Vec<u8> = Vec::new();
+    let limit: u64 = (MAX_HEADERS_SIZE - *headers_size + 1) as u64;
+    let bytes: usize = match reader.by_ref().take(limit).read_until(b'\n', &mut line) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            return Err(RequestError::Timeout)
+        },
+        Err(e) => return Err(RequestError::Io(e.to_string()))
+    };
+    if bytes == 0 {
+        return Ok(None);
+    }
+    *headers_size += bytes;
+    if *headers_size > MAX_HEADERS_SIZE {
+        return Err(RequestError::PayloadTooLarge(
+            format!("Request headers exceed limit of {} bytes", MAX_HEADERS_SIZE)
+        ));
+    }
+    if line.last() != Some(&b'\n') {
+        return Err(RequestError::BadRequest(String::from("Connection closed mid-line")));
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    match String::from_utf8(line) {
+        Ok(line) => Ok(Some(line)),
+        Err(_) => Err(RequestError::BadRequest(String::from("Request line or header is not valid UTF-8")))
+    }
+}
+
+/*
+ * {METHOD} {TARGET} {HTTP/x.y}
+ */
+fn parse_request_line(line: &str) -> Result<(String, String, String), RequestError> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    if parts.len() != 3 || parts.iter().any(|p: &&str| p.is_empty()) {
+        return Err(RequestError::BadRequest(format!("Malformed request line: {:?}", line)));
+    }
+    if !parts[0].chars().all(|c: char| c.is_ascii_uppercase()) {
+        return Err(RequestError::BadRequest(format!("Invalid request method: {}", parts[0])));
+    }
+    if !parts[2].starts_with("HTTP/1.") {
+        return Err(RequestError::BadRequest(format!("Unsupported protocol version: {}", parts[2])));
+    }
+    Ok((parts[0].to_string(), parts[1].to_string(), parts[2].to_string()))
+}
+
+/*
+ * {name}: {value} -- name is lowercased, value is trimmed
+ */
+pub fn parse_header_line(line: &str) -> Result<(String, String), RequestError> {
+    if line.starts_with(' ') || line.starts_with('\t') {
+        return Err(RequestError::BadRequest(String::from("Obsolete header line folding is not supported")));
+    }
+    match line.split_once(':') {
+        Some((name, value)) => {
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace()) {
+                return Err(RequestError::BadRequest(format!("Invalid header name: {:?}", name)));
+            }
+            Ok((name.to_lowercase(), value.trim().to_string()))
+        },
+        None => Err(RequestError::BadRequest(format!("Malformed header line: {:?}", line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(raw: &[u8]) -> Result<HttpRequest, RequestError> {
+        let mut reader: &[u8] = raw;
+        HttpRequest::read_from(&mut reader)
+    }
+
+    fn decode_chunked(raw: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader: &[u8] = raw;
+        let mut body: Vec<u8> = Vec::new();
+        ChunkedReader { inner: &mut reader, remaining: 0, done: false }.read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    #[test]
+    fn reads_request_line_headers_and_body() {
+        let request: HttpRequest = read(b"\r\nPOST /users?id=1 HTTP/1.1\r\nHost: localhost\r\nX-Tag: a\r\nx-tag: b\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/users?id=1");
+        assert_eq!(request.version, "HTTP/1.1");
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.header("x-tag"), Some("a, b"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn accepts_bare_lf_line_endings() {
+        let request: HttpRequest = read(b"GET / HTTP/1.0\nHost: localhost\n\n").unwrap();
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+    }
+
+    #[test]
+    fn leaves_pipelined_request_unread() {
+        let mut reader: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 2\r\n\r\nokGET /b HTTP/1.1\r\n\r\n";
+        assert_eq!(HttpRequest::read_from(&mut reader).unwrap().body, b"ok");
+        assert_eq!(HttpRequest::read_from(&mut reader).unwrap().target, "/b");
+        assert!(matches!(HttpRequest::read_from(&mut reader), Err(RequestError::Closed)));
+    }
+
+    #[test]
+    fn empty_stream_is_closed() {
+        assert!(matches!(read(b""), Err(RequestError::Closed)));
+    }
+
+    #[test]
+    fn rejects_malformed_heads() {
+        assert!(matches!(read(b"GET /\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"get / HTTP/1.1\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"GET / HTTP/2.0\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"GET / HTTP/1.1\r\nno colon\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"GET / HTTP/1.1\r\nHost: a\r\n folded\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"GET / HTTP/1.1\r\nBad Name: a\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"GET / HTTP/1.1\r\nHost: a\r\n"), Err(RequestError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_with_content_length() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+        assert!(matches!(read(raw), Err(RequestError::BadRequest(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_transfer_encoding() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert!(matches!(read(raw), Err(RequestError::NotImplemented(_))));
+    }
+
+    #[test]
+    fn content_length_must_be_valid_and_consistent() {
+        assert!(matches!(read(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), Err(RequestError::BadRequest(_))));
+        assert!(matches!(read(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd"), Err(RequestError::BadRequest(_))));
+        assert_eq!(read(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc").unwrap().body, b"abc");
+        assert!(matches!(read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"), Err(RequestError::BadRequest(_))));
+    }
+
+    #[test]
+    fn enforces_header_and_body_limits() {
+        let mut raw: Vec<u8> = b"GET / HTTP/1.1\r\nX-Big: ".to_vec();
+        raw.extend(vec![b'a'; MAX_HEADERS_SIZE]);
+        raw.extend(b"\r\n\r\n");
+        assert!(matches!(read(&raw), Err(RequestError::PayloadTooLarge(_))));
+
+        let raw: String = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        assert!(matches!(read(raw.as_bytes()), Err(RequestError::PayloadTooLarge(_))));
+
+        let mut raw: Vec<u8> = format!("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n", MAX_BODY_SIZE + 1).into_bytes();
+        raw.extend(vec![b'a'; MAX_BODY_SIZE + 1]);
+        raw.extend(b"\r\n0\r\n\r\n");
+        assert!(matches!(read(&raw), Err(RequestError::PayloadTooLarge(_))));
+    }
+
+    #[test]
+    fn reads_chunked_body_and_leaves_next_request() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET /next HTTP/1.1\r\n\r\n";
+        let mut reader: &[u8] = raw;
+        let request: HttpRequest = HttpRequest::read_from(&mut reader).unwrap();
+        assert_eq!(request.body, b"Wikipedia");
+        assert_eq!(HttpRequest::read_from(&mut reader).unwrap().target, "/next");
+    }
+
+    #[test]
+    fn chunked_reader_decodes_chunks() {
+        assert_eq!(decode_chunked(b"3\r\nabc\r\nA\r\n0123456789\r\n0\r\n\r\n").unwrap(), b"abc0123456789");
+        assert_eq!(decode_chunked(b"0\r\n\r\n").unwrap(), b"");
+    }
+
+    #[test]
+    fn chunked_reader_rejects_malformed_chunks() {
+        let malformed: [&[u8]; 5] = [
+            b"zz\r\nabc\r\n0\r\n\r\n",
+            b"3\r\nabcd\r\n0\r\n\r\n",
+            b"5\r\nabc",
+            b"3\r\nabc\r\n",
+            b"0\r\nX-Trailer: 1\r\n",
+        ];
+        for raw in malformed {
+            let e: io::Error = decode_chunked(raw).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::InvalidData, "{:?}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[test]
+    fn malformed_chunked_request_is_bad_request() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n";
+        assert!(matches!(read(raw), Err(RequestError::BadRequest(_))));
+    }
+
+    #[test]
+    fn reads_multipart_fields() {
+        let raw: &[u8] = b"POST / HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=XyZ\r\nContent-Length: 63\r\n\r\n--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n--XyZ--\r\nGET";
+        let mut reader: &[u8] = raw;
+        let request: HttpRequest = HttpRequest::read_from(&mut reader).unwrap();
+        assert_eq!(request.multipart.unwrap().fields.get("a").map(String::as_str), Some("1"));
+        assert!(request.body.is_empty());
+        assert_eq!(reader, b"GET");
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::{self, BufWriter, Write};
+
+/*
+ * Size of the buffer in front of the chunked encoder -
+ * small writes from a streamed body are grouped into chunks of this size
+ */
+static CHUNK_SIZE: usize = 8 * 1024;
+
+/*
+ * Streamed body is written directly to the socket by the closure
+ */
+pub type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+pub enum ResponseBody {
+    /* fully buffered body => content-length */
+    Bytes(Vec<u8>),
+    /* body produced while writing => transfer-encoding: chunked */
+    Stream(BodyWriter),
+    /* body produced while writing with length known upfront (e.g. file) => content-length */
+    SizedStream(u64, BodyWriter),
+}
+
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ResponseBody,
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, body: Vec<u8>) -> HttpResponse {
+        HttpResponse { status, headers: Vec::new(), body: ResponseBody::Bytes(body) }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.set_header(name, value);
+        self
+    }
+
+    /*
+     * Replaces header with the same (case-insensitive) name
+     */
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_lowercase(), value.to_string()));
+    }
+
+    /*
+     * Adds a header next to the ones with the same name - set-cookie can't be folded into one line
+     */
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_lowercase(), value.to_string()));
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /*
+     * Appends to the vary header instead of replacing it: "accept" + "accept-encoding" => "accept, accept-encoding"
+     */
+    pub fn add_vary(&mut self, name: &str) {
+        let vary: String = match self.header("vary") {
+            Some(vary) if vary.split(',').any(|n: &str| n.trim().eq_ignore_ascii_case(name)) => return,
+            Some(vary) => format!("{}, {}", vary, name),
+            None => name.to_string()
+        };
+        self.set_header("vary", &vary);
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        matches!(self.body, ResponseBody::Stream(_))
+    }
+
+    /*
+     * HTTP text-based protocol response format:
+     *
+     * {HTTP/1.1 200 OK}\r\n
+     * {HEADERS}\r\n
+     * \r\n
+     * {CONTENT}
+     *
+     * Streamed body is sent chunked if the client speaks HTTP/1.1,
+     * otherwise it's sent as-is and the end of body is marked by closing the connection.
+     * head_only (HEAD request) => same headers as the full response, body is not sent.
+     * Returns number of body bytes written - without headers & chunk framing.
+     */
+    pub fn write_to(self, stream: &mut dyn Write, http_protocol: &str, chunked: bool, head_only: bool) -> io::Result<usize> {
+        let mut head: String = format!("{} {} {}\r\n", http_protocol, self.status, reason_phrase(self.status));
+        for (name, value) in self.headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        /* 1xx, 204 & 304 never carry a body */
+        if self.status < 200 || self.status == 204 || self.status == 304 {
+            head.push_str("\r\n");
+            stream.write_all(head.as_bytes())?;
+            stream.flush()?;
+            return Ok(0);
+        }
+        let mut body_bytes: usize = 0;
+        match self.body {
+            ResponseBody::Bytes(body) => {
+                head.push_str(&format!("content-length: {}\r\n\r\n", body.len()));
+                stream.write_all(head.as_bytes())?;
+                if !head_only {
+                    stream.write_all(&body)?;
+                    body_bytes = body.len();
+                }
+            },
+            ResponseBody::SizedStream(len, body_writer) => {
+                head.push_str(&format!("content-length: {}\r\n\r\n", len));
+                stream.write_all(head.as_bytes())?;
+                if !head_only {
+                    let mut writer: CountingWriter = CountingWriter { inner: stream, count: 0 };
+                    body_writer(&mut writer)?;
+                    body_bytes = writer.count;
+                }
+            },
+            ResponseBody::Stream(body_writer) => {
+                if chunked {
+                    head.push_str("transfer-encoding: chunked\r\n\r\n");
+                    stream.write_all(head.as_bytes())?;
+                    if !head_only {
+                        let mut buffered: BufWriter<ChunkedWriter> =
+                            BufWriter::with_capacity(CHUNK_SIZE, ChunkedWriter::new(stream));
+                        let mut writer: CountingWriter = CountingWriter { inner: &mut buffered, count: 0 };
+                        body_writer(&mut writer)?;
+                        body_bytes = writer.count;
+                        match buffered.into_inner() {
+                            Ok(chunked_writer) => chunked_writer.finish()?,
+                            Err(e) => return Err(e.into_error())
+                        }
+                    }
+                } else {
+                    head.push_str("\r\n");
+                    stream.write_all(head.as_bytes())?;
+                    if !head_only {
+                        let mut writer: CountingWriter = CountingWriter { inner: stream, count: 0 };
+                        body_writer(&mut writer)?;
+                        body_bytes = writer.count;
+                    }
+                }
+            }
+        }
+        stream.flush()?;
+        Ok(body_bytes)
+    }
+}
+
+/*
+ * Wraps every write into a single chunk: {size in hex}\r\n{data}\r\n
+ * finish() writes the terminating zero-length chunk.
+ */
+pub struct ChunkedWriter<'a> {
+    inner: &'a mut dyn Write,
+}
+
+impl<'a> ChunkedWriter<'a> {
+    pub fn new(inner: &'a mut dyn Write) -> ChunkedWriter<'a> {
+        ChunkedWriter { inner }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()
+    }
+}
+
+impl Write for ChunkedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        /* empty chunk would terminate the body */
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.inner.write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn Write,
+    count: usize,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes: usize = self.inner.write(buf)?;
+        self.count += bytes;
+        Ok(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Entity",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,623 @@
+use std::cell::Cell;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use rand::Rng;
+use openssl::base64;
+use serde_json::json;
+use crate::server::cstmconfig::{self, AssetsConfig, CorsConfig, MiddlewareConfig, ProxyConfig, ProxyRoute, SecurityConfig, SessionConfig, VirtualHost, VirtualHostConfig};
+use crate::server::cstmfiles;
+use crate::server::helpers;
+use crate::server::database::{Token, User};
+use crate::server::httperror::HttpError;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::HttpResponse;
+use crate::server::proxy;
+use crate::server::ratelimit::{self, Limit};
+use crate::server::sessions;
+use crate::server::virtualhost;
+
+/*
+ * Longest x-request-id accepted from the client - longer or non-token ids are replaced
+ */
+static MAX_REQUEST_ID_LEN: usize = 64;
+const IDENTIFICATOR: &str = "middleware";
+
+/*
+ * Per-request state shared by the middlewares
+ */
+pub struct RequestContext {
+    pub peer: Option<SocketAddr>,
+    pub tls: bool,
+    pub started: Instant,
+    /* RequestId => x-request-id */
+    pub request_id: String,
+    /* Sessions => logged in user, Auth => owner of the bearer token */
+    pub user_id: Option<u64>,
+    /* Proxy => response comes from an upstream - its encoding & validators are passed on unchanged */
+    pub proxied: bool,
+}
+
+impl RequestContext {
+    pub fn new(peer: Option<SocketAddr>, tls: bool) -> RequestContext {
+        RequestContext { peer, tls, started: Instant::now(), request_id: String::new(), user_id: None, proxied: false }
+    }
+}
+
+/*
+ * Hooks around the route handler:
+ *  -- before(): in registration order, may annotate the request, Ok(Some(response)) or Err short-circuits the rest & the handler
+ *  -- after(): in reverse order, only for middlewares whose before() ran
+ *  -- complete(): once the response was written - status & body bytes sent
+ */
+pub trait Middleware: Send + Sync {
+    fn before(&self, _request: &mut HttpRequest, _ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        Ok(None)
+    }
+
+    fn after(&self, _request: &HttpRequest, _ctx: &RequestContext, _response: &mut HttpResponse) {}
+
+    fn complete(&self, _request: &HttpRequest, _ctx: &RequestContext, _status: u16, _bytes: usize) {}
+}
+
+pub type RequestHandler = fn(&HttpRequest) -> HttpResponse;
+
+pub struct Pipeline {
+    middlewares: Vec<Box<dyn Middleware>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline { middlewares: Vec::new() }
+    }
+
+    pub fn with<M: Middleware + 'static>(&mut self, middleware: M) -> &mut Pipeline {
+        self.middlewares.push(Box::new(middleware));
+        self
+    }
+
+    /*
+     * Handler runs on the worker thread - Timeout bounds its database connects, reads & writes (database::init_db)
+     */
+    pub fn handle(&self, mut request: HttpRequest, ctx: &mut RequestContext, handler: RequestHandler) -> (HttpRequest, HttpResponse) {
+        let mut ran: usize = 0;
+        let mut short_circuit: Option<HttpResponse> = None;
+        for middleware in self.middlewares.iter() {
+            ran += 1;
+            match middleware.before(&mut request, ctx) {
+                Ok(None) => {},
+                Ok(Some(response)) => {
+                    short_circuit = Some(response);
+                    break;
+                },
+                Err(e) => {
+                    short_circuit = Some(e.into_response(Some(&request)));
+                    break;
+                }
+            }
+        }
+        let mut response: HttpResponse = match short_circuit {
+            Some(response) => response,
+            None => handler(&request)
+        };
+        for middleware in self.middlewares[..ran].iter().rev() {
+            middleware.after(&request, ctx, &mut response);
+        }
+        (request, response)
+    }
+
+    pub fn complete(&self, request: &HttpRequest, ctx: &RequestContext, status: u16, bytes: usize) {
+        for middleware in self.middlewares.iter() {
+            middleware.complete(request, ctx, status, bytes);
+        }
+    }
+}
+
+/*
+ * Picks the site of VHOST.HOSTS by the host header - first, so every response (incl. error pages
+ * of the other middlewares) comes from the site's document root
+ */
+pub struct VirtualHosts {
+    cfg: VirtualHostConfig,
+}
+
+impl VirtualHosts {
+    pub fn new(cfg: VirtualHostConfig) -> VirtualHosts {
+        VirtualHosts { cfg }
+    }
+}
+
+impl Middleware for VirtualHosts {
+    fn before(&self, request: &mut HttpRequest, _ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        if self.cfg.hosts.is_empty() {
+            return Ok(None);
+        }
+        request.vhost = virtualhost::select(&self.cfg, request.header("host"));
+        println!("{}: Host {} => site {}", IDENTIFICATOR, request.header("host").unwrap_or("-"),
+            request.vhost.as_ref().map(|vhost: &VirtualHost| vhost.name.as_str()).unwrap_or("default"));
+        Ok(None)
+    }
+}
+
+/*
+ * x-request-id from the client (if it's a short token) or a new random one,
+ * echoed in the response and written to the access log
+ */
+pub struct RequestId;
+
+impl Middleware for RequestId {
+    fn before(&self, request: &mut HttpRequest, ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        ctx.request_id = match request.header("x-request-id").map(str::trim) {
+            Some(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars().all(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') => id.to_string(),
+            _ => format!("{:016x}", rand::thread_rng().gen::<u64>())
+        };
+        Ok(None)
+    }
+
+    fn after(&self, _request: &HttpRequest, ctx: &RequestContext, response: &mut HttpResponse) {
+        response.set_header("x-request-id", &ctx.request_id);
+    }
+}
+
+enum AccessLogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+/*
+ * Request line on arrival, access log entry to stdout & APP.LOG_DIR/APP.ACCESS_LOG_PATH once written.
+ * APP.ACCESS_LOG_FORMAT:
+ *  -- common:   {ip} - {user} [{time}] "{request line}" {status} {bytes}
+ *  -- combined: common + "{referer}" "{user-agent}"
+ *  -- json:     one object per line with the same fields + latency & request id
+ * bytes => body bytes sent to the client ("-" for none in common/combined, like Apache's %b).
+ */
+pub struct AccessLog {
+    path: String,
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    pub fn new(assets_cfg: &AssetsConfig) -> AccessLog {
+        let format: AccessLogFormat = match assets_cfg.access_log_format.as_str() {
+            "common" => AccessLogFormat::Common,
+            "json" => AccessLogFormat::Json,
+            "combined" => AccessLogFormat::Combined,
+            other => {
+                println!("{}: Unknown access log format {} - using combined", IDENTIFICATOR, other);
+                AccessLogFormat::Combined
+            }
+        };
+        AccessLog { path: format!("{}/{}", assets_cfg.log_dir, assets_cfg.access_log_path), format }
+    }
+}
+
+/*
+ * Quoted fields of the common/combined formats: quote, backslash & control characters are escaped
+ */
+fn escape_log_field(value: &str) -> String {
+    let mut escaped: String = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+impl Middleware for AccessLog {
+    fn before(&self, request: &mut HttpRequest, _ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        println!("http-request: {} {} {} -- {} header(s), {} byte(s) body",
+            request.method, request.target, request.version, request.headers.len(), request.body.len());
+        Ok(None)
+    }
+
+    fn complete(&self, request: &HttpRequest, ctx: &RequestContext, status: u16, bytes: usize) {
+        let now: DateTime<Local> = Local::now();
+        let latency: Duration = ctx.started.elapsed();
+        let client: String = ctx.peer.map(|peer: SocketAddr| peer.ip().to_string()).unwrap_or(String::from("-"));
+        let user: String = ctx.user_id.map(|id: u64| id.to_string()).unwrap_or(String::from("-"));
+        let entry: String = match self.format {
+            AccessLogFormat::Json => json!({
+                "time": now.to_rfc3339(),
+                "client": client,
+                "user": ctx.user_id,
+                "method": request.method,
+                "target": request.target,
+                "protocol": request.version,
+                "status": status,
+                "bytes": bytes,
+                "referer": request.header("referer"),
+                "user_agent": request.header("user-agent"),
+                "latency_ms": latency.as_micros() as f64 / 1000.0,
+                "request_id": ctx.request_id,
+            }).to_string(),
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                let common: String = format!("{} - {} [{}] \"{}\" {} {}",
+                    client,
+                    user,
+                    now.format("%d/%b/%Y:%H:%M:%S %z"),
+                    escape_log_field(&format!("{} {} {}", request.method, request.target, request.version)),
+                    status,
+                    if bytes == 0 { String::from("-") } else { bytes.to_string() });
+                match self.format {
+                    AccessLogFormat::Common => common,
+                    _ => format!("{} \"{}\" \"{}\"",
+                        common,
+                        escape_log_field(request.header("referer").unwrap_or("-")),
+                        escape_log_field(request.header("user-agent").unwrap_or("-")))
+                }
+            }
+        };
+        println!("http-response: {}", &entry);
+        match cstmfiles::f_write(&self.path, entry) {
+            Ok(()) => {}
+            Err(e) => { println!("Oops! Error writing to access log! {:?}", e); }
+        }
+    }
+}
+
+/*
+ * Browser hardening headers from SECURITY.* (+ per-route overrides) - a header set by the handler is kept.
+ * A CSP with {nonce} gets a fresh nonce per request, views render it as {{ csp_nonce }}.
+ */
+pub struct SecurityHeaders {
+    cfg: &'static SecurityConfig,
+}
+
+impl SecurityHeaders {
+    pub fn new(cfg: &'static SecurityConfig) -> SecurityHeaders {
+        SecurityHeaders { cfg }
+    }
+
+    /*
+     * Global headers with the overrides of the longest route prefix matching the path
+     */
+    fn headers_for(&self, path: Option<&str>) -> Vec<(String, String)> {
+        let mut headers: Vec<(String, String)> = self.cfg.headers.clone();
+        let route: Option<&(String, Vec<(String, String)>)> = path.and_then(|path: &str| {
+            self.cfg.routes.iter()
+                .filter(|(prefix, _): &&(String, Vec<(String, String)>)| {
+                    let prefix: &str = prefix.trim_end_matches('/');
+                    prefix.is_empty() || path == prefix || path.starts_with(&format!("{}/", prefix))
+                })
+                .max_by_key(|(prefix, _): &&(String, Vec<(String, String)>)| prefix.len())
+        });
+        if let Some((_, overrides)) = route {
+            for (name, value) in overrides.iter() {
+                match headers.iter_mut().find(|(n, _)| n == name) {
+                    Some(header) => header.1 = value.clone(),
+                    None => headers.push((name.clone(), value.clone()))
+                }
+            }
+        }
+        headers
+    }
+
+    /*
+     * Also used for error responses to requests that couldn't be read (no pipeline, no path)
+     */
+    pub fn apply(&self, response: &mut HttpResponse, path: Option<&str>, nonce: Option<&str>, tls: bool) {
+        let nonce_source: String = nonce.map(|nonce: &str| format!("'nonce-{}'", nonce)).unwrap_or_default();
+        for (name, value) in self.headers_for(path).iter() {
+            if value.is_empty() || response.header(name).is_some() {
+                continue;
+            }
+            if name == "strict-transport-security" && !tls {
+                continue;
+            }
+            let value: String = value.replace("{nonce}", &nonce_source);
+            /* "script-src 'self' {nonce};" without a nonce => "script-src 'self' ;" */
+            let value: String = value.split(';')
+                .map(|directive: &str| directive.split_whitespace().collect::<Vec<&str>>().join(" "))
+                .filter(|directive: &String| !directive.is_empty())
+                .collect::<Vec<String>>()
+                .join("; ");
+            response.set_header(name, &value);
+        }
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn before(&self, request: &mut HttpRequest, _ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        let wants_nonce: bool = self.headers_for(Some(request.path())).iter()
+            .any(|(name, value)| name == "content-security-policy" && value.contains("{nonce}"));
+        if wants_nonce {
+            request.csp_nonce = Some(base64::encode_block(&rand::thread_rng().gen::<[u8; 16]>()));
+        }
+        Ok(None)
+    }
+
+    fn after(&self, request: &HttpRequest, ctx: &RequestContext, response: &mut HttpResponse) {
+        self.apply(response, Some(request.path()), request.csp_nonce.as_deref(), ctx.tls);
+    }
+}
+
+/*
+ * CORS policy from SERVER.CORS_* for requests with an allowed origin:
+ *  -- preflight (OPTIONS + access-control-request-method) => allowed methods, headers & max-age
+ *  -- other requests => allowed origin (+ credentials, exposed headers)
+ * "*" is sent as-is unless credentials are allowed - then the origin is echoed.
+ * Other origins get no CORS headers => the browser blocks the response.
+ */
+pub struct Cors {
+    cfg: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(cfg: CorsConfig) -> Cors {
+        Cors { cfg }
+    }
+
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.cfg.origins.iter().any(|allowed: &String| allowed == "*") {
+            return Some(if self.cfg.credentials { origin.to_string() } else { String::from("*") });
+        }
+        self.cfg.origins.iter()
+            .find(|allowed: &&String| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+            .map(|_| origin.to_string())
+    }
+}
+
+impl Middleware for Cors {
+    fn after(&self, request: &HttpRequest, _ctx: &RequestContext, response: &mut HttpResponse) {
+        if self.cfg.origins.is_empty() {
+            return;
+        }
+        let origin: &str = match request.header("origin") {
+            Some(origin) => origin,
+            None => return
+        };
+        let allow_origin: Option<String> = self.allow_origin(origin);
+        /* answer depends on the origin unless every origin gets "*" */
+        if allow_origin.as_deref() != Some("*") {
+            response.add_vary("origin");
+        }
+        let allow_origin: String = match allow_origin {
+            Some(allow_origin) => allow_origin,
+            None => {
+                println!("{}: CORS request from {} not allowed", IDENTIFICATOR, origin);
+                return;
+            }
+        };
+        response.set_header("access-control-allow-origin", &allow_origin);
+        if self.cfg.credentials {
+            response.set_header("access-control-allow-credentials", "true");
+        }
+        let preflight: bool = request.method == "OPTIONS" && request.header("access-control-request-method").is_some();
+        if !preflight {
+            if !self.cfg.expose_headers.is_empty() {
+                response.set_header("access-control-expose-headers", &self.cfg.expose_headers.join(", "));
+            }
+            return;
+        }
+        if response.status != 204 {
+            return;
+        }
+        let methods: Option<String> = if self.cfg.methods.is_empty() {
+            response.header("allow").map(str::to_string)
+        } else {
+            Some(self.cfg.methods.join(", "))
+        };
+        if let Some(methods) = methods {
+            response.set_header("access-control-allow-methods", &methods);
+        }
+        /* "*" => whatever the browser asks for (a literal "*" isn't honoured with credentials) */
+        let headers: Option<String> = if self.cfg.headers.iter().any(|header: &String| header == "*") {
+            request.header("access-control-request-headers").map(str::to_string)
+        } else if self.cfg.headers.is_empty() {
+            None
+        } else {
+            Some(self.cfg.headers.join(", "))
+        };
+        if let Some(headers) = headers {
+            response.set_header("access-control-allow-headers", &headers);
+        }
+        response.set_header("access-control-max-age", &self.cfg.max_age.to_string());
+    }
+}
+
+/*
+ * RATELIMIT.HTTP_RATE requests per second per client IP => 429 + retry-after
+ */
+pub struct RateLimit;
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut HttpRequest, ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        let ip: IpAddr = match ctx.peer {
+            Some(peer) => peer.ip(),
+            None => return Ok(None)
+        };
+        match ratelimit::check(Limit::HttpRequests, ip) {
+            Ok(()) => Ok(None),
+            Err(retry_after) => {
+                println!("{}: {} over the HTTP request limit - {} {}", IDENTIFICATOR, ip, request.method, request.target);
+                Err(HttpError::TooManyRequests(retry_after))
+            }
+        }
+    }
+}
+
+/*
+ * Loads the cookie session into the request (user => ctx.user_id) & stores it with its cookies after the handler
+ */
+pub struct Sessions {
+    cfg: SessionConfig,
+}
+
+impl Sessions {
+    pub fn new(cfg: SessionConfig) -> Sessions {
+        Sessions { cfg }
+    }
+}
+
+impl Middleware for Sessions {
+    fn before(&self, request: &mut HttpRequest, ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        request.session = Some(sessions::load(request, &self.cfg));
+        ctx.user_id = sessions::user_id(request);
+        Ok(None)
+    }
+
+    fn after(&self, request: &HttpRequest, ctx: &RequestContext, response: &mut HttpResponse) {
+        if let Some(session) = request.session.as_ref() {
+            sessions::save(session, &self.cfg, ctx.tls, response);
+        }
+    }
+}
+
+/*
+ * Paths under SERVER.AUTH_PATHS require a logged in session or "authorization: Bearer {access_token}"
+ * of a token that hasn't expired => 401 otherwise. Preflight requests pass.
+ */
+pub struct Auth {
+    paths: Vec<String>,
+    admin_paths: Vec<String>,
+    admin_role_id: u64,
+}
+
+impl Auth {
+    pub fn new(paths: Vec<String>, admin_paths: Vec<String>, admin_role_id: u64) -> Auth {
+        Auth { paths, admin_paths, admin_role_id }
+    }
+
+    fn has_prefix(paths: &[String], path: &str) -> bool {
+        paths.iter().any(|prefix: &String| {
+            /* "/" => everything */
+            let prefix: &str = prefix.trim_end_matches('/');
+            prefix.is_empty() || path == prefix || path.starts_with(&format!("{}/", prefix))
+        })
+    }
+
+    fn bearer_user_id(request: &HttpRequest) -> Result<u64, HttpError> {
+        let token: &str = match request.header("authorization").and_then(|auth: &str| auth.trim().split_once(' ')) {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() => token.trim(),
+            _ => return Err(HttpError::Unauthorized(String::from("Bearer token required")))
+        };
+        match Token::select_user_id(&helpers::sha256_hex(token))? {
+            Some(user_id) => Ok(user_id),
+            None => Err(HttpError::Unauthorized(String::from("Invalid or expired token")))
+        }
+    }
+}
+
+impl Middleware for Auth {
+    fn before(&self, request: &mut HttpRequest, ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        let admin_only: bool = Auth::has_prefix(&self.admin_paths, request.path());
+        if request.method == "OPTIONS" || !(admin_only || Auth::has_prefix(&self.paths, request.path())) {
+            return Ok(None);
+        }
+        let user_id: u64 = match ctx.user_id {
+            Some(user_id) => user_id,
+            None => Auth::bearer_user_id(request)?
+        };
+        ctx.user_id = Some(user_id);
+        if !admin_only {
+            return Ok(None);
+        }
+        match User::select_by_id(user_id)? {
+            Some(user) if user.is_active() && user.role_id() == self.admin_role_id => Ok(None),
+            _ => Err(HttpError::Forbidden(String::from("Admin role required")))
+        }
+    }
+}
+
+/*
+ * Paths under PROXY.ROUTES are answered by their upstream instead of the handler -
+ * the request is forwarded on the worker thread, PROXY.CONNECT_TIMEOUT & PROXY.READ_TIMEOUT bound the upstream
+ */
+pub struct Proxy {
+    cfg: ProxyConfig,
+}
+
+impl Proxy {
+    pub fn new(cfg: ProxyConfig) -> Proxy {
+        Proxy { cfg }
+    }
+}
+
+impl Middleware for Proxy {
+    fn before(&self, request: &mut HttpRequest, ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        let route: &ProxyRoute = match proxy::find_route(&self.cfg.routes, request.path()) {
+            Some(route) => route,
+            None => return Ok(None)
+        };
+        println!("{}: {} {} => upstream {}", IDENTIFICATOR, request.method, request.target, route.upstream);
+        ctx.proxied = true;
+        proxy::forward(request, ctx, route, &self.cfg).map(Some)
+    }
+}
+
+thread_local! {
+    /* Timeout => when the request on this worker has to be done, None => no limit */
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/*
+ * Time the request on this worker has left - read by database::init_db, zero => it already timed out
+ */
+pub fn time_left() -> Option<Duration> {
+    DEADLINE.with(|deadline: &Cell<Option<Instant>>| deadline.get())
+        .map(|deadline: Instant| deadline.saturating_duration_since(Instant::now()))
+}
+
+/*
+ * SERVER.HANDLER_TIMEOUT seconds for the middlewares after it & the handler (0 => no limit) -
+ * every database connect, read & write gets what's left, once it's over they fail at once (=> 503).
+ * The worker isn't interrupted otherwise, streamed bodies are written after the deadline is cleared.
+ */
+pub struct Timeout {
+    timeout: u64,
+}
+
+impl Timeout {
+    pub fn new(timeout: u64) -> Timeout {
+        Timeout { timeout }
+    }
+}
+
+impl Middleware for Timeout {
+    fn before(&self, _request: &mut HttpRequest, _ctx: &mut RequestContext) -> Result<Option<HttpResponse>, HttpError> {
+        if self.timeout > 0 {
+            DEADLINE.with(|deadline: &Cell<Option<Instant>>| deadline.set(Some(Instant::now() + Duration::from_secs(self.timeout))));
+        }
+        Ok(None)
+    }
+
+    fn after(&self, request: &HttpRequest, ctx: &RequestContext, _response: &mut HttpResponse) {
+        if time_left() == Some(Duration::ZERO) {
+            println!("{}: [{}] {} {} took longer than {}s", IDENTIFICATOR, ctx.request_id, request.method, request.path(), self.timeout);
+        }
+        DEADLINE.with(|deadline: &Cell<Option<Instant>>| deadline.set(None));
+    }
+}
+
+/*
+ * Built-in middlewares in order - built with their config once (at startup) & shared by all workers
+ */
+pub fn pipeline() -> &'static Pipeline {
+    static PIPELINE: OnceLock<Pipeline> = OnceLock::new();
+    PIPELINE.get_or_init(build_pipeline)
+}
+
+fn build_pipeline() -> Pipeline {
+    let middleware_cfg: MiddlewareConfig = MiddlewareConfig::new_cfg();
+    let mut pipeline: Pipeline = Pipeline::new();
+    pipeline
+        .with(VirtualHosts::new(VirtualHostConfig::new_cfg()))
+        .with(RequestId)
+        .with(AccessLog::new(cstmconfig::assets()))
+        .with(SecurityHeaders::new(cstmconfig::security()))
+        .with(Cors::new(CorsConfig::new_cfg()))
+        .with(RateLimit)
+        .with(Timeout::new(middleware_cfg.handler_timeout))
+        .with(Sessions::new(SessionConfig::new_cfg()))
+        .with(Auth::new(middleware_cfg.auth_paths, middleware_cfg.admin_paths, middleware_cfg.admin_role_id))
+        .with(Proxy::new(ProxyConfig::new_cfg()));
+    pipeline
+}
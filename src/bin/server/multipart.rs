@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use rand::Rng;
+use crate::server::cstmconfig;
+use crate::server::httprequest::RequestError;
+
+/*
+ * Upper bounds for a single multipart/form-data body:
+ *  -- all file parts together (written to APP.UPLOAD_DIR, never kept in memory)
+ *  -- number of parts
+ *  -- headers of a single part
+ */
+pub static MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+static MAX_PARTS: usize = 64;
+static MAX_PART_HEADERS_SIZE: u64 = 8 * 1024;
+static READ_BUFFER_SIZE: usize = 8 * 1024;
+const IDENTIFICATOR: &str = "multipart";
+
+/*
+ * File part stored in APP.UPLOAD_DIR under a random name.
+ * The file is removed when the request is dropped unless keep() was called.
+ */
+#[derive(Debug)]
+pub struct UploadedFile {
+    pub field: String,
+    pub filename: String,
+    pub content_type: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /* keep() is called through the shared request reference handlers get */
+    kept: AtomicBool,
+}
+
+impl UploadedFile {
+    pub fn keep(&self) {
+        self.kept.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.kept.load(Ordering::Relaxed) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                println!("{}: Error removing upload {:?}: {}", IDENTIFICATOR, self.path, e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Multipart {
+    pub fields: HashMap<String, String>,
+    pub files: Vec<UploadedFile>,
+}
+
+/*
+ * content-type: multipart/form-data; boundary=----abc => "----abc"
+ */
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param: &str| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary: &String| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/*
+ * Reads the whole multipart body from the reader:
+ *
+ * [preamble]--{boundary}\r\n
+ * content-disposition: form-data; name="{field}"[; filename="{file}"]\r\n
+ * [content-type: {type}]\r\n
+ * \r\n
+ * {data}\r\n--{boundary}\r\n
+ * ...
+ * {data}\r\n--{boundary}--[epilogue]
+ *
+ * Text parts are collected into fields (max_fields_size bytes in total),
+ * file parts are streamed to APP.UPLOAD_DIR.
+ */
+pub fn read_multipart(reader: &mut dyn Read, boundary: &str, max_fields_size: usize) -> Result<Multipart, RequestError> {
+    let mut reader: PartReader = PartReader { inner: reader, buf: Vec::new(), pos: 0 };
+    let mut multipart: Multipart = Multipart::default();
+    let dash_boundary: String = format!("--{}", boundary);
+    let delimiter: String = format!("\r\n--{}", boundary);
+    let mut fields_size: usize = 0;
+    let mut uploaded_size: u64 = 0;
+
+    reader.copy_until(dash_boundary.as_bytes(), &mut io::sink(), MAX_PART_HEADERS_SIZE)?;
+    for parts in 0.. {
+        match reader.take_bytes(2)?.as_slice() {
+            b"--" => break,
+            b"\r\n" => {},
+            _ => return Err(RequestError::BadRequest(String::from("Malformed multipart boundary")))
+        }
+        if parts >= MAX_PARTS {
+            return Err(RequestError::PayloadTooLarge(format!("More than {} multipart parts", MAX_PARTS)));
+        }
+        let mut raw_headers: Vec<u8> = Vec::new();
+        reader.copy_until(b"\r\n\r\n", &mut raw_headers, MAX_PART_HEADERS_SIZE)?;
+        let (field, filename, content_type): (String, Option<String>, String) = parse_part_headers(&raw_headers)?;
+        match filename {
+            /* browsers send an empty file part for file inputs left empty */
+            Some(filename) if filename.is_empty() => {
+                reader.copy_until(delimiter.as_bytes(), &mut io::sink(), MAX_UPLOAD_SIZE - uploaded_size)?;
+            },
+            Some(filename) => {
+                let mut uploaded: UploadedFile = create_upload(Path::new(&cstmconfig::assets().upload_dir), field, filename, content_type)?;
+                let file: File = match File::create(&uploaded.path) {
+                    Ok(file) => file,
+                    Err(e) => return Err(RequestError::Io(format!("Error creating {:?}: {}", uploaded.path, e)))
+                };
+                let mut writer: BufWriter<File> = BufWriter::new(file);
+                uploaded.size = reader.copy_until(delimiter.as_bytes(), &mut writer, MAX_UPLOAD_SIZE - uploaded_size)?;
+                if let Err(e) = writer.flush() {
+                    return Err(RequestError::Io(format!("Error writing {:?}: {}", uploaded.path, e)));
+                }
+                uploaded_size += uploaded.size;
+                println!("{}: Stored upload {:?} ({}, {} bytes) as {:?}",
+                    IDENTIFICATOR, uploaded.filename, uploaded.content_type, uploaded.size, uploaded.path);
+                multipart.files.push(uploaded);
+            },
+            None => {
+                let mut value: Vec<u8> = Vec::new();
+                let limit: u64 = max_fields_size.saturating_sub(fields_size) as u64;
+                fields_size += reader.copy_until(delimiter.as_bytes(), &mut value, limit)? as usize;
+                match String::from_utf8(value) {
+                    Ok(value) => { multipart.fields.insert(field, value); },
+                    Err(_) => return Err(RequestError::BadRequest(format!("Field {} is not valid UTF-8", field)))
+                }
+            }
+        }
+    }
+    /* epilogue is ignored, but has to be read so the next request starts at the right byte */
+    reader.drain()?;
+    Ok(multipart)
+}
+
+fn create_upload(upload_dir: &Path, field: String, filename: String, content_type: String) -> Result<UploadedFile, RequestError> {
+    if let Err(e) = fs::create_dir_all(upload_dir) {
+        return Err(RequestError::Io(format!("Error creating upload dir {:?}: {}", upload_dir, e)));
+    }
+    /* client's file name is only kept as metadata - only a sane extension makes it to the disk */
+    let extension: String = Path::new(&filename).extension()
+        .and_then(|e| e.to_str())
+        .filter(|e: &&str| e.len() <= 10 && e.chars().all(|c: char| c.is_ascii_alphanumeric()))
+        .map(|e: &str| format!(".{}", e.to_lowercase()))
+        .unwrap_or_default();
+    let name: String = format!("{:032x}{}", rand::thread_rng().gen::<u128>(), extension);
+    Ok(UploadedFile { field, filename, content_type, path: upload_dir.join(name), size: 0, kept: AtomicBool::new(false) })
+}
+
+/*
+ * Returns field name, file name (file parts only) and content type of the part
+ */
+fn parse_part_headers(raw_headers: &[u8]) -> Result<(String, Option<String>, String), RequestError> {
+    let headers: &str = match std::str::from_utf8(raw_headers) {
+        Ok(headers) => headers,
+        Err(_) => return Err(RequestError::BadRequest(String::from("Multipart headers are not valid UTF-8")))
+    };
+    let mut field: Option<String> = None;
+    let mut filename: Option<String> = None;
+    let mut content_type: String = String::from("text/plain");
+    for line in headers.split("\r\n") {
+        let (name, value): (&str, &str) = match line.split_once(':') {
+            Some(header) => header,
+            None => return Err(RequestError::BadRequest(format!("Malformed multipart header: {:?}", line)))
+        };
+        if name.trim().eq_ignore_ascii_case("content-type") {
+            content_type = value.trim().to_string();
+        }
+        if name.trim().eq_ignore_ascii_case("content-disposition") {
+            for param in value.split(';').skip(1) {
+                match param.split_once('=') {
+                    Some((key, v)) if key.trim().eq_ignore_ascii_case("name") => field = Some(unquote(v)),
+                    Some((key, v)) if key.trim().eq_ignore_ascii_case("filename") => filename = Some(unquote(v)),
+                    _ => {}
+                }
+            }
+        }
+    }
+    match field {
+        Some(field) => Ok((field, filename, content_type)),
+        None => Err(RequestError::BadRequest(String::from("Multipart part without content-disposition name")))
+    }
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches('"').replace("\\\"", "\"")
+}
+
+/*
+ * Buffered reader that can search for a delimiter spanning several reads
+ * and keep whatever follows it for the next call
+ */
+struct PartReader<'a> {
+    inner: &'a mut dyn Read,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl PartReader<'_> {
+    /*
+     * Moves unread bytes to the front and appends the next read - false on EOF
+     */
+    fn fill(&mut self) -> Result<bool, RequestError> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let start: usize = self.buf.len();
+        self.buf.resize(start + READ_BUFFER_SIZE, 0);
+        let result: io::Result<usize> = self.inner.read(&mut self.buf[start..]);
+        self.buf.truncate(start + *result.as_ref().unwrap_or(&0));
+        match result {
+            Ok(bytes) => Ok(bytes > 0),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => Err(RequestError::Timeout),
+            Err(e) if e.kind() == ErrorKind::InvalidData => Err(RequestError::BadRequest(e.to_string())),
+            Err(e) => Err(RequestError::Io(e.to_string()))
+        }
+    }
+
+    /*
+     * Writes everything before the delimiter to the sink and consumes the delimiter.
+     * Returns number of bytes written, more than limit => PayloadTooLarge.
+     */
+    fn copy_until(&mut self, delimiter: &[u8], sink: &mut dyn Write, limit: u64) -> Result<u64, RequestError> {
+        let mut written: u64 = 0;
+        loop {
+            let unread: &[u8] = &self.buf[self.pos..];
+            let (data, found): (usize, bool) = match unread.windows(delimiter.len()).position(|w: &[u8]| w == delimiter) {
+                Some(index) => (index, true),
+                /* tail might be the beginning of the delimiter */
+                None => (unread.len().saturating_sub(delimiter.len() - 1), false)
+            };
+            written += data as u64;
+            if written > limit {
+                return Err(RequestError::PayloadTooLarge(format!("Multipart part exceeds limit of {} bytes", limit)));
+            }
+            if let Err(e) = sink.write_all(&unread[..data]) {
+                return Err(RequestError::Io(format!("Error storing multipart data: {}", e)));
+            }
+            self.pos += data;
+            if found {
+                self.pos += delimiter.len();
+                return Ok(written);
+            }
+            if !self.fill()? {
+                return Err(RequestError::BadRequest(String::from("Multipart body ended before closing boundary")));
+            }
+        }
+    }
+
+    fn drain(&mut self) -> Result<(), RequestError> {
+        self.pos = self.buf.len();
+        while self.fill()? {
+            self.pos = self.buf.len();
+        }
+        Ok(())
+    }
+
+    fn take_bytes(&mut self, count: usize) -> Result<Vec<u8>, RequestError> {
+        while self.buf.len() - self.pos < count {
+            if !self.fill()? {
+                return Err(RequestError::BadRequest(String::from("Multipart body ended before closing boundary")));
+            }
+        }
+        let bytes: Vec<u8> = self.buf[self.pos..self.pos + count].to_vec();
+        self.pos += count;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * Hands out a few bytes per read so boundaries & delimiters end up split between reads
+     */
+    struct Trickle<'a> {
+        data: &'a [u8],
+        step: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let bytes: usize = self.data.len().min(self.step).min(buf.len());
+            buf[..bytes].copy_from_slice(&self.data[..bytes]);
+            self.data = &self.data[bytes..];
+            Ok(bytes)
+        }
+    }
+
+    static BODY: &[u8] = b"preamble\r\n--b0und\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nfirst line\r\n--b0un\r\nd\r\n--b0und\r\ncontent-disposition: form-data; name=\"empty\"\r\n\r\n\r\n--b0und\r\nContent-Disposition: form-data; name=\"file\"; filename=\"\"\r\nContent-Type: application/octet-stream\r\n\r\n\r\n--b0und--\r\nepilogue";
+
+    #[test]
+    fn parses_boundary_from_content_type() {
+        assert_eq!(boundary("multipart/form-data; boundary=----abc").as_deref(), Some("----abc"));
+        assert_eq!(boundary("Multipart/Form-Data; charset=utf-8; Boundary=\"a b\"").as_deref(), Some("a b"));
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert_eq!(boundary(&format!("multipart/form-data; boundary={}", "a".repeat(71))), None);
+        assert_eq!(boundary("text/plain; boundary=abc"), None);
+    }
+
+    #[test]
+    fn reads_fields_with_boundary_split_across_reads() {
+        for step in [1, 2, 3, 7, 8, BODY.len()] {
+            let mut reader: Trickle = Trickle { data: BODY, step };
+            let multipart: Multipart = read_multipart(&mut reader, "b0und", 1024).unwrap();
+            assert_eq!(multipart.fields.get("title").map(String::as_str), Some("first line\r\n--b0un\r\nd"), "step {}", step);
+            assert_eq!(multipart.fields.get("empty").map(String::as_str), Some(""), "step {}", step);
+            assert!(multipart.files.is_empty());
+            assert!(reader.data.is_empty(), "epilogue left unread with step {}", step);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_or_malformed_bodies() {
+        let truncated: &[u8] = &BODY[..BODY.len() - 20];
+        assert!(matches!(read_multipart(&mut Trickle { data: truncated, step: 5 }, "b0und", 1024), Err(RequestError::BadRequest(_))));
+        let no_name: &[u8] = b"--b\r\nContent-Disposition: form-data\r\n\r\nx\r\n--b--";
+        assert!(matches!(read_multipart(&mut Trickle { data: no_name, step: 64 }, "b", 1024), Err(RequestError::BadRequest(_))));
+        let bad_boundary: &[u8] = b"--bXX";
+        assert!(matches!(read_multipart(&mut Trickle { data: bad_boundary, step: 64 }, "b", 1024), Err(RequestError::BadRequest(_))));
+    }
+
+    #[test]
+    fn enforces_fields_size() {
+        assert!(matches!(read_multipart(&mut Trickle { data: BODY, step: 4 }, "b0und", 8), Err(RequestError::PayloadTooLarge(_))));
+    }
+}
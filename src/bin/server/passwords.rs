@@ -0,0 +1,106 @@
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5;
+use rand::Rng;
+
+/*
+ * PBKDF2-HMAC-SHA256 work factor & sizes - stored hashes keep their own iteration count,
+ * so raising it only affects passwords written afterwards
+ */
+static ITERATIONS: usize = 600_000;
+static SALT_LEN: usize = 16;
+static HASH_LEN: usize = 32;
+static SCHEME: &str = "pbkdf2_sha256";
+const IDENTIFICATOR: &str = "passwords";
+
+fn derive(password: &str, salt: &[u8], iterations: usize, len: usize) -> Result<Vec<u8>, String> {
+    let mut key: Vec<u8> = vec![0; len];
+    match pkcs5::pbkdf2_hmac(password.as_bytes(), salt, iterations, MessageDigest::sha256(), &mut key) {
+        Ok(()) => Ok(key),
+        Err(e) => Err(format!("{}: Error deriving key: {}", IDENTIFICATOR, e))
+    }
+}
+
+/*
+ * Stored form: pbkdf2_sha256${iterations}${salt base64}${hash base64}, random salt per password
+ */
+pub fn hash(password: &str) -> Result<String, String> {
+    let salt: [u8; SALT_LEN] = rand::thread_rng().gen();
+    let key: Vec<u8> = derive(password, &salt, ITERATIONS, HASH_LEN)?;
+    Ok(format!("{}${}${}${}", SCHEME, ITERATIONS, base64::encode_block(&salt), base64::encode_block(&key)))
+}
+
+/*
+ * (iterations, salt, hash) of a stored hash - None for anything else (e.g. a plaintext password)
+ */
+fn parse(stored: &str) -> Option<(usize, Vec<u8>, Vec<u8>)> {
+    let mut parts: std::str::Split<char> = stored.split('$');
+    if parts.next() != Some(SCHEME) {
+        return None;
+    }
+    let iterations: usize = parts.next()?.parse::<usize>().ok().filter(|iterations: &usize| *iterations > 0)?;
+    let salt: Vec<u8> = base64::decode_block(parts.next()?).ok()?;
+    let key: Vec<u8> = base64::decode_block(parts.next()?).ok().filter(|key: &Vec<u8>| !key.is_empty())?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((iterations, salt, key))
+}
+
+/*
+ * Derived key is compared in constant time against the stored one (both have the stored length).
+ * stored None (unknown user) => a throwaway key is derived anyway so the answer takes as long as for a known user.
+ */
+pub fn verify(password: &str, stored: Option<&str>) -> bool {
+    let stored: &str = match stored {
+        Some(stored) => stored,
+        None => {
+            let _ = derive(password, &[0; SALT_LEN], ITERATIONS, HASH_LEN);
+            return false;
+        }
+    };
+    let (iterations, salt, key): (usize, Vec<u8>, Vec<u8>) = match parse(stored) {
+        Some(parsed) => parsed,
+        None => {
+            println!("{}: Stored password is not a {} hash - it has to be set again", IDENTIFICATOR, SCHEME);
+            return false;
+        }
+    };
+    match derive(password, &salt, iterations, key.len()) {
+        Ok(derived) => memcmp::eq(&derived, &key),
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_against_own_hash() {
+        let stored: String = hash("s3cret").unwrap();
+        assert!(stored.starts_with("pbkdf2_sha256$600000$"));
+        assert_ne!(stored, hash("s3cret").unwrap());
+        assert!(verify("s3cret", Some(&stored)));
+        assert!(!verify("S3cret", Some(&stored)));
+    }
+
+    #[test]
+    fn keeps_iterations_of_stored_hash() {
+        let key: Vec<u8> = derive("pw", b"salt", 1000, HASH_LEN).unwrap();
+        let stored: String = format!("{}$1000${}${}", SCHEME, base64::encode_block(b"salt"), base64::encode_block(&key));
+        assert!(verify("pw", Some(&stored)));
+    }
+
+    #[test]
+    fn rejects_unknown_users_and_malformed_hashes() {
+        assert!(!verify("pw", None));
+        for stored in ["pw", "", "pbkdf2_sha256$0$c2FsdA==$a2V5", "pbkdf2_sha256$1000$c2FsdA==", "pbkdf2_sha256$1000$c2FsdA==$$", "md5$1$c2FsdA==$a2V5"] {
+            assert!(!verify("pw", Some(stored)), "{}", stored);
+        }
+    }
+}
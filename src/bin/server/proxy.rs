@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use crate::server::cstmconfig::{ProxyConfig, ProxyRoute};
+use crate::server::httperror::HttpError;
+use crate::server::httprequest::{self, ChunkedReader, HttpRequest, RequestError};
+use crate::server::httpresponse::{HttpResponse, ResponseBody};
+use crate::server::middleware::RequestContext;
+
+/*
+ * Keep-alive connections kept per upstream once a response was read completely
+ */
+static MAX_IDLE_CONNECTIONS: usize = 8;
+/*
+ * Seconds an idle upstream connection is reused for - below the usual 5s keep-alive timeout of upstreams
+ */
+static IDLE_TIMEOUT: u64 = 4;
+/*
+ * Headers describing a single connection - never forwarded in either direction
+ */
+static HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+/*
+ * Methods whose request can be sent twice with the same effect (RFC 9110 9.2.2) -
+ * only these reuse idle connections & are retried when the upstream closed one
+ */
+static IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+const IDENTIFICATOR: &str = "proxy";
+
+type Connection = BufReader<TcpStream>;
+
+struct IdleConnection {
+    connection: Connection,
+    since: Instant,
+}
+
+fn pool() -> MutexGuard<'static, HashMap<String, Vec<IdleConnection>>> {
+    static POOL: OnceLock<Mutex<HashMap<String, Vec<IdleConnection>>>> = OnceLock::new();
+    match POOL.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(pool) => pool,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+/*
+ * Most recently used idle connection to the upstream
+ */
+fn checkout(upstream: &str) -> Option<Connection> {
+    let mut pool: MutexGuard<HashMap<String, Vec<IdleConnection>>> = pool();
+    let idle: &mut Vec<IdleConnection> = pool.get_mut(upstream)?;
+    idle.retain(|idle: &IdleConnection| idle.since.elapsed() < Duration::from_secs(IDLE_TIMEOUT));
+    idle.pop().map(|idle: IdleConnection| idle.connection)
+}
+
+/*
+ * Connection is only reusable if nothing past the response was received
+ */
+fn checkin(upstream: &str, connection: Connection) {
+    if !connection.buffer().is_empty() {
+        return;
+    }
+    let mut pool: MutexGuard<HashMap<String, Vec<IdleConnection>>> = pool();
+    let idle: &mut Vec<IdleConnection> = pool.entry(upstream.to_string()).or_default();
+    if idle.len() < MAX_IDLE_CONNECTIONS {
+        idle.push(IdleConnection { connection, since: Instant::now() });
+    }
+}
+
+fn connect(route: &ProxyRoute, cfg: &ProxyConfig) -> Result<Connection, HttpError> {
+    let addrs: Vec<SocketAddr> = match route.upstream.to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => return Err(HttpError::BadGateway(format!("{}: Can't resolve upstream {}: {}", IDENTIFICATOR, route.upstream, e)))
+    };
+    let mut last_error: Option<io::Error> = None;
+    for addr in addrs.iter() {
+        match TcpStream::connect_timeout(addr, Duration::from_secs(cfg.connect_timeout)) {
+            Ok(stream) => {
+                let timeout: Option<Duration> = Some(Duration::from_secs(cfg.read_timeout));
+                if let Err(e) = stream.set_read_timeout(timeout).and(stream.set_write_timeout(timeout)) {
+                    return Err(HttpError::BadGateway(format!("{}: Error setting upstream timeouts: {}", IDENTIFICATOR, e)));
+                }
+                return Ok(BufReader::new(stream));
+            },
+            Err(e) => last_error = Some(e)
+        }
+    }
+    match last_error {
+        Some(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => Err(HttpError::GatewayTimeout(
+            format!("{}: Connecting to upstream {} timed out after {}s", IDENTIFICATOR, route.upstream, cfg.connect_timeout)
+        )),
+        Some(e) => Err(HttpError::BadGateway(format!("{}: Can't connect to upstream {}: {}", IDENTIFICATOR, route.upstream, e))),
+        None => Err(HttpError::BadGateway(format!("{}: Upstream {} resolved to no address", IDENTIFICATOR, route.upstream)))
+    }
+}
+
+/*
+ * Route with the longest prefix matching the path
+ */
+pub fn find_route<'a>(routes: &'a [ProxyRoute], path: &str) -> Option<&'a ProxyRoute> {
+    routes.iter()
+        .filter(|route: &&ProxyRoute| {
+            let prefix: &str = route.path.trim_end_matches('/');
+            prefix.is_empty() || path == prefix || path.starts_with(&format!("{}/", prefix))
+        })
+        .max_by_key(|route: &&ProxyRoute| route.path.len())
+}
+
+/*
+ * Upstream URL path replaces the route prefix: /api + http://upstream/v1 => /api/users?page=2 => /v1/users?page=2
+ */
+fn upstream_target(request: &HttpRequest, route: &ProxyRoute) -> String {
+    let upstream_path: &str = match route.upstream_path.as_deref() {
+        Some(upstream_path) => upstream_path,
+        None => return request.target.clone()
+    };
+    let rest: &str = &request.path()[route.path.trim_end_matches('/').len()..];
+    let mut target: String = format!("{}{}", upstream_path.trim_end_matches('/'), rest);
+    if !target.starts_with('/') {
+        target.insert(0, '/');
+    }
+    if let Some((_, query)) = request.target.split_once('?') {
+        target.push('?');
+        target.push_str(query);
+    }
+    target
+}
+
+/*
+ * Header names listed in the connection header are hop-by-hop as well
+ */
+fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name)
+        || connection.is_some_and(|connection: &str| connection.split(',').any(|token: &str| token.trim().eq_ignore_ascii_case(name)))
+}
+
+/*
+ * Request as sent to the upstream:
+ *  -- hop-by-hop headers are dropped, the body (already decoded) is sent with content-length
+ *  -- host => upstream host:port (or the client's with PRESERVE_HOST), original host => x-forwarded-host
+ *  -- client IP is appended to x-forwarded-for, x-forwarded-proto => http / https
+ *  -- x-request-id => id of the request on this server
+ */
+fn build_request(request: &HttpRequest, ctx: &RequestContext, route: &ProxyRoute) -> Vec<u8> {
+    let connection: Option<&str> = request.header("connection");
+    let mut head: String = format!("{} {} HTTP/1.1\r\n", request.method, upstream_target(request, route));
+    let host: &str = match request.header("host") {
+        Some(host) if route.preserve_host => host,
+        _ => &route.upstream
+    };
+    head.push_str(&format!("host: {}\r\n", host));
+    for (name, value) in request.headers.iter() {
+        let skip: bool = is_hop_by_hop(name, connection) || matches!(name.as_str(),
+            "host" | "content-length" | "expect" | "x-forwarded-for" | "x-forwarded-proto" | "x-forwarded-host" | "x-request-id");
+        if !skip {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    if let Some(peer) = ctx.peer {
+        let forwarded_for: String = match request.header("x-forwarded-for") {
+            Some(forwarded_for) => format!("{}, {}", forwarded_for, peer.ip()),
+            None => peer.ip().to_string()
+        };
+        head.push_str(&format!("x-forwarded-for: {}\r\n", forwarded_for));
+    }
+    head.push_str(&format!("x-forwarded-proto: {}\r\n", if ctx.tls { "https" } else { "http" }));
+    if let Some(host) = request.header("host") {
+        head.push_str(&format!("x-forwarded-host: {}\r\n", host));
+    }
+    if !ctx.request_id.is_empty() {
+        head.push_str(&format!("x-request-id: {}\r\n", ctx.request_id));
+    }
+    if !request.body.is_empty() || matches!(request.method.as_str(), "POST" | "PUT" | "PATCH") {
+        head.push_str(&format!("content-length: {}\r\n", request.body.len()));
+    }
+    head.push_str("connection: keep-alive\r\n\r\n");
+    let mut bytes: Vec<u8> = head.into_bytes();
+    bytes.extend_from_slice(&request.body);
+    bytes
+}
+
+struct ResponseHead {
+    status: u16,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/*
+ * {HTTP/1.x} {status} {reason} + header lines, interim 1xx responses are skipped.
+ * RequestError::Closed => upstream closed the connection before answering.
+ */
+fn read_response_head(connection: &mut Connection) -> Result<ResponseHead, RequestError> {
+    loop {
+        let mut head_size: usize = 0;
+        let status_line: String = match httprequest::read_line(connection, &mut head_size)? {
+            Some(line) => line,
+            None => return Err(RequestError::Closed)
+        };
+        let mut parts = status_line.splitn(3, ' ');
+        let version: String = parts.next().unwrap_or("").to_string();
+        let status: u16 = match parts.next().map(|status: &str| status.parse::<u16>()) {
+            Some(Ok(status)) if version.starts_with("HTTP/1.") && (100..600).contains(&status) => status,
+            _ => return Err(RequestError::BadRequest(format!("Malformed status line: {:?}", status_line)))
+        };
+        let mut headers: Vec<(String, String)> = Vec::new();
+        loop {
+            match httprequest::read_line(connection, &mut head_size)? {
+                Some(line) if line.is_empty() => break,
+                Some(line) => headers.push(httprequest::parse_header_line(&line)?),
+                None => return Err(RequestError::BadRequest(String::from("Connection closed inside headers")))
+            }
+        }
+        if status >= 200 || status == 101 {
+            return Ok(ResponseHead { status, version, headers });
+        }
+    }
+}
+
+/*
+ * Sends the request and reads the response head
+ */
+fn exchange(connection: &mut Connection, request: &[u8]) -> Result<ResponseHead, RequestError> {
+    let stream: &mut TcpStream = connection.get_mut();
+    match stream.write_all(request).and_then(|()| stream.flush()) {
+        Ok(()) => {},
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Err(RequestError::Timeout),
+        Err(e) => return Err(RequestError::Io(e.to_string()))
+    }
+    read_response_head(connection)
+}
+
+/*
+ * Body of the upstream response is streamed to the client, the connection goes back to the pool
+ * once the body was read to its end (content-length or last chunk) and the upstream keeps it open.
+ */
+fn build_response(mut connection: Connection, head: ResponseHead, request: &HttpRequest, upstream: &str) -> Result<HttpResponse, HttpError> {
+    let connection_header: Option<String> = head.header("connection").map(str::to_lowercase);
+    let reusable: bool = match connection_header.as_deref() {
+        Some(connection) if connection.split(',').any(|token: &str| token.trim() == "close") => false,
+        Some(connection) if head.version == "HTTP/1.0" => connection.split(',').any(|token: &str| token.trim() == "keep-alive"),
+        _ => head.version != "HTTP/1.0"
+    };
+    let chunked: bool = head.header("transfer-encoding").is_some_and(|encoding: &str| encoding.to_lowercase().contains("chunked"));
+    let content_length: Option<u64> = match head.header("content-length") {
+        Some(len) => match len.trim().parse::<u64>() {
+            Ok(len) => Some(len),
+            Err(_) => return Err(HttpError::BadGateway(format!("{}: Invalid content-length from upstream {}: {}", IDENTIFICATOR, upstream, len)))
+        },
+        None => None
+    };
+    if head.status == 101 {
+        return Err(HttpError::BadGateway(format!("{}: Upstream {} switched protocols - upgrades aren't proxied", IDENTIFICATOR, upstream)));
+    }
+    let mut response: HttpResponse = HttpResponse::new(head.status, Vec::new());
+    response.headers = head.headers.iter()
+        .filter(|(name, _)| !is_hop_by_hop(name, connection_header.as_deref()) && name != "content-length")
+        .cloned()
+        .collect();
+    let upstream: String = upstream.to_string();
+    let no_body: bool = request.method == "HEAD" || head.status == 204 || head.status == 304;
+    response.body = if no_body {
+        if reusable {
+            checkin(&upstream, connection);
+        }
+        match content_length {
+            /* HEAD => length of the body a GET would get */
+            Some(len) if !chunked => ResponseBody::SizedStream(len, Box::new(|_writer: &mut dyn Write| Ok(()))),
+            _ if request.method == "HEAD" => ResponseBody::Stream(Box::new(|_writer: &mut dyn Write| Ok(()))),
+            _ => ResponseBody::Bytes(Vec::new())
+        }
+    } else if chunked {
+        ResponseBody::Stream(Box::new(move |writer: &mut dyn Write| {
+            io::copy(&mut ChunkedReader::new(&mut connection), writer)?;
+            if reusable {
+                checkin(&upstream, connection);
+            }
+            Ok(())
+        }))
+    } else if let Some(len) = content_length {
+        ResponseBody::SizedStream(len, Box::new(move |writer: &mut dyn Write| {
+            let copied: u64 = io::copy(&mut (&mut connection).take(len), writer)?;
+            if copied < len {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, format!("Upstream sent {} of {} bytes", copied, len)));
+            }
+            if reusable {
+                checkin(&upstream, connection);
+            }
+            Ok(())
+        }))
+    } else {
+        /* body ends when the upstream closes the connection */
+        ResponseBody::Stream(Box::new(move |writer: &mut dyn Write| {
+            io::copy(&mut connection, writer)?;
+            Ok(())
+        }))
+    };
+    Ok(response)
+}
+
+fn to_http_error(e: RequestError, route: &ProxyRoute, cfg: &ProxyConfig) -> HttpError {
+    match e {
+        RequestError::Timeout => HttpError::GatewayTimeout(
+            format!("{}: Upstream {} didn't respond within {}s", IDENTIFICATOR, route.upstream, cfg.read_timeout)
+        ),
+        RequestError::Closed => HttpError::BadGateway(format!("{}: Upstream {} closed the connection", IDENTIFICATOR, route.upstream)),
+        e => HttpError::BadGateway(format!("{}: Invalid response from upstream {}: {}", IDENTIFICATOR, route.upstream, e))
+    }
+}
+
+/*
+ * Forwards the request to the route's upstream:
+ *  -- can't connect / invalid response => 502, connect or read timeout => 504
+ *  -- idempotent requests reuse idle connections & are retried once on a new connection if the upstream closed it -
+ *     it may have processed the request already, so POST/PATCH always get a new connection and are never sent twice
+ *  -- multipart bodies are parsed (& stored) while reading the request => 415, they can't be forwarded
+ *  -- WebSocket & other upgrades aren't supported (upgrade is a hop-by-hop header)
+ */
+pub fn forward(request: &HttpRequest, ctx: &RequestContext, route: &ProxyRoute, cfg: &ProxyConfig) -> Result<HttpResponse, HttpError> {
+    if request.multipart.is_some() {
+        return Err(HttpError::UnsupportedMediaType(String::from("Multipart bodies can't be forwarded")));
+    }
+    let upstream_request: Vec<u8> = build_request(request, ctx, route);
+    let idempotent: bool = IDEMPOTENT_METHODS.contains(&request.method.as_str());
+    if let Some(mut connection) = if idempotent { checkout(&route.upstream) } else { None } {
+        match exchange(&mut connection, &upstream_request) {
+            Ok(head) => return build_response(connection, head, request, &route.upstream),
+            Err(RequestError::Closed) | Err(RequestError::Io(_)) => {
+                println!("{}: Idle connection to {} was closed - retrying on a new one", IDENTIFICATOR, route.upstream);
+            },
+            Err(e) => return Err(to_http_error(e, route, cfg))
+        }
+    }
+    let mut connection: Connection = connect(route, cfg)?;
+    match exchange(&mut connection, &upstream_request) {
+        Ok(head) => build_response(connection, head, request, &route.upstream),
+        Err(e) => Err(to_http_error(e, route, cfg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, upstream_path: Option<&str>) -> ProxyRoute {
+        ProxyRoute { path: path.to_string(), upstream: String::from("127.0.0.1:8081"), upstream_path: upstream_path.map(str::to_string), preserve_host: false }
+    }
+
+    fn request(target: &str) -> HttpRequest {
+        let raw: String = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target);
+        HttpRequest::read_from(&mut raw.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins_on_segment_boundaries() {
+        let routes: Vec<ProxyRoute> = vec![route("/api", None), route("/api/v2/", None), route("/static", None)];
+        assert_eq!(find_route(&routes, "/api").unwrap().path, "/api");
+        assert_eq!(find_route(&routes, "/api/users").unwrap().path, "/api");
+        assert_eq!(find_route(&routes, "/api/v2").unwrap().path, "/api/v2/");
+        assert_eq!(find_route(&routes, "/api/v2/users").unwrap().path, "/api/v2/");
+        assert!(find_route(&routes, "/apis").is_none());
+        assert!(find_route(&routes, "/").is_none());
+    }
+
+    #[test]
+    fn root_route_matches_everything() {
+        let routes: Vec<ProxyRoute> = vec![route("/", None), route("/api", None)];
+        assert_eq!(find_route(&routes, "/anything").unwrap().path, "/");
+        assert_eq!(find_route(&routes, "/api/x").unwrap().path, "/api");
+    }
+
+    #[test]
+    fn target_is_kept_without_upstream_path() {
+        assert_eq!(upstream_target(&request("/api/users?page=2"), &route("/api", None)), "/api/users?page=2");
+    }
+
+    #[test]
+    fn upstream_path_replaces_prefix() {
+        assert_eq!(upstream_target(&request("/api/users?page=2"), &route("/api", Some("/v1"))), "/v1/users?page=2");
+        assert_eq!(upstream_target(&request("/api/users"), &route("/api/", Some("/v1/"))), "/v1/users");
+        assert_eq!(upstream_target(&request("/api"), &route("/api", Some("/"))), "/");
+        assert_eq!(upstream_target(&request("/api?q=1"), &route("/api", Some("/v1"))), "/v1?q=1");
+        assert_eq!(upstream_target(&request("/x/y"), &route("/", Some("/v1"))), "/v1/x/y");
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::Instant;
+use crate::server::cstmconfig::RateLimitConfig;
+
+/*
+ * Clients tracked per limiter - above it, clients whose bucket refilled are forgotten
+ */
+static MAX_TRACKED_CLIENTS: usize = 10_000;
+const IDENTIFICATOR: &str = "ratelimit";
+
+/*
+ * What is being limited - each has its own buckets & rate (RATELIMIT.*)
+ */
+pub enum Limit {
+    HttpRequests,
+    Connections,
+    TcpMessages,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/*
+ * Token bucket per client IP: holds up to burst tokens, refills at rate tokens/s,
+ * every request/connection/message takes one
+ */
+struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    fn new(rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter { rate, burst: burst.max(1.0), buckets: Mutex::new(HashMap::new()) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<IpAddr, Bucket>> {
+        match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+
+    /*
+     * Err => seconds until the next token
+     */
+    fn take(&self, ip: IpAddr) -> Result<(), u64> {
+        /* rate 0 => unlimited */
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let now: Instant = Instant::now();
+        let mut buckets: MutexGuard<HashMap<IpAddr, Bucket>> = self.lock();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            let (rate, burst): (f64, f64) = (self.rate, self.burst);
+            buckets.retain(|_, bucket: &mut Bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+        }
+        let bucket: &mut Bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+    }
+}
+
+struct Limiters {
+    http_requests: RateLimiter,
+    connections: RateLimiter,
+    tcp_messages: RateLimiter,
+    tcp_disconnect: bool,
+}
+
+/*
+ * Built on first use - buckets live as long as the server
+ */
+fn limiters() -> &'static Limiters {
+    static LIMITERS: OnceLock<Limiters> = OnceLock::new();
+    LIMITERS.get_or_init(|| {
+        let cfg: RateLimitConfig = RateLimitConfig::new_cfg();
+        println!("{}: HTTP requests {}/s (burst {}), connections {}/s (burst {}), TCP messages {}/s (burst {})",
+            IDENTIFICATOR, cfg.http_rate, cfg.http_burst, cfg.connection_rate, cfg.connection_burst, cfg.tcp_rate, cfg.tcp_burst);
+        Limiters {
+            http_requests: RateLimiter::new(cfg.http_rate, cfg.http_burst),
+            connections: RateLimiter::new(cfg.connection_rate, cfg.connection_burst),
+            tcp_messages: RateLimiter::new(cfg.tcp_rate, cfg.tcp_burst),
+            tcp_disconnect: cfg.tcp_disconnect,
+        }
+    })
+}
+
+/*
+ * Takes a token for the client - Err(retry after seconds) if it's over the limit
+ */
+pub fn check(limit: Limit, ip: IpAddr) -> Result<(), u64> {
+    let limiters: &Limiters = limiters();
+    let limiter: &RateLimiter = match limit {
+        Limit::HttpRequests => &limiters.http_requests,
+        Limit::Connections => &limiters.connections,
+        Limit::TcpMessages => &limiters.tcp_messages,
+    };
+    limiter.take(ip)
+}
+
+/*
+ * TCP client over the message limit: true => disconnect, false => drop messages with a notice
+ */
+pub fn tcp_disconnect() -> bool {
+    limiters().tcp_disconnect
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn allows_burst_then_reports_wait() {
+        let limiter: RateLimiter = RateLimiter::new(0.5, 3.0);
+        let ip: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        for _ in 0..3 {
+            assert_eq!(limiter.take(ip), Ok(()));
+        }
+        assert_eq!(limiter.take(ip), Err(2));
+    }
+
+    #[test]
+    fn buckets_are_per_client() {
+        let limiter: RateLimiter = RateLimiter::new(1.0, 1.0);
+        let first: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let second: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(limiter.take(first), Ok(()));
+        assert_eq!(limiter.take(first), Err(1));
+        assert_eq!(limiter.take(second), Ok(()));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter: RateLimiter = RateLimiter::new(1000.0, 1.0);
+        let ip: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(limiter.take(ip), Ok(()));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(limiter.take(ip), Ok(()));
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let limiter: RateLimiter = RateLimiter::new(0.0, 1.0);
+        for _ in 0..100 {
+            assert_eq!(limiter.take(IpAddr::V4(Ipv4Addr::LOCALHOST)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn forgets_refilled_clients_when_full() {
+        let limiter: RateLimiter = RateLimiter::new(1000.0, 1.0);
+        for i in 0..MAX_TRACKED_CLIENTS as u32 {
+            let _ = limiter.take(IpAddr::V4(Ipv4Addr::from(i)));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(limiter.take(IpAddr::V4(Ipv4Addr::LOCALHOST)), Ok(()));
+        assert!(limiter.lock().len() < MAX_TRACKED_CLIENTS);
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use crate::server::httperror::HttpError;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::HttpResponse;
+
+/*
+ * Values extracted from {name} segments of the matched pattern
+ */
+pub type Params = HashMap<String, String>;
+pub type Handler = fn(&HttpRequest, &Params) -> Result<HttpResponse, HttpError>;
+
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+struct Route {
+    method: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+pub enum RouteMatch {
+    Found(Handler, Params),
+    /* path exists but not for this method - carries methods that are registered for it */
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
+/*
+ * Routes are registered per method and pattern:
+ *  -- "/users"                => static path
+ *  -- "/users/{id}"           => {id} matches any single segment
+ *  -- "/tokens/{id}/refresh"  => params can be anywhere in the path
+ * Trailing and repeated slashes are ignored.
+ */
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn add(&mut self, method: &str, pattern: &str, handler: Handler) -> &mut Router {
+        let segments: Vec<Segment> = split_path(pattern).iter().map(|s: &&str| {
+            match s.strip_prefix('{').and_then(|s: &str| s.strip_suffix('}')) {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Static(s.to_string())
+            }
+        }).collect();
+        self.routes.push(Route { method: method.to_string(), segments, handler });
+        self
+    }
+
+    pub fn get(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.add("GET", pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.add("POST", pattern, handler)
+    }
+
+    pub fn put(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.add("PUT", pattern, handler)
+    }
+
+    pub fn patch(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.add("PATCH", pattern, handler)
+    }
+
+    pub fn delete(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.add("DELETE", pattern, handler)
+    }
+
+    /*
+     * First route matching both method and path wins - HEAD is served by GET routes.
+     * If only the path matches => 405, if nothing matches => 404.
+     */
+    pub fn route(&self, method: &str, path: &str) -> RouteMatch {
+        let lookup_method: &str = if method == "HEAD" { "GET" } else { method };
+        let path_segments: Vec<&str> = split_path(path);
+        for route in self.routes.iter() {
+            if route.method != lookup_method {
+                continue;
+            }
+            if let Some(params) = match_segments(&route.segments, &path_segments) {
+                return RouteMatch::Found(route.handler, params);
+            }
+        }
+        let allowed: Vec<String> = self.allowed_methods(path);
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+
+    /*
+     * Methods registered for the path (+ HEAD for GET routes and OPTIONS),
+     * empty if no route matches the path
+     */
+    pub fn allowed_methods(&self, path: &str) -> Vec<String> {
+        let path_segments: Vec<&str> = split_path(path);
+        let mut allowed: Vec<String> = Vec::new();
+        for route in self.routes.iter() {
+            if match_segments(&route.segments, &path_segments).is_some() && !allowed.contains(&route.method) {
+                allowed.push(route.method.clone());
+            }
+        }
+        if allowed.is_empty() {
+            return allowed;
+        }
+        if allowed.iter().any(|m: &String| m == "GET") {
+            allowed.push(String::from("HEAD"));
+        }
+        allowed.push(String::from("OPTIONS"));
+        allowed
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s: &&str| !s.is_empty()).collect()
+}
+
+fn match_segments(segments: &[Segment], path_segments: &[&str]) -> Option<Params> {
+    if segments.len() != path_segments.len() {
+        return None;
+    }
+    let mut params: Params = HashMap::new();
+    for (segment, value) in segments.iter().zip(path_segments.iter()) {
+        match segment {
+            Segment::Static(s) => {
+                if s != value {
+                    return None;
+                }
+            },
+            Segment::Param(name) => {
+                params.insert(name.clone(), value.to_string());
+            }
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(_request: &HttpRequest, _params: &Params) -> Result<HttpResponse, HttpError> {
+        Ok(HttpResponse::new(200, b"list".to_vec()))
+    }
+
+    fn show(_request: &HttpRequest, params: &Params) -> Result<HttpResponse, HttpError> {
+        Ok(HttpResponse::new(200, params["id"].as_bytes().to_vec()))
+    }
+
+    fn router() -> Router {
+        let mut router: Router = Router::new();
+        router.get("/users", list)
+            .post("/users", list)
+            .get("/users/{id}", show)
+            .delete("/users/{id}", show)
+            .post("/tokens/{id}/refresh", show);
+        router
+    }
+
+    fn found(router: &Router, method: &str, path: &str) -> Option<Params> {
+        match router.route(method, path) {
+            RouteMatch::Found(_, params) => Some(params),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn matches_static_and_param_segments() {
+        let router: Router = router();
+        assert_eq!(found(&router, "GET", "/users"), Some(Params::new()));
+        assert_eq!(found(&router, "GET", "/users/42").unwrap()["id"], "42");
+        assert_eq!(found(&router, "POST", "/tokens/7/refresh").unwrap()["id"], "7");
+        assert_eq!(found(&router, "GET", "//users//42/").unwrap()["id"], "42");
+    }
+
+    #[test]
+    fn head_is_served_by_get_routes() {
+        assert!(found(&router(), "HEAD", "/users/1").is_some());
+    }
+
+    #[test]
+    fn unknown_path_is_not_found() {
+        let router: Router = router();
+        for path in ["/", "/user", "/users/1/2", "/tokens/7"] {
+            assert!(matches!(router.route("GET", path), RouteMatch::NotFound), "{}", path);
+        }
+    }
+
+    #[test]
+    fn known_path_with_other_method_is_not_allowed() {
+        match router().route("PUT", "/users/1") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, ["GET", "DELETE", "HEAD", "OPTIONS"]),
+            _ => panic!("expected 405")
+        }
+        match router().route("GET", "/tokens/1/refresh") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, ["POST", "OPTIONS"]),
+            _ => panic!("expected 405")
+        }
+        assert!(router().allowed_methods("/nope").is_empty());
+    }
+}
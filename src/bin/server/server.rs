@@ -3,17 +3,39 @@
  * traits that let us read from and write to the stream
  */
 use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use openssl::ssl::SslAcceptor;
 use crate::server::cstmconfig::AssetsConfig;
 mod threadpool;
 mod thrstdin;
 mod helpers;
 mod database;
+mod eventstream;
+mod cookies;
 mod cstmconfig;
 mod cstmfiles;
+mod httpcompression;
 mod httpconnection;
+mod httperror;
+mod httphandlers;
+mod httprange;
+mod httprequest;
+mod httpresponse;
+mod middleware;
 mod tcpconnection;
 mod thrchannel;
+mod tlsstream;
+mod multipart;
+mod passwords;
+mod proxy;
+mod ratelimit;
+mod router;
+mod sessions;
+mod staticfiles;
+mod templates;
 mod validator;
+mod virtualhost;
+mod websocket;
 
 const IDENTIFICATOR: &str = "core";
 
@@ -22,9 +44,10 @@ pub struct Server {}
 impl Server {
 
     pub fn server(&self) -> Result<(), String> {
-        let cfg: cstmconfig::ServerConfig = cstmconfig::ServerConfig::new_cfg();
-        let assets_cfg: AssetsConfig = cstmconfig::AssetsConfig::new_cfg();
+        let cfg: &cstmconfig::ServerConfig = cstmconfig::server();
+        let assets_cfg: &AssetsConfig = cstmconfig::assets();
         let log_path: String = format!("{}/{}", &assets_cfg.log_dir, &assets_cfg.log_path);
+        let access_log_path: String = format!("{}/{}", &assets_cfg.log_dir, &assets_cfg.access_log_path);
         match cstmfiles::f_create_dir(&assets_cfg.log_dir) {
             Ok(()) => {
                 println!("cstmfiles: Parent dir created: {}", assets_cfg.log_dir);
@@ -58,10 +81,28 @@ impl Server {
             Ok(()) => { println!("{}: Successfuly created log file at {}", IDENTIFICATOR, &log_path); }
             Err(_e) => {}
         }
-        match self::init_server(&addrs) {
-            Ok(listener) => {
-                println!("{}: [{}] listening for connections..", IDENTIFICATOR, &addrs[0]);
-                match self::listen_for_connections(&listener) {
+        match cstmfiles::f_create(&access_log_path) {
+            Ok(()) => { println!("{}: Successfuly created access log file at {}", IDENTIFICATOR, &access_log_path); }
+            Err(_e) => {}
+        }
+        match database::migrate() {
+            Ok(()) => { println!("{}: Database schema is up to date", IDENTIFICATOR); }
+            Err(e) => { println!("{}: Database migration skipped: {}", IDENTIFICATOR, e); }
+        }
+        /* middleware pipeline & router are built once with their config - a broken .env shows up before the first request */
+        middleware::pipeline();
+        httpconnection::router();
+        let acceptor: Option<Arc<SslAcceptor>> = if cfg.tls_ports.is_empty() {
+            None
+        } else {
+            Some(Arc::new(tlsstream::build_acceptor(&cfg.tls_cert, &cfg.tls_key)?))
+        };
+        match self::init_server(&addrs, &cfg.tls_ports) {
+            Ok(listeners) => {
+                let listeners: Vec<(TcpListener, Option<Arc<SslAcceptor>>)> = listeners.into_iter()
+                    .map(|(listener, tls): (TcpListener, bool)| (listener, if tls { acceptor.clone() } else { None }))
+                    .collect();
+                match self::listen_for_connections(listeners) {
                     Ok(()) => Ok(()),
                     Err(e) => return Err(format!("{}: Error on listener: {}", IDENTIFICATOR, e))
                 }
@@ -72,22 +113,37 @@ impl Server {
 }
 
 
-fn init_server(ip_port: &[SocketAddr; 2]) -> Result<TcpListener, String>{
+/*
+ * PORT1 is bound, PORT2 is the fallback if PORT1 can't be bound.
+ * If exactly one of them is in SERVER.TLS_PORTS both are bound - e.g. plain on PORT1 & TLS on PORT2.
+ * Returns listeners with a flag whether they serve TLS.
+ */
+fn init_server(ip_port: &[SocketAddr; 2], tls_ports: &[u16]) -> Result<Vec<(TcpListener, bool)>, String> {
+    let tls: [bool; 2] = [tls_ports.contains(&ip_port[0].port()), tls_ports.contains(&ip_port[1].port())];
+    let mut listeners: Vec<(TcpListener, bool)> = Vec::new();
     match TcpListener::bind(format!("{}", ip_port[0])) {
-        Ok(listener) => Ok(listener),
-        _ => {
-            println!("{}: Error on bind().. Trying fallback ip:port pair..", IDENTIFICATOR);
-            match TcpListener::bind(format!("{}", ip_port[1])) {
-                Ok(listener) => Ok(listener),
-                _ => return Err(format!("{}: Error on bind() on fallback ip:port pair.", IDENTIFICATOR))
-            }
+        Ok(listener) => {
+            println!("{}: [{}] listening for {} connections..", IDENTIFICATOR, &ip_port[0], if tls[0] { "TLS" } else { "plain" });
+            listeners.push((listener, tls[0]));
+        },
+        _ => println!("{}: Error on bind().. Trying fallback ip:port pair..", IDENTIFICATOR)
+    }
+    if listeners.is_empty() || tls[0] != tls[1] {
+        match TcpListener::bind(format!("{}", ip_port[1])) {
+            Ok(listener) => {
+                println!("{}: [{}] listening for {} connections..", IDENTIFICATOR, &ip_port[1], if tls[1] { "TLS" } else { "plain" });
+                listeners.push((listener, tls[1]));
+            },
+            _ if listeners.is_empty() => return Err(format!("{}: Error on bind() on fallback ip:port pair.", IDENTIFICATOR)),
+            _ => println!("{}: Error on bind() on {} - serving {} only.", IDENTIFICATOR, &ip_port[1], &ip_port[0])
         }
     }
+    Ok(listeners)
 }
 
 
-fn listen_for_connections(listener: &TcpListener) -> Result<(), String> {
-    match threadpool::handle_in_threadpool(&listener) {
+fn listen_for_connections(listeners: Vec<(TcpListener, Option<Arc<SslAcceptor>>)>) -> Result<(), String> {
+    match threadpool::handle_in_threadpool(listeners) {
         Ok(()) => { println!("{}: Worker finsihed the job successfuly.", IDENTIFICATOR); },
         Err(e) => { println!("{}: Error on threadpool handler: {}", IDENTIFICATOR, e); }
     }
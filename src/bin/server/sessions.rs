@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use chrono::Utc;
+use rand::Rng;
+use crate::server::cookies::Cookie;
+use crate::server::cstmconfig::SessionConfig;
+use crate::server::database::{RememberToken, StoredSession, User};
+use crate::server::helpers;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::HttpResponse;
+
+/*
+ * Sessions kept in memory - above it, expired sessions are dropped & then the least recently used ones
+ */
+static MAX_SESSIONS: usize = 100_000;
+/* seconds after which a session gets a new id - login & values are kept */
+static ROTATE_INTERVAL: i64 = 15 * 60;
+/* seconds the previous id of a rotated session (or a used remember token) still works - requests already on their way with the old cookie */
+static ROTATE_GRACE: i64 = 30;
+/* seconds between writes of an unchanged session to MySQL (refreshes updated_at) */
+static TOUCH_INTERVAL: i64 = 60;
+/* seconds between deletions of expired rows from the sessions table */
+static CLEANUP_INTERVAL: i64 = 60 * 60;
+static REMEMBER_COOKIE: &str = "remember_token";
+const IDENTIFICATOR: &str = "sessions";
+
+#[derive(Debug, Clone)]
+struct SessionData {
+    user_id: Option<u64>,
+    values: HashMap<String, String>,
+    /* unix timestamps */
+    created_at: i64,
+    last_seen: i64,
+    rotated_at: i64,
+    /* last write to the sessions table */
+    stored_at: i64,
+    /* previous id of a rotated session => id of the session for ROTATE_GRACE seconds after rotated_at */
+    moved_to: Option<String>,
+}
+
+impl SessionData {
+    fn new(now: i64) -> SessionData {
+        SessionData { user_id: None, values: HashMap::new(), created_at: now, last_seen: now, rotated_at: now, stored_at: 0, moved_to: None }
+    }
+
+    fn expired(&self, cfg: &SessionConfig, now: i64) -> bool {
+        now - self.last_seen > cfg.idle_timeout
+            || now - self.created_at > cfg.lifetime
+            || (self.moved_to.is_some() && now - self.rotated_at > ROTATE_GRACE)
+    }
+}
+
+/*
+ * What happens to the "remember me" cookie after the request
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Remember {
+    Keep,
+    /* login with remember (None => expires in SESSION.REMEMBER_DAYS) or a used token (its expiry is kept) => new token for the user */
+    Issue(u64, Option<i64>),
+    /* cookie didn't match an unexpired token of an active user */
+    Clear,
+    /* logout => tokens of the user are removed */
+    Revoke(u64),
+}
+
+#[derive(Debug)]
+struct SessionState {
+    /* None => nothing stored yet, the session is created on its first change */
+    id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    /* new id after the request (login, ROTATE_INTERVAL) */
+    rotate: bool,
+    /* login => previous id stops working at once */
+    logged_in: bool,
+    destroyed: bool,
+    /* cookie named a session that is gone => cookie is removed */
+    stale_cookie: bool,
+    remember: Remember,
+}
+
+/*
+ * Session of one request - handlers use user_id/get/set/login/logout,
+ * the Sessions middleware loads it before the handler & stores it after
+ */
+#[derive(Debug)]
+pub struct Session {
+    state: Mutex<SessionState>,
+}
+
+impl Session {
+    fn lock(&self) -> MutexGuard<'_, SessionState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+/*
+ * session id => data, lives as long as the server
+ */
+fn store() -> MutexGuard<'static, HashMap<String, SessionData>> {
+    static STORE: OnceLock<Mutex<HashMap<String, SessionData>>> = OnceLock::new();
+    match STORE.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(store) => store,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+/* 256 random bits as hex - session ids & remember tokens */
+fn new_id() -> String {
+    rand::thread_rng().gen::<[u8; 32]>().iter().map(|byte: &u8| format!("{:02x}", byte)).collect()
+}
+
+/*
+ * MySQL only sees hashes of ids & remember tokens - a leaked table can't be used to log in
+ */
+fn hash(token: &str) -> String {
+    helpers::sha256_hex(token)
+}
+
+/*
+ * (current id, data) of the session - previous ids of rotated sessions lead to the current one
+ */
+fn find(id: &str, cfg: &SessionConfig, now: i64) -> Option<(String, SessionData)> {
+    {
+        let store: MutexGuard<HashMap<String, SessionData>> = store();
+        if let Some(data) = store.get(id) {
+            return match &data.moved_to {
+                None => Some((id.to_string(), data.clone())),
+                Some(new_id) if now - data.rotated_at <= ROTATE_GRACE => {
+                    store.get(new_id).map(|data: &SessionData| (new_id.clone(), data.clone()))
+                },
+                Some(_) => None
+            };
+        }
+    }
+    if !cfg.mysql {
+        return None;
+    }
+    match StoredSession::select_by_token(&hash(id)) {
+        /* user was deactivated since the session was stored */
+        Ok(Some(stored)) if stored.user_id.is_some_and(|user_id: u64| !is_active(user_id)) => {
+            remove(id, cfg);
+            None
+        },
+        Ok(Some(stored)) => Some((id.to_string(), SessionData {
+            user_id: stored.user_id,
+            values: serde_json::from_str(&stored.data).unwrap_or_default(),
+            created_at: stored.created_at,
+            last_seen: stored.updated_at,
+            rotated_at: stored.updated_at,
+            stored_at: stored.updated_at,
+            moved_to: None,
+        })),
+        Ok(None) => None,
+        Err(e) => {
+            println!("{}: Error loading session: {}", IDENTIFICATOR, e);
+            None
+        }
+    }
+}
+
+/*
+ * Logged in user still exists & is active - a database error doesn't end the session
+ */
+fn is_active(user_id: u64) -> bool {
+    match User::select_by_id(user_id) {
+        Ok(user) => user.is_some_and(|user: User| user.is_active()),
+        Err(e) => {
+            println!("{}: Error checking user {}: {}", IDENTIFICATOR, user_id, e);
+            true
+        }
+    }
+}
+
+/*
+ * Active user of a "remember me" cookie & when its token expires. The token is rotated on use -
+ * it keeps working for ROTATE_GRACE seconds and the client gets a new one, a copy replayed later is refused.
+ */
+fn remembered_user(token: &str, now: i64) -> mysql::Result<Option<(User, i64)>> {
+    let token_hash: String = hash(token);
+    let remembered: RememberToken = match RememberToken::select_by_token(&token_hash, now)? {
+        Some(remembered) => remembered,
+        None => return Ok(None)
+    };
+    let user: User = match User::select_by_id(remembered.user_id)? {
+        Some(user) if user.is_active() => user,
+        _ => return Ok(None)
+    };
+    RememberToken::expire(&token_hash, now + ROTATE_GRACE)?;
+    Ok(Some((user, remembered.expires_at)))
+}
+
+/*
+ * Previous id of a rotated session stays in memory for ROTATE_GRACE seconds
+ */
+fn retire(id: &str, new_id: &str, cfg: &SessionConfig, now: i64) {
+    if let Some(data) = store().get_mut(id) {
+        data.moved_to = Some(new_id.to_string());
+        data.rotated_at = now;
+    }
+    if cfg.mysql {
+        if let Err(e) = StoredSession::delete(&hash(id)) {
+            println!("{}: Error deleting session: {}", IDENTIFICATOR, e);
+        }
+    }
+}
+
+fn remove(id: &str, cfg: &SessionConfig) {
+    store().remove(id);
+    if cfg.mysql {
+        if let Err(e) = StoredSession::delete(&hash(id)) {
+            println!("{}: Error deleting session: {}", IDENTIFICATOR, e);
+        }
+    }
+}
+
+/*
+ * Writes the session to memory & - if it changed or TOUCH_INTERVAL passed - to MySQL
+ */
+fn persist(id: &str, data: &mut SessionData, cfg: &SessionConfig, changed: bool, now: i64) {
+    let write_mysql: bool = cfg.mysql && (changed || now - data.stored_at >= TOUCH_INTERVAL);
+    if write_mysql {
+        data.stored_at = now;
+    }
+    {
+        let mut store: MutexGuard<HashMap<String, SessionData>> = store();
+        if store.len() >= MAX_SESSIONS && !store.contains_key(id) {
+            store.retain(|_, data: &mut SessionData| !data.expired(cfg, now));
+            if store.len() >= MAX_SESSIONS {
+                let mut by_last_seen: Vec<(i64, String)> = store.iter().map(|(id, data)| (data.last_seen, id.clone())).collect();
+                by_last_seen.sort();
+                let evicted: usize = store.len() + 1 - MAX_SESSIONS;
+                for (_, id) in by_last_seen.into_iter().take(evicted) {
+                    store.remove(&id);
+                }
+            }
+        }
+        store.insert(id.to_string(), data.clone());
+    }
+    if !write_mysql {
+        return;
+    }
+    let stored: StoredSession = StoredSession {
+        user_id: data.user_id,
+        data: serde_json::to_string(&data.values).unwrap_or(String::from("{}")),
+        created_at: data.created_at,
+        updated_at: data.last_seen,
+    };
+    if let Err(e) = StoredSession::save(&hash(id), &stored) {
+        println!("{}: Error storing session: {}", IDENTIFICATOR, e);
+    }
+    static LAST_CLEANUP: AtomicI64 = AtomicI64::new(0);
+    let last_cleanup: i64 = LAST_CLEANUP.load(Ordering::Relaxed);
+    if now - last_cleanup >= CLEANUP_INTERVAL && LAST_CLEANUP.compare_exchange(last_cleanup, now, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+        match StoredSession::delete_expired(now - cfg.idle_timeout, now - cfg.lifetime) {
+            Ok(deleted) => println!("{}: {} expired sessions deleted", IDENTIFICATOR, deleted),
+            Err(e) => println!("{}: Error deleting expired sessions: {}", IDENTIFICATOR, e)
+        }
+    }
+}
+
+/*
+ * Cookies are never readable from scripts, Secure on TLS connections or with SESSION.SECURE=true
+ */
+fn cookie(name: &str, value: &str, cfg: &SessionConfig, secure: bool) -> Cookie {
+    Cookie::new(name, value).http_only(true).secure(secure).same_site(cfg.same_site)
+}
+
+/*
+ * Session named by the cookie if it exists & hasn't expired, otherwise an empty one -
+ * or, with a valid "remember me" cookie, a new session of its user
+ */
+pub fn load(request: &HttpRequest, cfg: &SessionConfig) -> Session {
+    let now: i64 = Utc::now().timestamp();
+    let mut state: SessionState = SessionState {
+        id: None,
+        data: SessionData::new(now),
+        changed: false,
+        rotate: false,
+        logged_in: false,
+        destroyed: false,
+        stale_cookie: false,
+        remember: Remember::Keep,
+    };
+    if let Some(cookie_id) = request.cookie(&cfg.cookie_name) {
+        match find(&cookie_id, cfg, now) {
+            Some((id, data)) if !data.expired(cfg, now) => {
+                let rotate: bool = now - data.rotated_at >= ROTATE_INTERVAL;
+                /* sessions in memory re-check their user when they're rotated, stored ones when they're loaded (find) */
+                if rotate && data.user_id.is_some_and(|user_id: u64| !is_active(user_id)) {
+                    remove(&id, cfg);
+                    state.stale_cookie = true;
+                } else {
+                    state.rotate = rotate;
+                    state.id = Some(id);
+                    state.data = data;
+                }
+            },
+            Some((id, _)) => {
+                remove(&id, cfg);
+                state.stale_cookie = true;
+            },
+            None => {
+                state.stale_cookie = true;
+            }
+        }
+    }
+    if state.id.is_none() {
+        if let Some(token) = request.cookie(REMEMBER_COOKIE) {
+            match remembered_user(&token, now) {
+                Ok(Some((user, expires_at))) => {
+                    println!("{}: user {} logged in by remember token", IDENTIFICATOR, user.id());
+                    state.data.user_id = Some(user.id());
+                    state.data.values.insert(String::from("username"), user.username().to_string());
+                    state.changed = true;
+                    state.remember = Remember::Issue(user.id(), Some(expires_at));
+                },
+                Ok(None) => {
+                    state.remember = Remember::Clear;
+                },
+                Err(e) => {
+                    println!("{}: Error checking remember token: {}", IDENTIFICATOR, e);
+                }
+            }
+        }
+    }
+    Session { state: Mutex::new(state) }
+}
+
+/*
+ * Stores the session after the handler & sets/removes the cookies:
+ *  -- destroyed (logout) => session deleted, cookie removed
+ *  -- new, rotated => stored under a new id, cookie set
+ *  -- unchanged => last seen time refreshed
+ * Nothing is stored for clients that never got a session.
+ */
+pub fn save(session: &Session, cfg: &SessionConfig, tls: bool, response: &mut HttpResponse) {
+    let now: i64 = Utc::now().timestamp();
+    let secure: bool = cfg.secure || tls;
+    let mut cookies: Vec<Cookie> = Vec::new();
+    let mut state: MutexGuard<SessionState> = session.lock();
+    match state.remember {
+        Remember::Keep => {},
+        Remember::Issue(user_id, expires_at) => {
+            let token: String = new_id();
+            let remembered: RememberToken = RememberToken {
+                user_id,
+                expires_at: expires_at.unwrap_or(now + cfg.remember_days * 24 * 60 * 60),
+            };
+            match RememberToken::insert(&hash(&token), &remembered, now) {
+                Ok(()) => cookies.push(cookie(REMEMBER_COOKIE, &token, cfg, secure).max_age(remembered.expires_at - now)),
+                Err(e) => println!("{}: Error storing remember token: {}", IDENTIFICATOR, e)
+            }
+        },
+        Remember::Clear => {
+            cookies.push(cookie(REMEMBER_COOKIE, "", cfg, secure).max_age(0));
+        },
+        Remember::Revoke(user_id) => {
+            if let Err(e) = RememberToken::delete_user(user_id) {
+                println!("{}: Error removing remember tokens: {}", IDENTIFICATOR, e);
+            }
+            cookies.push(cookie(REMEMBER_COOKIE, "", cfg, secure).max_age(0));
+        }
+    }
+    if state.destroyed {
+        if let Some(id) = state.id.take() {
+            remove(&id, cfg);
+        }
+        cookies.push(cookie(&cfg.cookie_name, "", cfg, secure).max_age(0));
+    } else if state.id.is_some() || state.changed {
+        let rotate: bool = state.rotate;
+        let (id, new_cookie): (String, bool) = match state.id.take() {
+            Some(id) if !rotate => (id, false),
+            old_id => {
+                let id: String = new_id();
+                match old_id {
+                    /* fixation: an id known before the login is never the id of the logged in session */
+                    Some(old_id) if state.logged_in => remove(&old_id, cfg),
+                    Some(old_id) => retire(&old_id, &id, cfg, now),
+                    None => {}
+                }
+                state.data.rotated_at = now;
+                (id, true)
+            }
+        };
+        state.data.last_seen = now;
+        let changed: bool = state.changed || new_cookie;
+        persist(&id, &mut state.data, cfg, changed, now);
+        if new_cookie {
+            cookies.push(cookie(&cfg.cookie_name, &id, cfg, secure));
+        }
+        state.id = Some(id);
+    } else if state.stale_cookie {
+        cookies.push(cookie(&cfg.cookie_name, "", cfg, secure).max_age(0));
+    }
+    if !cookies.is_empty() {
+        /* responses setting cookies must never be served to others from a shared cache */
+        response.set_header("cache-control", "no-store");
+        for cookie in cookies.iter() {
+            response.append_header("set-cookie", &cookie.to_header());
+        }
+    }
+}
+
+fn state(request: &HttpRequest) -> Option<MutexGuard<'_, SessionState>> {
+    request.session.as_ref().map(|session: &Session| session.lock())
+}
+
+/*
+ * Logged in user of the request's session
+ */
+pub fn user_id(request: &HttpRequest) -> Option<u64> {
+    state(request).and_then(|state: MutexGuard<SessionState>| state.data.user_id)
+}
+
+pub fn get(request: &HttpRequest, key: &str) -> Option<String> {
+    state(request).and_then(|state: MutexGuard<SessionState>| state.data.values.get(key).cloned())
+}
+
+/*
+ * Creates the session if the client has none yet
+ */
+pub fn set(request: &HttpRequest, key: &str, value: &str) -> Result<(), String> {
+    let mut state: MutexGuard<SessionState> = state(request).ok_or(String::from("Request has no session"))?;
+    state.data.values.insert(key.to_string(), value.to_string());
+    state.changed = true;
+    Ok(())
+}
+
+/*
+ * Logs the user in - the session gets a new id, remember => "remember me" cookie for SESSION.REMEMBER_DAYS
+ */
+pub fn login(request: &HttpRequest, user_id: u64, remember: bool) -> Result<(), String> {
+    let mut state: MutexGuard<SessionState> = state(request).ok_or(String::from("Request has no session"))?;
+    /* values of another user don't carry over */
+    if state.data.user_id.is_some_and(|id: u64| id != user_id) {
+        state.data.values.clear();
+    }
+    state.data.user_id = Some(user_id);
+    state.changed = true;
+    state.rotate = true;
+    state.logged_in = true;
+    state.destroyed = false;
+    if remember {
+        state.remember = Remember::Issue(user_id, None);
+    }
+    println!("{}: user {} logged in", IDENTIFICATOR, user_id);
+    Ok(())
+}
+
+/*
+ * Destroys the session - a logged in user also loses the "remember me" cookies on all devices
+ */
+pub fn logout(request: &HttpRequest) {
+    if let Some(mut state) = state(request) {
+        if let Some(user_id) = state.data.user_id {
+            state.remember = Remember::Revoke(user_id);
+            println!("{}: user {} logged out", IDENTIFICATOR, user_id);
+        }
+        state.destroyed = true;
+    }
+}
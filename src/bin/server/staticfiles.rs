@@ -0,0 +1,239 @@
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+use chrono::{DateTime, Utc};
+use crate::server::cstmconfig::VirtualHost;
+use crate::server::helpers;
+use crate::server::httpcompression;
+use crate::server::httprange::{self, RangeRequest};
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::{HttpResponse, ResponseBody};
+use crate::server::templates;
+use crate::server::virtualhost;
+
+/*
+ * Seconds a client may reuse a static file before revalidating with ETag/Last-Modified
+ */
+static CACHE_MAX_AGE: u64 = 3600;
+const IDENTIFICATOR: &str = "staticfiles";
+
+/*
+ * Same types as deployment/nginx-config/mime-types.conf (+ a few modern web formats)
+ */
+fn mime_type(extension: &str) -> &'static str {
+    match extension.to_lowercase().as_str() {
+        "html" | "htm" | "shtml" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "xml" | "rss" => "text/xml; charset=utf-8",
+        "gif" => "image/gif",
+        "jpeg" | "jpg" => "image/jpeg",
+        "js" => "application/x-javascript; charset=utf-8",
+        "txt" => "text/plain; charset=utf-8",
+        "htc" => "text/x-component",
+        "mml" => "text/mathml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        "jng" => "image/x-jng",
+        "wbmp" => "image/vnd.wap.wbmp",
+        "jar" | "war" | "ear" => "application/java-archive",
+        "hqx" => "application/mac-binhex40",
+        "pdf" => "application/pdf",
+        "cco" => "application/x-cocoa",
+        "jardiff" => "application/x-java-archive-diff",
+        "jnlp" => "application/x-java-jnlp-file",
+        "run" => "application/x-makeself",
+        "pl" | "pm" => "application/x-perl",
+        "prc" | "pdb" => "application/x-pilot",
+        "rar" => "application/x-rar-compressed",
+        "rpm" => "application/x-redhat-package-manager",
+        "sea" => "application/x-sea",
+        "swf" => "application/x-shockwave-flash",
+        "sit" => "application/x-stuffit",
+        "tcl" | "tk" => "application/x-tcl",
+        "der" | "pem" | "crt" => "application/x-x509-ca-cert",
+        "xpi" => "application/x-xpinstall",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "ra" => "audio/x-realaudio",
+        "mpeg" | "mpg" => "video/mpeg",
+        "mov" => "video/quicktime",
+        "flv" => "video/x-flv",
+        "avi" => "video/x-msvideo",
+        "wmv" => "video/x-ms-wmv",
+        "asx" | "asf" => "video/x-ms-asf",
+        "mng" => "video/x-mng",
+        "json" => "application/json",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp4" => "video/mp4",
+        "log" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/*
+ * Maps request path to a file under the document root (APP.HTML_BASE_PATH or the virtual host's):
+ *  -- path is percent-decoded and split into segments
+ *  -- "..", "." and hidden (dot) segments are refused
+ *  -- resolved file (after symlinks) must stay inside the base dir & outside of the views (templates::VIEWS_DIR)
+ *  -- directory => its index.html
+ * Returns None if there is no such file.
+ */
+fn resolve_path(base_path: &str, request_path: &str) -> Option<PathBuf> {
+    let decoded: String = helpers::percent_decode(request_path)?;
+    if decoded.contains('\0') || decoded.contains('\\') {
+        return None;
+    }
+    let base: PathBuf = match fs::canonicalize(base_path) {
+        Ok(base) => base,
+        Err(e) => {
+            println!("{}: Error resolving base path {}: {}", IDENTIFICATOR, base_path, e);
+            return None;
+        }
+    };
+    let mut candidate: PathBuf = base.clone();
+    for segment in decoded.split('/').filter(|s: &&str| !s.is_empty()) {
+        if segment.starts_with('.') {
+            return None;
+        }
+        candidate.push(segment);
+    }
+    if candidate.is_dir() {
+        candidate.push("index.html");
+    }
+    let resolved: PathBuf = fs::canonicalize(&candidate).ok()?;
+    if !resolved.starts_with(&base) || resolved.starts_with(base.join(templates::VIEWS_DIR)) || !resolved.is_file() {
+        return None;
+    }
+    Some(resolved)
+}
+
+/*
+ * Whether request path maps to a file that serve_file() would send
+ */
+pub fn file_exists(request: &HttpRequest) -> bool {
+    let site: VirtualHost = virtualhost::site(Some(request));
+    resolve_path(&site.html_base_path, request.path()).is_some()
+}
+
+/*
+ * Validator built from modification time and size: "{mtime hex}-{size hex}"
+ */
+fn fetch_etag(metadata: &Metadata) -> String {
+    let mtime: u64 = metadata.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", mtime, metadata.len())
+}
+
+/*
+ * If-None-Match takes precedence over If-Modified-Since
+ */
+fn is_not_modified(request: &HttpRequest, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+    if let Some(if_none_match) = request.header("if-none-match") {
+        return if_none_match.split(',').any(|tag: &str| {
+            let tag: &str = tag.trim().trim_start_matches("W/");
+            /* compressed copy of the file is the same version */
+            tag == "*" || tag == etag || httpcompression::ENCODINGS.iter()
+                .any(|encoding: &&str| tag == httpcompression::encoded_etag(etag, encoding))
+        });
+    }
+    match (request.header("if-modified-since").and_then(helpers::parse_http_date), last_modified) {
+        (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+        _ => false
+    }
+}
+
+/*
+ * Serves a file from the document root of the request's site for GET/HEAD requests that didn't match any route.
+ * Supports range requests (206/416) for resuming interrupted downloads.
+ * Returns None if no such file exists.
+ */
+pub fn serve_file(request: &HttpRequest) -> Option<HttpResponse> {
+    let site: VirtualHost = virtualhost::site(Some(request));
+    let file_path: PathBuf = resolve_path(&site.html_base_path, request.path())?;
+    let metadata: Metadata = match fs::metadata(&file_path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            println!("{}: Error reading metadata of {:?}: {}", IDENTIFICATOR, file_path, e);
+            return None;
+        }
+    };
+    let etag: String = fetch_etag(&metadata);
+    let last_modified: Option<DateTime<Utc>> = metadata.modified().ok().map(DateTime::<Utc>::from);
+    let extension: &str = file_path.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+    let content_type: &str = mime_type(extension);
+    let total: u64 = metadata.len();
+
+    let mut response: HttpResponse = if is_not_modified(request, &etag, last_modified) {
+        HttpResponse::new(304, Vec::new())
+    } else {
+        let range_request: RangeRequest = match request.header("range") {
+            Some(range) if httprange::if_range_matches(request, &etag, last_modified) => httprange::parse_range(range, total),
+            _ => RangeRequest::Full
+        };
+        match range_request {
+            RangeRequest::Full => {
+                let file: File = match File::open(&file_path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        println!("{}: Error opening {:?}: {}", IDENTIFICATOR, file_path, e);
+                        return None;
+                    }
+                };
+                HttpResponse {
+                    status: 200,
+                    headers: Vec::new(),
+                    /* content-length is sent first - a file that changed since gets cut at it or fails the connection */
+                    body: ResponseBody::SizedStream(total, Box::new(move |writer: &mut dyn Write| {
+                        let copied: u64 = io::copy(&mut file.take(total), writer)?;
+                        if copied != total {
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shrunk while sending it"));
+                        }
+                        Ok(())
+                    })),
+                }.with_header("content-type", content_type)
+            },
+            RangeRequest::Partial(ranges) => {
+                let content_range: Option<String> = match ranges.as_slice() {
+                    [range] => Some(range.content_range(total)),
+                    _ => None
+                };
+                let (partial_type, body): (String, ResponseBody) = match httprange::partial_body(file_path.clone(), ranges, total, content_type) {
+                    Ok(partial) => partial,
+                    Err(e) => {
+                        println!("{}: Error opening {:?}: {}", IDENTIFICATOR, file_path, e);
+                        return None;
+                    }
+                };
+                let mut partial: HttpResponse = HttpResponse { status: 206, headers: Vec::new(), body }
+                    .with_header("content-type", &partial_type);
+                if let Some(content_range) = content_range {
+                    partial.set_header("content-range", &content_range);
+                }
+                partial
+            },
+            RangeRequest::Unsatisfiable => {
+                HttpResponse::new(416, String::from("416 Range Not Satisfiable").into_bytes())
+                    .with_header("content-type", "text/plain; charset=utf-8")
+                    .with_header("content-range", &format!("bytes */{}", total))
+            }
+        }
+    };
+    if response.status == 304 {
+        response.set_header("content-type", content_type);
+    }
+    response.set_header("accept-ranges", "bytes");
+    response.set_header("etag", &etag);
+    response.set_header("cache-control", &format!("public, max-age={}", CACHE_MAX_AGE));
+    if let Some(modified) = last_modified {
+        response.set_header("last-modified", &helpers::http_date(modified));
+    }
+    println!("{}: Serving {:?} [{}]", IDENTIFICATOR, file_path, response.status);
+    Some(response)
+}
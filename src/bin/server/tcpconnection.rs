@@ -5,10 +5,14 @@ use super::cstmconfig::AssetsConfig;
 use chrono::format::{DelayedFormat, StrftimeItems};
 use chrono::Local;
 use crate::server::cstmfiles;
+use crate::server::eventstream;
+use crate::server::ratelimit::{self, Limit};
 use std::net::Shutdown;
 use std::fs::File;
 use std::io::{self, BufReader, Read, Write};
 use super::helpers;
+use crate::server::tlsstream::ServerStream;
+use serde_json::json;
 
 const IDENTIFICATOR: &str = "tcpconnection";
 
@@ -17,14 +21,16 @@ const IDENTIFICATOR: &str = "tcpconnection";
  * client -> server
  * 
  */
-pub fn loop_connection(stream: &TcpStream) -> Result<(), String> {
+pub fn loop_connection(stream: &ServerStream) -> Result<(), String> {
     let (ip, port): (IpAddr, u16) = match stream.peer_addr() {
         Ok(saddr) => (saddr.ip(), saddr.port()),
         Err(e) => return Err(format!("{}: Error fetching ip:port for client: {}", IDENTIFICATOR, e))
     };
     let assets_cfg: AssetsConfig = AssetsConfig::new_cfg();
     let fpath: String = String::from(assets_cfg.log_dir+"/"+&assets_cfg.log_path);
-    let mut reader: BufReader<&TcpStream> = BufReader::new(&stream);
+    let mut reader: BufReader<&ServerStream> = BufReader::new(stream);
+    /* notice is sent once per throttled burst, not for every dropped message */
+    let mut throttled: bool = false;
     loop {
         let mut buffer: [u8; 1024] = [0; 1024];
         match reader.read(&mut buffer) {
@@ -33,12 +39,27 @@ pub fn loop_connection(stream: &TcpStream) -> Result<(), String> {
                     println!("tcp-handler: Empty line");
                     break;
                 }
+                if let Err(retry_after) = ratelimit::check(Limit::TcpMessages, ip) {
+                    if ratelimit::tcp_disconnect() {
+                        println!("tcp-handler: {}:{} over the message limit - disconnecting", ip, port);
+                        send_notice(stream, &format!("Rate limit exceeded - disconnected, retry in {}s\n", retry_after));
+                        break;
+                    }
+                    if !throttled {
+                        println!("tcp-handler: {}:{} over the message limit - dropping messages", ip, port);
+                        send_notice(stream, &format!("Rate limit exceeded - messages are dropped, retry in {}s\n", retry_after));
+                        throttled = true;
+                    }
+                    continue;
+                }
+                throttled = false;
                 let recv: Cow<'_, str> = String::from_utf8_lossy(&buffer[..]);
                 let data: &str = recv.trim_matches(char::from(0));
                 let now: DelayedFormat<StrftimeItems> = Local::now().format("%Y-%m-%d %H:%M:%S");
                 let msg: String = format!("[{}] -- [{}:{}] -- [{} bytes]: {}\n", now.to_string(), ip, port, bytes, &data);
                 print!("{}", &msg);
                 cstmfiles::f_write(&fpath, msg).expect("Error writing file.");
+                eventstream::publish("message", json!({ "ip": ip.to_string(), "port": port, "bytes": bytes, "data": data }));
             },
             Err(e) => {
                 println!("tcp-handler: Error when reading line: {}", e);
@@ -49,12 +70,18 @@ pub fn loop_connection(stream: &TcpStream) -> Result<(), String> {
     Ok(())
 }
 
+fn send_notice(mut stream: &ServerStream, notice: &str) {
+    if let Err(e) = stream.write_all(notice.as_bytes()).and_then(|()| stream.flush()) {
+        println!("tcp-handler: Error sending notice: {}", e);
+    }
+}
+
 /*
  * 
  * server -> client
  * 
  */
-pub fn send_message(server_input: &String, mut socket: &TcpStream, logfile: &String) -> Result<u8, String> {
+pub fn send_message(server_input: &String, mut socket: &ServerStream, logfile: &String) -> Result<u8, String> {
     let (ip, port): (String, u16) = match socket.peer_addr() {
         Ok(saddr) => (saddr.ip().to_string(), saddr.port()),
         Err(e) => return Err(format!("{}: Error fetching ip:port for client: {}", IDENTIFICATOR, e))
@@ -82,7 +109,7 @@ pub fn send_message(server_input: &String, mut socket: &TcpStream, logfile: &Str
  * 
  * 
  */
-pub fn send_file(streams: &Vec<TcpStream>, file_path: &str, ip_input: &str, port_input: u16) -> Result<(), String>{
+pub fn send_file(streams: &Vec<ServerStream>, file_path: &str, ip_input: &str, port_input: u16) -> Result<(), String>{
     if ! Path::exists(Path::new(file_path)) {
         return Err(format!("{}: Error opening path: {}", IDENTIFICATOR, file_path));
     }
@@ -173,7 +200,7 @@ pub fn process_stdin() -> String {
     return server_input
 }
 
-pub fn print_connected(streams: &Vec<TcpStream>) -> Vec<usize> {
+pub fn print_connected(streams: &Vec<ServerStream>) -> Vec<usize> {
     println!("\nConnected streams:");
     let mut streams_to_rm: Vec<usize> = Vec::new();
     for (i, s) in streams.iter().enumerate() {
@@ -191,13 +218,13 @@ pub fn print_connected(streams: &Vec<TcpStream>) -> Vec<usize> {
     streams_to_rm
 }
 
-pub fn dc_all_nodes(streams: &Vec<TcpStream>) {
+pub fn dc_all_nodes(streams: &Vec<ServerStream>) {
     for (_, stream) in streams.iter().enumerate() {
         stream.shutdown(Shutdown::Both).unwrap();
     }
 }
 
-pub fn dc_node(streams: &Vec<TcpStream>, ip_input: &str, port_input: u16) -> Result<usize, String> {
+pub fn dc_node(streams: &Vec<ServerStream>, ip_input: &str, port_input: u16) -> Result<usize, String> {
     let mut idx: usize = 0;
     for (i, s) in streams.iter().enumerate() {
         let (ip, port): (String, u16) = match s.peer_addr() {
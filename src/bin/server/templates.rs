@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::SystemTime;
+use serde_json::{json, Value};
+use crate::server::helpers;
+
+/*
+ * Views in VIEWS_DIR of APP.HTML_BASE_PATH (or of the virtual host's document root) are templates:
+ *
+ * {{ user.username }}                                 => value, HTML-escaped
+ * {{ user.config|raw }}                               => value as-is
+ * {% if user.active %}..{% else %}..{% endif %}       => also "if not user.active"
+ * {% for user in rows %}..{% else %}..{% endfor %}    => else for an empty list; loop.index, loop.first, loop.last
+ * {% for column, value in user %}..{% endfor %}       => object entries (array => index, item)
+ * {% include "header.html" %}
+ * {% extends "layout.html" %} + {% block content %}..{% endblock %}
+ * {# comment #}
+ *
+ * Missing values render as empty string and are false in conditions.
+ * Parsed templates are cached until one of the files they are built from changes.
+ */
+static MAX_INCLUDE_DEPTH: usize = 16;
+/*
+ * Sub-directory of the document root holding the views - never served as static files
+ */
+pub static VIEWS_DIR: &str = "views/";
+const IDENTIFICATOR: &str = "templates";
+
+enum Token {
+    Text(String),
+    Expr(String),
+    Tag(String),
+}
+
+enum Node {
+    Text(String),
+    Var { path: Vec<String>, raw: bool },
+    If { negate: bool, path: Vec<String>, then: Vec<Node>, otherwise: Vec<Node> },
+    For { key: Option<String>, var: String, path: Vec<String>, body: Vec<Node>, empty: Vec<Node> },
+    Include(Arc<Template>),
+    Block(String, Vec<Node>),
+}
+
+pub struct Template {
+    nodes: Vec<Node>,
+    parent: Option<Arc<Template>>,
+    /* files the template is built from (incl. includes & layouts) with their mtime */
+    sources: Vec<(String, Option<SystemTime>)>,
+}
+
+impl Template {
+    fn is_fresh(&self) -> bool {
+        self.sources.iter().all(|(path, mtime): &(String, Option<SystemTime>)| modified(path) == *mtime)
+    }
+
+    /*
+     * Blocks of the most derived template win, the root layout provides the page
+     */
+    pub fn render(&self, context: &Value, writer: &mut dyn Write) -> io::Result<()> {
+        let mut blocks: HashMap<&str, &[Node]> = HashMap::new();
+        let mut template: &Template = self;
+        collect_blocks(&template.nodes, &mut blocks);
+        while let Some(parent) = &template.parent {
+            template = parent;
+            collect_blocks(&template.nodes, &mut blocks);
+        }
+        let mut renderer: Renderer = Renderer { root: context, blocks, scopes: Vec::new() };
+        renderer.render_nodes(&template.nodes, writer)
+    }
+
+    pub fn render_to_vec(&self, context: &Value) -> Vec<u8> {
+        let mut rendered: Vec<u8> = Vec::new();
+        /* writing to Vec can't fail */
+        let _ = self.render(context, &mut rendered);
+        rendered
+    }
+}
+
+fn collect_blocks<'a>(nodes: &'a [Node], blocks: &mut HashMap<&'a str, &'a [Node]>) {
+    for node in nodes {
+        if let Node::Block(name, body) = node {
+            blocks.entry(name.as_str()).or_insert(body.as_slice());
+            collect_blocks(body, blocks);
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata: fs::Metadata| metadata.modified()).ok()
+}
+
+fn cache() -> MutexGuard<'static, HashMap<String, Arc<Template>>> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Arc<Template>>>> = OnceLock::new();
+    match CACHE.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(cache) => cache,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+/*
+ * Template from the views of the document root - parsed once, reparsed when any of its files changes
+ */
+pub fn load(base_path: &str, name: &str) -> Result<Arc<Template>, String> {
+    let views_path: String = format!("{}{}", base_path, VIEWS_DIR);
+    let key: String = format!("{}{}", views_path, name);
+    if let Some(template) = cache().get(&key) {
+        if template.is_fresh() {
+            return Ok(template.clone());
+        }
+    }
+    let template: Arc<Template> = Arc::new(compile(&views_path, name, 0)?);
+    println!("{}: Loaded template {}", IDENTIFICATOR, key);
+    cache().insert(key, template.clone());
+    Ok(template)
+}
+
+fn compile(base_path: &str, name: &str, depth: usize) -> Result<Template, String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(format!("Template {}: includes nested deeper than {} (cycle?)", name, MAX_INCLUDE_DEPTH));
+    }
+    if name.is_empty() || name.starts_with('/') || name.split('/').any(|segment: &str| segment == "..") {
+        return Err(format!("Invalid template name {:?}", name));
+    }
+    let path: String = format!("{}{}", base_path, name);
+    let mtime: Option<SystemTime> = modified(&path);
+    let source: String = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => return Err(format!("Error opening template {}: {}", path, e))
+    };
+    let mut parser: Parser = Parser {
+        base_path,
+        name,
+        depth,
+        tokens: tokenize(name, &source)?.into_iter(),
+        parent: None,
+        sources: vec![(path, mtime)],
+    };
+    let (nodes, _): (Vec<Node>, String) = parser.parse_nodes(&[])?;
+    Ok(Template { nodes, parent: parser.parent, sources: parser.sources })
+}
+
+fn tokenize(name: &str, source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut text: String = String::new();
+    let mut rest: &str = source;
+    while let Some(start) = rest.match_indices('{')
+        .map(|(i, _)| i)
+        .find(|i: &usize| matches!(rest.as_bytes().get(i + 1), Some(b'{' | b'%' | b'#')))
+    {
+        let open: &str = &rest[start..start + 2];
+        let close: &str = match open { "{{" => "}}", "{%" => "%}", _ => "#}" };
+        text.push_str(&rest[..start]);
+        let inner: &str = &rest[start + 2..];
+        let end: usize = match inner.find(close) {
+            Some(end) => end,
+            None => return Err(format!("Template {}: unclosed {} near {:?}", name, open, inner.chars().take(30).collect::<String>()))
+        };
+        let content: String = inner[..end].trim().to_string();
+        if open != "{#" {
+            if !text.is_empty() {
+                tokens.push(Token::Text(std::mem::take(&mut text)));
+            }
+            tokens.push(if open == "{{" { Token::Expr(content) } else { Token::Tag(content) });
+        }
+        rest = &inner[end + 2..];
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+    Ok(tokens)
+}
+
+/*
+ * user.config => ["user", "config"]
+ */
+fn parse_path(name: &str, expr: &str) -> Result<Vec<String>, String> {
+    let path: Vec<String> = expr.trim().split('.').map(str::to_string).collect();
+    let valid: bool = path.iter().all(|segment: &String| {
+        !segment.is_empty() && segment.chars().all(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    });
+    if !valid {
+        return Err(format!("Template {}: invalid expression {:?}", name, expr));
+    }
+    Ok(path)
+}
+
+fn unquote(name: &str, arg: &str) -> Result<String, String> {
+    let arg: &str = arg.trim();
+    if arg.len() >= 2 && (arg.starts_with('"') && arg.ends_with('"') || arg.starts_with('\'') && arg.ends_with('\'')) {
+        return Ok(arg[1..arg.len() - 1].to_string());
+    }
+    Err(format!("Template {}: expected quoted template name, got {:?}", name, arg))
+}
+
+struct Parser<'a> {
+    base_path: &'a str,
+    name: &'a str,
+    depth: usize,
+    tokens: std::vec::IntoIter<Token>,
+    parent: Option<Arc<Template>>,
+    sources: Vec<(String, Option<SystemTime>)>,
+}
+
+impl Parser<'_> {
+    /*
+     * Parses until one of the end tags - returns the nodes and the end tag found
+     */
+    fn parse_nodes(&mut self, ends: &[&str]) -> Result<(Vec<Node>, String), String> {
+        let mut nodes: Vec<Node> = Vec::new();
+        while let Some(token) = self.tokens.next() {
+            let tag: String = match token {
+                Token::Text(text) => { nodes.push(Node::Text(text)); continue; },
+                Token::Expr(expr) => {
+                    let (expr, filter): (&str, Option<&str>) = match expr.split_once('|') {
+                        Some((expr, filter)) => (expr, Some(filter.trim())),
+                        None => (expr.as_str(), None)
+                    };
+                    if filter.is_some_and(|filter: &str| filter != "raw") {
+                        return Err(format!("Template {}: unknown filter {:?}", self.name, filter.unwrap_or("")));
+                    }
+                    nodes.push(Node::Var { path: parse_path(self.name, expr)?, raw: filter.is_some() });
+                    continue;
+                },
+                Token::Tag(tag) => tag
+            };
+            let (keyword, args): (&str, &str) = tag.split_once(char::is_whitespace).unwrap_or((tag.as_str(), ""));
+            if ends.contains(&keyword) {
+                return Ok((nodes, keyword.to_string()));
+            }
+            match keyword {
+                "if" => {
+                    let (negate, expr): (bool, &str) = match args.trim().strip_prefix("not ") {
+                        Some(expr) => (true, expr),
+                        None => (false, args)
+                    };
+                    let (then, end): (Vec<Node>, String) = self.parse_nodes(&["else", "endif"])?;
+                    let otherwise: Vec<Node> = if end == "else" { self.parse_nodes(&["endif"])?.0 } else { Vec::new() };
+                    nodes.push(Node::If { negate, path: parse_path(self.name, expr)?, then, otherwise });
+                },
+                "for" => {
+                    let (vars, expr): (&str, &str) = match args.split_once(" in ") {
+                        Some(for_in) => for_in,
+                        None => return Err(format!("Template {}: expected {{% for item in list %}}, got {:?}", self.name, tag))
+                    };
+                    let (key, var): (Option<String>, String) = match vars.split_once(',') {
+                        Some((key, var)) => (Some(key.trim().to_string()), var.trim().to_string()),
+                        None => (None, vars.trim().to_string())
+                    };
+                    let (body, end): (Vec<Node>, String) = self.parse_nodes(&["else", "endfor"])?;
+                    let empty: Vec<Node> = if end == "else" { self.parse_nodes(&["endfor"])?.0 } else { Vec::new() };
+                    nodes.push(Node::For { key, var, path: parse_path(self.name, expr)?, body, empty });
+                },
+                "block" => {
+                    let name: String = parse_path(self.name, args)?.join(".");
+                    let (body, _): (Vec<Node>, String) = self.parse_nodes(&["endblock"])?;
+                    nodes.push(Node::Block(name, body));
+                },
+                "include" | "extends" => {
+                    let template: Template = compile(self.base_path, &unquote(self.name, args)?, self.depth + 1)?;
+                    self.sources.extend(template.sources.iter().cloned());
+                    if keyword == "include" {
+                        nodes.push(Node::Include(Arc::new(template)));
+                    } else {
+                        self.parent = Some(Arc::new(template));
+                    }
+                },
+                _ => return Err(format!("Template {}: unexpected {{% {} %}}", self.name, tag))
+            }
+        }
+        match ends.last() {
+            Some(end) => Err(format!("Template {}: missing {{% {} %}}", self.name, end)),
+            None => Ok((nodes, String::new()))
+        }
+    }
+}
+
+struct Renderer<'a> {
+    root: &'a Value,
+    blocks: HashMap<&'a str, &'a [Node]>,
+    /* loop variables, innermost last */
+    scopes: Vec<(String, Value)>,
+}
+
+impl<'a> Renderer<'a> {
+    fn lookup(&self, path: &[String]) -> Option<&Value> {
+        let (first, rest): (&String, &[String]) = path.split_first()?;
+        let mut value: &Value = match self.scopes.iter().rev().find(|(name, _)| name == first) {
+            Some((_, value)) => value,
+            None => self.root.get(first)?
+        };
+        for segment in rest {
+            value = match value {
+                Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+                value => value.get(segment)?
+            };
+        }
+        Some(value)
+    }
+
+    fn render_nodes(&mut self, nodes: &'a [Node], writer: &mut dyn Write) -> io::Result<()> {
+        for node in nodes {
+            match node {
+                Node::Text(text) => writer.write_all(text.as_bytes())?,
+                Node::Var { path, raw } => {
+                    let value: String = match self.lookup(path) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(value)) => value.clone(),
+                        Some(value) => value.to_string()
+                    };
+                    if *raw {
+                        writer.write_all(value.as_bytes())?;
+                    } else {
+                        writer.write_all(helpers::html_escape(&value).as_bytes())?;
+                    }
+                },
+                Node::If { negate, path, then, otherwise } => {
+                    if is_truthy(self.lookup(path)) != *negate {
+                        self.render_nodes(then, writer)?;
+                    } else {
+                        self.render_nodes(otherwise, writer)?;
+                    }
+                },
+                Node::For { key, var, path, body, empty } => {
+                    let items: Vec<(Value, Value)> = match self.lookup(path) {
+                        Some(Value::Array(items)) => items.iter().enumerate().map(|(i, item)| (json!(i), item.clone())).collect(),
+                        Some(Value::Object(entries)) => entries.iter().map(|(k, v)| (json!(k), v.clone())).collect(),
+                        _ => Vec::new()
+                    };
+                    if items.is_empty() {
+                        self.render_nodes(empty, writer)?;
+                    }
+                    let len: usize = items.len();
+                    for (i, (k, item)) in items.into_iter().enumerate() {
+                        let scopes_len: usize = self.scopes.len();
+                        if let Some(key) = key {
+                            self.scopes.push((key.clone(), k));
+                        }
+                        self.scopes.push((var.clone(), item));
+                        self.scopes.push((String::from("loop"), json!({ "index": i + 1, "first": i == 0, "last": i + 1 == len })));
+                        self.render_nodes(body, writer)?;
+                        self.scopes.truncate(scopes_len);
+                    }
+                },
+                Node::Include(template) => self.render_nodes(&template.nodes, writer)?,
+                Node::Block(name, default) => {
+                    let nodes: &'a [Node] = self.blocks.get(name.as_str()).copied().unwrap_or(default);
+                    self.render_nodes(nodes, writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(value)) => *value,
+        Some(Value::Number(value)) => value.as_f64() != Some(0.0),
+        Some(Value::String(value)) => !value.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(entries)) => !entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * Fresh views directory in the temp dir with the given templates - returns its path as compile() expects it
+     */
+    fn views(test: &str, files: &[(&str, &str)]) -> String {
+        let base: String = format!("{}/rust-tcp-http-templates-{}-{}/", std::env::temp_dir().display(), std::process::id(), test);
+        let _ = fs::remove_dir_all(&base);
+        for (name, source) in files {
+            let path: String = format!("{}{}", base, name);
+            fs::create_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+            fs::write(&path, source).unwrap();
+        }
+        base
+    }
+
+    fn render(base: &str, name: &str, context: Value) -> String {
+        String::from_utf8(compile(base, name, 0).unwrap().render_to_vec(&context)).unwrap()
+    }
+
+    #[test]
+    fn renders_escaped_and_raw_values() {
+        let base: String = views("values", &[("page.html", "{# note #}<p>{{ user.name }}|{{ user.name|raw }}|{{ user.tags.1 }}|{{ missing.value }}|{{ count }}</p>")]);
+        let context: Value = json!({ "user": { "name": "<b>Tom & 'Jerry'</b>", "tags": ["a", "b"] }, "count": 3 });
+        assert_eq!(render(&base, "page.html", context), "<p>&lt;b&gt;Tom &amp; &#39;Jerry&#39;&lt;/b&gt;|<b>Tom & 'Jerry'</b>|b||3</p>");
+    }
+
+    #[test]
+    fn renders_conditions_and_loops() {
+        let base: String = views("control", &[(
+            "list.html",
+            "{% if not rows %}none{% else %}{% for row in rows %}{{ loop.index }}:{{ row.name }}{% if not loop.last %},{% endif %}{% endfor %}{% endif %}\
+             |{% for key, value in config %}{{ key }}={{ value }};{% endfor %}|{% for x in empty %}{% else %}empty{% endfor %}"
+        )]);
+        let context: Value = json!({ "rows": [{ "name": "a" }, { "name": "b" }], "config": { "k": "v" }, "empty": [] });
+        assert_eq!(render(&base, "list.html", context), "1:a,2:b|k=v;|empty");
+        assert!(render(&base, "list.html", json!({})).starts_with("none|"));
+    }
+
+    #[test]
+    fn includes_and_extends() {
+        let base: String = views("layout", &[
+            ("layout.html", "<title>{% block title %}default{% endblock %}</title>{% include \"partials/nav.html\" %}{% block content %}{% endblock %}"),
+            ("partials/nav.html", "<nav>{{ site }}</nav>"),
+            ("page.html", "{% extends \"layout.html\" %}{% block content %}<main>{{ site }}</main>{% endblock %}"),
+        ]);
+        assert_eq!(render(&base, "page.html", json!({ "site": "docs" })), "<title>default</title><nav>docs</nav><main>docs</main>");
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let base: String = views("invalid", &[
+            ("unclosed.html", "{{ user.name"),
+            ("endless.html", "{% if user %}x"),
+            ("filter.html", "{{ user|upper }}"),
+            ("expr.html", "{{ user name }}"),
+            ("cycle.html", "{% include \"cycle.html\" %}"),
+            ("escape.html", "{% include \"../secret.html\" %}"),
+        ]);
+        for name in ["unclosed.html", "endless.html", "filter.html", "expr.html", "cycle.html", "escape.html", "missing.html", "../x.html"] {
+            assert!(compile(&base, name, 0).is_err(), "{}", name);
+        }
+    }
+}
@@ -1,14 +1,14 @@
 use std::sync::{Arc,Mutex,mpsc};
-use std::net::TcpStream;
+use crate::server::tlsstream::ServerStream;
 
 pub struct ThrChannel {
-    pub tx: Arc<Mutex<mpsc::Sender<TcpStream>>>,
-    pub rx: Arc<Mutex<mpsc::Receiver<TcpStream>>>
+    pub tx: Arc<Mutex<mpsc::Sender<ServerStream>>>,
+    pub rx: Arc<Mutex<mpsc::Receiver<ServerStream>>>
 }
 
 impl ThrChannel {
     pub fn new_channel() -> ThrChannel {
-        let (tx, rx) : (mpsc::Sender<TcpStream>, mpsc::Receiver<TcpStream>) = mpsc::channel();
+        let (tx, rx) : (mpsc::Sender<ServerStream>, mpsc::Receiver<ServerStream>) = mpsc::channel();
         return ThrChannel {
             tx: Arc::new(Mutex::new(tx)),
             rx: Arc::new(Mutex::new(rx)),
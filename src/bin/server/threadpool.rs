@@ -1,10 +1,17 @@
-use std::net::{TcpStream, TcpListener, Shutdown, IpAddr};
+use std::net::{TcpListener, Shutdown, IpAddr};
+use openssl::ssl::SslAcceptor;
 use std::sync::{Mutex, Arc};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::thread::{self, JoinHandle};
-use crate::server::{httpconnection, tcpconnection};
+use crate::server::{eventstream, httpconnection, tcpconnection};
+use crate::server::ratelimit::{self, Limit};
 use crate::server::{thrstdin, validator};
 use crate::server::thrchannel::{self, ThrChannel};
+use crate::server::httpconnection::HttpConnectionEnd;
+use crate::server::tlsstream::ServerStream;
+use crate::server::websocket::WebSocketStream;
+use serde_json::json;
 
 /*
  * 1. The ThreadPool will create a channel and hold on to the sending side of the channel.
@@ -14,6 +21,14 @@ use crate::server::thrchannel::{self, ThrChannel};
  * 5. In its thread, the Worker will loop over its receiving side of the channel and execute the closures of any jobs it receives.
  */
 static THREAD_LIMIT : usize = 10;
+/*
+ * Workers long-lived streams (event streams, WebSocket & TCP hub clients) may hold at once -
+ * the rest stays free for HTTP requests
+ */
+static MAX_STREAMS: usize = THREAD_LIMIT / 2;
+/* jobs sent to the pool that haven't finished yet - running & queued */
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+static STREAMS: AtomicUsize = AtomicUsize::new(0);
 const IDENTIFICATOR: &str = "threadpool";
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
@@ -27,7 +42,7 @@ impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
         assert!(size > 0);
         let (tx,rx) = mpsc::channel();
-        let rx: Arc<Mutex<mpsc::Receiver<Box<dyn FnOnce() + Send>>>> = Arc::new(Mutex::new(rx));
+        let rx: Arc<Mutex<mpsc::Receiver<Job>>> = Arc::new(Mutex::new(rx));
         let mut workers: Vec<Worker> = Vec::with_capacity(size);
         for id in 0..size {
             workers.push(Worker::new(id, Arc::clone(&rx)));
@@ -36,10 +51,41 @@ impl ThreadPool {
     }
     pub fn execute<F>(&self, f: F) where F: FnOnce() + Send + 'static {
         let job: Box<F> = Box::new(f);
+        PENDING.fetch_add(1, Ordering::SeqCst);
         self.tx.send(job).unwrap();
     }
 }
 
+/*
+ * Every worker is taken or connections wait for one - idle keep-alive connections would only make them wait longer
+ */
+pub fn is_busy() -> bool {
+    PENDING.load(Ordering::SeqCst) >= THREAD_LIMIT
+}
+
+/*
+ * Worker reserved for a long-lived stream - released when dropped
+ */
+pub struct StreamSlot;
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn acquire_stream() -> Result<StreamSlot, String> {
+    match STREAMS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open: usize| if open < MAX_STREAMS { Some(open + 1) } else { None }) {
+        Ok(_) => Ok(StreamSlot),
+        Err(open) => Err(format!("{}: Too many open streams ({} of {} workers)", IDENTIFICATOR, open, THREAD_LIMIT))
+    }
+}
+
+/* answer 503 before upgrading instead of dropping the stream afterwards */
+pub fn streams_available() -> bool {
+    STREAMS.load(Ordering::SeqCst) < MAX_STREAMS
+}
+
 /*
  * Worker is responsible for taking jobs and exec them 
  */
@@ -75,12 +121,16 @@ impl Worker {
                         }
                     };
                     job();
+                    PENDING.fetch_sub(1, Ordering::SeqCst);
                 }).unwrap();
         Worker { id, thread: thr}
     }
 }
 
-pub fn handle_in_threadpool(listener: &TcpListener) -> Result<(), String> {
+/*
+ * Each listener gets its own accept loop, connections from all of them share the pool
+ */
+pub fn handle_in_threadpool(listeners: Vec<(TcpListener, Option<Arc<SslAcceptor>>)>) -> Result<(), String> {
     let pool: ThreadPool = ThreadPool::new(THREAD_LIMIT);
     println!("{}: Initializing thread channel.", IDENTIFICATOR);
     let thrstdin_thrmain_channel: ThrChannel = thrchannel::ThrChannel::new_channel();
@@ -89,12 +139,48 @@ pub fn handle_in_threadpool(listener: &TcpListener) -> Result<(), String> {
         Ok(()) => {},
         Err(e) => return Err(format!("Error: failed to initialize thread: {}", e))
     }
+    let thrstdin_thrmain_channel_tx: &Arc<Mutex<mpsc::Sender<ServerStream>>> = &thrstdin_thrmain_channel.tx;
+    let pool: &ThreadPool = &pool;
+    thread::scope(|scope| {
+        let accept_loops: Vec<thread::ScopedJoinHandle<Result<(), String>>> = listeners.into_iter()
+            .map(|(listener, acceptor): (TcpListener, Option<Arc<SslAcceptor>>)| scope.spawn(move || {
+                accept_connections(&listener, acceptor, pool, thrstdin_thrmain_channel_tx)
+            }))
+            .collect();
+        for accept_loop in accept_loops {
+            match accept_loop.join() {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Err(format!("{}: Listener thread panicked", IDENTIFICATOR))
+            }
+        }
+        Ok(())
+    })
+}
+
+fn accept_connections(
+    listener: &TcpListener,
+    acceptor: Option<Arc<SslAcceptor>>,
+    pool: &ThreadPool,
+    thrstdin_thrmain_channel_tx: &Arc<Mutex<mpsc::Sender<ServerStream>>>
+) -> Result<(), String> {
     for s in listener.incoming() {
         match s {
             Ok(stream) => {
-                let thrstdin_thrmain_channel_tx_clone: Arc<Mutex<mpsc::Sender<TcpStream>>> = thrstdin_thrmain_channel.tx.clone();
+                /* connection floods are dropped here so they never occupy a worker */
+                if let Ok(saddr) = stream.peer_addr() {
+                    if let Err(retry_after) = ratelimit::check(Limit::Connections, saddr.ip()) {
+                        println!("{}: {} over the connection limit - dropped (retry in {}s)", IDENTIFICATOR, saddr.ip(), retry_after);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                }
+                let thrstdin_thrmain_channel_tx_clone: Arc<Mutex<mpsc::Sender<ServerStream>>> = thrstdin_thrmain_channel_tx.clone();
+                let acceptor: Option<Arc<SslAcceptor>> = acceptor.clone();
                 pool.execute(move || {
-                    match handle_connection(stream, thrstdin_thrmain_channel_tx_clone) {
+                    /* TLS handshake runs on the worker so a slow client can't stall the listener */
+                    match ServerStream::accept(stream, acceptor.as_deref())
+                        .and_then(|stream: ServerStream| handle_connection(stream, thrstdin_thrmain_channel_tx_clone)) {
                         Ok(()) => {},
                         Err(e) => println!("{}: Error on connection handler: {}", IDENTIFICATOR, e)
                     }
@@ -106,19 +192,15 @@ pub fn handle_in_threadpool(listener: &TcpListener) -> Result<(), String> {
     Ok(())
 }
 
-fn handle_connection(stream: TcpStream, thrstdin_thrmain_channel_tx: Arc<Mutex<mpsc::Sender<TcpStream>>>) -> Result<(), String> {
+fn handle_connection(stream: ServerStream, thrstdin_thrmain_channel_tx: Arc<Mutex<mpsc::Sender<ServerStream>>>) -> Result<(), String> {
     let (ip, port): (IpAddr, u16) = match stream.peer_addr() {
         Ok(saddr) => (saddr.ip(), saddr.port()),
         Err(e) => return Err(format!("{}: Error fetching ip:port for client: {}", IDENTIFICATOR, e))
     };
-    let stream_clone: TcpStream = match stream.try_clone() {
-        Ok(tcp_stream_clone) => tcp_stream_clone,
-        Err(e) => return Err(format!("{}: Error clonning TcpStream: {}", IDENTIFICATOR, e))
-    };
     let mut buffer: [u8; 1024] = [0; 1024];
     let mut _data: String = String::new();
     
-    println!("\nReceived connection from {}:{}", ip, port);
+    println!("\nReceived {}connection from {}:{}", if stream.is_tls() { "TLS " } else { "" }, ip, port);
     /* peek() - wait until client sends first packet */
     match stream.peek(&mut buffer) {
         Ok(bytes) => {
@@ -131,35 +213,74 @@ fn handle_connection(stream: TcpStream, thrstdin_thrmain_channel_tx: Arc<Mutex<m
         Ok(_http_request) => {
             /* HTTP request - do not send new connection to thread-stdin */
             println!(">>> {}: Handling HTTP response {}:{}\n>>>\n", IDENTIFICATOR, &ip, &port);
-            match httpconnection::write_http_response(&stream, &_data) {
-                Ok(()) => {}, 
-                Err(e) => println!("{}: Error sending html response to {}:{}: {}", IDENTIFICATOR, ip, port, e)
-            }
+            let end: HttpConnectionEnd = match httpconnection::handle_http_connection(&stream) {
+                Ok(end) => end,
+                Err(e) => {
+                    println!("{}: Error sending html response to {}:{}: {}", IDENTIFICATOR, ip, port, e);
+                    HttpConnectionEnd::Closed
+                }
+            };
             println!("\n<<<");
-            /* disconnect HTTP connection after serving content */
-            match stream.shutdown(Shutdown::Both) {
-                Ok(()) => println!("<<< HTTP connection [{}:{}] closed.", ip, port),
-                Err(e) => println!("shutdown() call failed on HTTP connection: {}", e)
+            match end {
+                HttpConnectionEnd::Closed => {
+                    /* disconnect HTTP connection after serving content */
+                    match stream.shutdown(Shutdown::Both) {
+                        Ok(()) => println!("<<< HTTP connection [{}:{}] closed.", ip, port),
+                        Err(e) => println!("shutdown() call failed on HTTP connection: {}", e)
+                    }
+                },
+                HttpConnectionEnd::Upgraded(buffered) => {
+                    /* WebSocket - the browser joins thread-stdin like any TCP client */
+                    println!(">>> {}: Handling WebSocket connection {}:{}>>>\n", IDENTIFICATOR, &ip, &port);
+                    let ws: WebSocketStream = match WebSocketStream::new(stream, buffered) {
+                        Ok(ws) => ws,
+                        Err(e) => return Err(format!("{}: Error creating WebSocket session: {}", IDENTIFICATOR, e))
+                    };
+                    join_message_hub(ServerStream::WebSocket(ws), thrstdin_thrmain_channel_tx, ip, port)?;
+                }
             }
         },
         _ => {
             /* default TCP request - send new connection to thread-stdin */
             println!(">>> {}: Handling TCP connection {}:{}>>>\n", IDENTIFICATOR, &ip, &port);
-            match thrstdin_thrmain_channel_tx.lock().unwrap().send(stream) {
-                Ok(()) => println!(">>> threadpool-threadchannel_tx: Transmitter sent new stream to thrstdin"), 
-                Err(e) => println!("threadpool-threadchannel_tx: Error sending new stream to thrstdin on listener: {}", e)
-            }
-            match tcpconnection::loop_connection(&stream_clone) {
-                Ok(()) => {},
-                Err(e) => println!("{}: Error handling tcp connection from {}:{}: {}", IDENTIFICATOR, ip, port, e)
-            }
-            /* attempt to disconnect TCP connection after loop exits */
-            match stream_clone.shutdown(Shutdown::Both) {
-                Ok(()) => println!("<<< TCP connection [{}:{}] closed.", ip, port),
-                Err(e) => println!("shutdown() call failed on TCP connection: {}", e)
-            }
+            join_message_hub(stream, thrstdin_thrmain_channel_tx, ip, port)?;
         }
     }
 
     Ok(())
 }
+
+/*
+ * Sends the stream to thread-stdin and reads from its clone until the client leaves
+ */
+fn join_message_hub(stream: ServerStream, thrstdin_thrmain_channel_tx: Arc<Mutex<mpsc::Sender<ServerStream>>>, ip: IpAddr, port: u16) -> Result<(), String> {
+    let _slot: StreamSlot = match acquire_stream() {
+        Ok(slot) => slot,
+        Err(e) => {
+            /* WebSocket => close frame (1001), TCP => plain disconnect */
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+    };
+    let stream_clone: ServerStream = match stream.try_clone() {
+        Ok(tcp_stream_clone) => tcp_stream_clone,
+        Err(e) => return Err(format!("{}: Error clonning stream: {}", IDENTIFICATOR, e))
+    };
+    let client: &str = if matches!(stream, ServerStream::WebSocket(_)) { "websocket" } else { "tcp" };
+    eventstream::publish("connect", json!({ "ip": ip.to_string(), "port": port, "client": client }));
+    match thrstdin_thrmain_channel_tx.lock().unwrap().send(stream) {
+        Ok(()) => println!(">>> threadpool-threadchannel_tx: Transmitter sent new stream to thrstdin"), 
+        Err(e) => println!("threadpool-threadchannel_tx: Error sending new stream to thrstdin on listener: {}", e)
+    }
+    match tcpconnection::loop_connection(&stream_clone) {
+        Ok(()) => {},
+        Err(e) => println!("{}: Error handling tcp connection from {}:{}: {}", IDENTIFICATOR, ip, port, e)
+    }
+    /* attempt to disconnect TCP connection after loop exits */
+    match stream_clone.shutdown(Shutdown::Both) {
+        Ok(()) => println!("<<< TCP connection [{}:{}] closed.", ip, port),
+        Err(e) => println!("shutdown() call failed on TCP connection: {}", e)
+    }
+    eventstream::publish("disconnect", json!({ "ip": ip.to_string(), "port": port, "client": client }));
+    Ok(())
+}
@@ -1,14 +1,16 @@
-use std::net::TcpStream;
 use std::thread;
 use crate::server::cstmconfig;
+use crate::server::eventstream;
 use crate::server::tcpconnection;
 use std::sync::{Mutex, Arc, mpsc};
 use std::sync::mpsc::TryRecvError;
 use super::cstmconfig::AssetsConfig;
+use crate::server::tlsstream::ServerStream;
+use serde_json::json;
 
 const IDENTIFICATOR: &str = "thrstdin";
 
-pub fn init_thread(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<TcpStream>>>) -> Result<(), String> {
+pub fn init_thread(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<ServerStream>>>) -> Result<(), String> {
     match loop_user_stdin(thrstdin_thrmain_channel_rx) {
         Ok(()) => Ok(()),
         Err(e) => {
@@ -24,13 +26,13 @@ pub fn init_thread(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<TcpStre
  *      -- send closed connections
  *      -- synchronize with input thread
  */
-pub fn loop_user_stdin(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<TcpStream>>>) -> Result<(), String> {
+pub fn loop_user_stdin(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<ServerStream>>>) -> Result<(), String> {
     /*
      * Using scopes guarantees to terminate before the scope exits,
      * allowing it to reference variables outside the scope.
      *    -- move -> give ownership to a thread
      */
-    let mut streams: Vec<TcpStream> = Vec::new();
+    let mut streams: Vec<ServerStream> = Vec::new();
     let assets_cfg: AssetsConfig = cstmconfig::AssetsConfig::new_cfg();
     let fpath: String = String::from(assets_cfg.log_dir+"/"+&assets_cfg.log_path);
     thread::Builder::new()
@@ -112,7 +114,7 @@ pub fn loop_user_stdin(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<Tcp
             match tcpconnection::connect_client(ip, port_u16) {
                 Ok(stream) => {
                     println!("Connected to node: {:?}", stream);
-                    streams.push(stream);
+                    streams.push(ServerStream::Plain(stream));
                 },
                 Err(e) => {
                     println!("Error connecting to client: {}", e);
@@ -162,6 +164,7 @@ pub fn loop_user_stdin(thrstdin_thrmain_channel_rx: Arc<Mutex<mpsc::Receiver<Tcp
             if i == 0 {
                 println!("thrstdin: No connected clients.");
             }
+            eventstream::publish("broadcast", json!({ "message": server_input, "clients": streams.len() }));
         }
     }).unwrap();
     Ok(())
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslStream};
+use crate::server::websocket::WebSocketStream;
+
+/*
+ * Seconds a client has to complete the TLS handshake
+ */
+static HANDSHAKE_TIMEOUT: u64 = 10;
+const IDENTIFICATOR: &str = "tlsstream";
+
+/*
+ * Acceptor for the TLS ports - built once on startup from SERVER.TLS_CERT & SERVER.TLS_KEY (PEM).
+ * Mozilla "intermediate" profile => TLS 1.2 & 1.3.
+ */
+pub fn build_acceptor(cert_path: &str, key_path: &str) -> Result<SslAcceptor, String> {
+    let mut builder = match SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()) {
+        Ok(builder) => builder,
+        Err(e) => return Err(format!("{}: Error creating TLS acceptor: {}", IDENTIFICATOR, e))
+    };
+    if let Err(e) = builder.set_certificate_chain_file(cert_path) {
+        return Err(format!("{}: Error loading certificate {}: {}", IDENTIFICATOR, cert_path, e));
+    }
+    if let Err(e) = builder.set_private_key_file(key_path, SslFiletype::PEM) {
+        return Err(format!("{}: Error loading private key {}: {}", IDENTIFICATOR, key_path, e));
+    }
+    if let Err(e) = builder.check_private_key() {
+        return Err(format!("{}: Private key {} doesn't match certificate {}: {}", IDENTIFICATOR, key_path, cert_path, e));
+    }
+    Ok(builder.build())
+}
+
+/*
+ * Accepted connection - plain or TLS. Both HTTP & TCP handlers and thread-stdin
+ * work with this type, so a TLS client gets the same treatment as a plain one.
+ * HTTP connection upgraded to WebSocket becomes a TCP client exchanging frames.
+ */
+#[derive(Debug)]
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+    WebSocket(WebSocketStream),
+}
+
+/*
+ * TLS session shared between the connection handler (reads) and thread-stdin (writes).
+ * Reader waits for data on the raw socket without holding the lock,
+ * so writes from other threads aren't blocked by an idle client.
+ */
+#[derive(Clone)]
+pub struct TlsStream {
+    raw: Arc<TcpStream>,
+    ssl: Arc<Mutex<SslStream<TcpStream>>>,
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsStream({:?})", self.raw)
+    }
+}
+
+impl TlsStream {
+    fn lock(&self) -> MutexGuard<'_, SslStream<TcpStream>> {
+        match self.ssl.lock() {
+            Ok(ssl) => ssl,
+            Err(poisoned) => poisoned.into_inner()
+        }
+    }
+}
+
+impl ServerStream {
+    /*
+     * TLS handshake on the accepting worker (not the listener thread)
+     */
+    pub fn accept(stream: TcpStream, acceptor: Option<&SslAcceptor>) -> Result<ServerStream, String> {
+        let acceptor: &SslAcceptor = match acceptor {
+            Some(acceptor) => acceptor,
+            None => return Ok(ServerStream::Plain(stream))
+        };
+        let raw: TcpStream = match stream.try_clone() {
+            Ok(raw) => raw,
+            Err(e) => return Err(format!("{}: Error clonning TcpStream: {}", IDENTIFICATOR, e))
+        };
+        if let Err(e) = raw.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT))) {
+            return Err(format!("{}: Error setting handshake timeout: {}", IDENTIFICATOR, e));
+        }
+        let ssl: SslStream<TcpStream> = match acceptor.accept(stream) {
+            Ok(ssl) => ssl,
+            Err(e) => {
+                let _ = raw.shutdown(Shutdown::Both);
+                return Err(format!("{}: TLS handshake failed: {}", IDENTIFICATOR, e));
+            }
+        };
+        if let Err(e) = raw.set_read_timeout(None) {
+            return Err(format!("{}: Error resetting read timeout: {}", IDENTIFICATOR, e));
+        }
+        println!("{}: TLS session established: {} {}",
+            IDENTIFICATOR, ssl.ssl().version_str(), ssl.ssl().current_cipher().map(|c| c.name()).unwrap_or("-"));
+        Ok(ServerStream::Tls(TlsStream { raw: Arc::new(raw), ssl: Arc::new(Mutex::new(ssl)) }))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, ServerStream::Tls(_))
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            ServerStream::Plain(stream) => stream.peer_addr(),
+            ServerStream::Tls(tls) => tls.raw.peer_addr(),
+            ServerStream::WebSocket(ws) => ws.peer_addr()
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            ServerStream::Plain(stream) => stream.set_read_timeout(timeout),
+            ServerStream::Tls(tls) => tls.raw.set_read_timeout(timeout),
+            ServerStream::WebSocket(ws) => ws.set_read_timeout(timeout)
+        }
+    }
+
+    /*
+     * Waits for the first (decrypted) bytes without consuming them
+     */
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ServerStream::Plain(stream) => stream.peek(buf),
+            ServerStream::Tls(tls) => tls.lock().ssl_peek(buf).map_err(|e| match e.into_io_error() {
+                Ok(e) => e,
+                Err(e) => io::Error::other(e)
+            }),
+            ServerStream::WebSocket(ws) => ws.peek(buf)
+        }
+    }
+
+    /*
+     * TLS => close_notify is sent before the socket is shut down
+     */
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ServerStream::Plain(stream) => stream.shutdown(how),
+            ServerStream::Tls(tls) => {
+                let _ = tls.lock().shutdown();
+                tls.raw.shutdown(how)
+            },
+            ServerStream::WebSocket(ws) => ws.shutdown(how)
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<ServerStream> {
+        match self {
+            ServerStream::Plain(stream) => Ok(ServerStream::Plain(stream.try_clone()?)),
+            ServerStream::Tls(tls) => Ok(ServerStream::Tls(tls.clone())),
+            ServerStream::WebSocket(ws) => Ok(ServerStream::WebSocket(ws.clone()))
+        }
+    }
+}
+
+impl Read for &ServerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ServerStream::Plain(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.read(buf)
+            },
+            ServerStream::Tls(tls) => {
+                if tls.lock().ssl().pending() == 0 {
+                    /* block on the raw socket (honours the read timeout), not on the lock */
+                    let mut byte: [u8; 1] = [0; 1];
+                    tls.raw.peek(&mut byte)?;
+                }
+                tls.lock().read(buf)
+            },
+            ServerStream::WebSocket(ws) => {
+                let mut ws: &WebSocketStream = ws;
+                ws.read(buf)
+            }
+        }
+    }
+}
+
+impl Write for &ServerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ServerStream::Plain(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.write(buf)
+            },
+            ServerStream::Tls(tls) => tls.lock().write(buf),
+            ServerStream::WebSocket(ws) => {
+                let mut ws: &WebSocketStream = ws;
+                ws.write(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ServerStream::Plain(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.flush()
+            },
+            ServerStream::Tls(tls) => tls.lock().flush(),
+            ServerStream::WebSocket(ws) => {
+                let mut ws: &WebSocketStream = ws;
+                ws.flush()
+            }
+        }
+    }
+}
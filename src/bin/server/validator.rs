@@ -1,22 +1,14 @@
-use crate::server::cstmconfig::ServerConfig;
+use crate::server::cstmconfig::{self, ServerConfig};
 
 pub fn validate_request_method(request_method: &str) -> Result<(), String> {
-    let server_config: ServerConfig = ServerConfig::new_cfg();
-    for method in server_config.request_methods {
+    let server_config: &ServerConfig = cstmconfig::server();
+    for method in server_config.request_methods.iter() {
         if request_method == method {
             return Ok(());
         }
     }
     Err(String::from("http-response: Invalid request method."))
 }
-pub fn validate_route<'a>(route: &'a str, routes: &Vec<&str>) -> Result<(), String> {
-    for (_, r) in routes.iter().enumerate() {
-        if <String as From<&str>>::from(r) == String::from(route) {
-            return Ok(());
-        }
-    }
-    Err(String::from("http-response: Invalid route path."))
-}
 pub fn validate_http_request(buffer: &str) -> Result<Vec<&str>, String> {
     match buffer.split_once("\r\n") {
         Some(httprequest) => {
@@ -0,0 +1,76 @@
+use crate::server::cstmconfig::{self, AssetsConfig, VirtualHost, VirtualHostConfig};
+use crate::server::httprequest::HttpRequest;
+
+const IDENTIFICATOR: &str = "virtualhost";
+
+/*
+ * "Docs.Internal:31500" => "docs.internal", "[::1]:31500" => "[::1]"
+ */
+fn host_name(host: &str) -> String {
+    let host: &str = host.trim();
+    let host: &str = match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host
+    };
+    host.trim_end_matches('.').to_lowercase()
+}
+
+fn matches_name(server_name: &str, host: &str) -> bool {
+    match server_name.strip_prefix("*.") {
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => server_name == host
+    }
+}
+
+/*
+ * Exact names win over wildcards, no match => VHOST.DEFAULT (None => APP.* site)
+ */
+pub fn select(cfg: &VirtualHostConfig, host: Option<&str>) -> Option<VirtualHost> {
+    let host: String = host.map(host_name).unwrap_or_default();
+    let exact: Option<&VirtualHost> = cfg.hosts.iter()
+        .find(|vhost: &&VirtualHost| vhost.server_names.contains(&host));
+    let wildcard = || cfg.hosts.iter()
+        .find(|vhost: &&VirtualHost| vhost.server_names.iter().any(|name: &String| matches_name(name, &host)));
+    let default = || cfg.default.as_ref().and_then(|default: &String| {
+        let vhost: Option<&VirtualHost> = cfg.hosts.iter().find(|vhost: &&VirtualHost| vhost.name.eq_ignore_ascii_case(default));
+        if vhost.is_none() {
+            println!("{}: VHOST.DEFAULT {} is not one of VHOST.HOSTS", IDENTIFICATOR, default);
+        }
+        vhost
+    });
+    exact.or_else(wildcard).or_else(default).cloned()
+}
+
+/*
+ * Site from APP.HTML_BASE_PATH & APP.ERROR_PAGE with all routes
+ */
+fn default_site() -> VirtualHost {
+    let assets_cfg: &AssetsConfig = cstmconfig::assets();
+    VirtualHost {
+        name: String::from("default"),
+        server_names: Vec::new(),
+        html_base_path: assets_cfg.html_base_path.clone(),
+        error_page: assets_cfg.error_page.clone(),
+        routes: vec![String::from("/")],
+    }
+}
+
+/*
+ * Site answering the request - request is None if it couldn't be read
+ */
+pub fn site(request: Option<&HttpRequest>) -> VirtualHost {
+    match request.and_then(|request: &HttpRequest| request.vhost.clone()) {
+        Some(vhost) => vhost,
+        None => default_site()
+    }
+}
+
+/*
+ * Whether built-in routes are served for the path on the site
+ */
+pub fn serves_route(site: &VirtualHost, path: &str) -> bool {
+    site.routes.iter().any(|prefix: &String| {
+        let prefix: &str = prefix.trim_end_matches('/');
+        prefix.is_empty() || path == prefix || path.starts_with(&format!("{}/", prefix))
+    })
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use openssl::base64;
+use openssl::sha;
+use crate::server::httperror::HttpError;
+use crate::server::httprequest::HttpRequest;
+use crate::server::httpresponse::HttpResponse;
+use crate::server::tlsstream::ServerStream;
+
+/*
+ * Upper bound for the payload of a single frame sent by the browser (=> close 1009)
+ */
+static MAX_FRAME_SIZE: u64 = 1024 * 1024;
+static WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const IDENTIFICATOR: &str = "websocket";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+/*
+ * sec-websocket-accept = base64(sha1(sec-websocket-key + GUID))
+ */
+fn accept_key(key: &str) -> String {
+    base64::encode_block(&sha::sha1(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()))
+}
+
+/*
+ * Validates the opening handshake (RFC 6455 4.2.1) and answers it with 101 Switching Protocols.
+ * After the 101 is written the connection is handed over to the TCP message hub.
+ */
+pub fn handshake(request: &HttpRequest) -> Result<HttpResponse, HttpError> {
+    let upgrade: bool = request.header("upgrade")
+        .is_some_and(|upgrade: &str| upgrade.trim().eq_ignore_ascii_case("websocket"));
+    let connection_upgrade: bool = request.header("connection")
+        .is_some_and(|connection: &str| connection.split(',').any(|token: &str| token.trim().eq_ignore_ascii_case("upgrade")));
+    if request.method != "GET" || !upgrade || !connection_upgrade {
+        return Err(HttpError::UpgradeRequired(String::from("This endpoint only accepts WebSocket connections")));
+    }
+    if request.version != "HTTP/1.1" {
+        return Err(HttpError::BadRequest(String::from("WebSocket handshake requires HTTP/1.1")));
+    }
+    if request.header("sec-websocket-version").map(str::trim) != Some("13") {
+        return Err(HttpError::UpgradeRequired(String::from("Unsupported WebSocket version")));
+    }
+    let key: &str = match request.header("sec-websocket-key").map(str::trim) {
+        Some(key) if base64::decode_block(key).is_ok_and(|decoded: Vec<u8>| decoded.len() == 16) => key,
+        _ => return Err(HttpError::BadRequest(String::from("Missing or invalid sec-websocket-key")))
+    };
+    Ok(HttpResponse::new(101, Vec::new())
+        .with_header("upgrade", "websocket")
+        .with_header("connection", "Upgrade")
+        .with_header("sec-websocket-accept", &accept_key(key)))
+}
+
+/*
+ * WebSocket session on top of a plain/TLS stream, used like a raw TCP client:
+ *  -- read() returns payload of text/binary frames, pings are answered, close => Ok(0)
+ *  -- write() sends the data as a single text frame
+ * Clones share the session - the hub writes from thread-stdin while the worker reads.
+ */
+#[derive(Clone)]
+pub struct WebSocketStream {
+    inner: Arc<ServerStream>,
+    reader: Arc<Mutex<FrameReader>>,
+    /* true once a close frame was sent - nothing may follow it */
+    closed: Arc<Mutex<bool>>,
+}
+
+struct FrameReader {
+    /* bytes the client sent right after the handshake, read before the socket */
+    buffered: Vec<u8>,
+    payload: Vec<u8>,
+    pos: usize,
+}
+
+impl fmt::Debug for WebSocketStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WebSocketStream({:?})", self.inner)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner()
+    }
+}
+
+impl WebSocketStream {
+    /*
+     * buffered => bytes read past the handshake request
+     */
+    pub fn new(inner: ServerStream, buffered: Vec<u8>) -> io::Result<WebSocketStream> {
+        /* hub clients stay connected until they leave - no idle timeout */
+        inner.set_read_timeout(None)?;
+        Ok(WebSocketStream {
+            inner: Arc::new(inner),
+            reader: Arc::new(Mutex::new(FrameReader { buffered, payload: Vec::new(), pos: 0 })),
+            closed: Arc::new(Mutex::new(false)),
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.peek(buf)
+    }
+
+    /*
+     * Close frame (1001 going away) is sent unless the session is already closing
+     */
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let _ = self.send_close(CLOSE_GOING_AWAY);
+        self.inner.shutdown(how)
+    }
+
+    /*
+     * Server frames are never masked: FIN + opcode, 7/16/64-bit length, payload
+     */
+    fn send_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut closed: MutexGuard<bool> = lock(&self.closed);
+        if *closed {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "WebSocket session is closed"));
+        }
+        let mut frame: Vec<u8> = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let mut inner: &ServerStream = &self.inner;
+        inner.write_all(&frame)?;
+        inner.flush()?;
+        *closed = opcode == OP_CLOSE;
+        Ok(())
+    }
+
+    fn send_close(&self, code: u16) -> io::Result<()> {
+        self.send_frame(OP_CLOSE, &code.to_be_bytes())
+    }
+
+    fn read_raw(&self, reader: &mut FrameReader, buf: &mut [u8]) -> io::Result<()> {
+        let from_buffer: usize = reader.buffered.len().min(buf.len());
+        buf[..from_buffer].copy_from_slice(&reader.buffered[..from_buffer]);
+        reader.buffered.drain(..from_buffer);
+        let mut inner: &ServerStream = &self.inner;
+        inner.read_exact(&mut buf[from_buffer..])
+    }
+
+    fn protocol_error(&self, code: u16, message: &str) -> io::Error {
+        println!("{}: Closing session with {}: {}", IDENTIFICATOR, code, message);
+        let _ = self.send_close(code);
+        io::Error::new(ErrorKind::InvalidData, message.to_string())
+    }
+
+    /*
+     * Reads frames until one with data arrives - None => session closed
+     * Client frames: FIN/RSV/opcode, MASK + 7/16/64-bit length, 4-byte mask, masked payload
+     */
+    fn read_frame(&self, reader: &mut FrameReader) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let mut header: [u8; 2] = [0; 2];
+            match self.read_raw(reader, &mut header) {
+                Ok(()) => {},
+                /* client dropped the connection without a close frame */
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e)
+            }
+            let (fin, rsv, opcode): (bool, u8, u8) = (header[0] & 0x80 != 0, header[0] & 0x70, header[0] & 0x0F);
+            let masked: bool = header[1] & 0x80 != 0;
+            let len: u64 = match header[1] & 0x7F {
+                126 => {
+                    let mut len: [u8; 2] = [0; 2];
+                    self.read_raw(reader, &mut len)?;
+                    u16::from_be_bytes(len) as u64
+                },
+                127 => {
+                    let mut len: [u8; 8] = [0; 8];
+                    self.read_raw(reader, &mut len)?;
+                    u64::from_be_bytes(len)
+                },
+                len => len as u64
+            };
+            if rsv != 0 || !masked {
+                return Err(self.protocol_error(CLOSE_PROTOCOL_ERROR, "Reserved bits set or unmasked client frame"));
+            }
+            if opcode >= OP_CLOSE && (!fin || len > 125) {
+                return Err(self.protocol_error(CLOSE_PROTOCOL_ERROR, "Fragmented or oversized control frame"));
+            }
+            if len > MAX_FRAME_SIZE {
+                return Err(self.protocol_error(CLOSE_TOO_BIG, &format!("Frame exceeds {} bytes", MAX_FRAME_SIZE)));
+            }
+            let mut mask: [u8; 4] = [0; 4];
+            self.read_raw(reader, &mut mask)?;
+            let mut payload: Vec<u8> = vec![0; len as usize];
+            self.read_raw(reader, &mut payload)?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+            match opcode {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => return Ok(Some(payload)),
+                OP_PING => self.send_frame(OP_PONG, &payload)?,
+                OP_PONG => {},
+                OP_CLOSE => {
+                    /* echo the status code back and end the session */
+                    let _ = self.send_frame(OP_CLOSE, payload.get(..2).unwrap_or(&[]));
+                    return Ok(None);
+                },
+                _ => return Err(self.protocol_error(CLOSE_PROTOCOL_ERROR, &format!("Unknown opcode {:#x}", opcode)))
+            }
+        }
+    }
+}
+
+impl Read for &WebSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader: MutexGuard<FrameReader> = lock(&self.reader);
+        while reader.pos >= reader.payload.len() {
+            match self.read_frame(&mut reader)? {
+                Some(payload) => {
+                    reader.payload = payload;
+                    reader.pos = 0;
+                },
+                None => return Ok(0)
+            }
+        }
+        let bytes: usize = buf.len().min(reader.payload.len() - reader.pos);
+        buf[..bytes].copy_from_slice(&reader.payload[reader.pos..reader.pos + bytes]);
+        reader.pos += bytes;
+        Ok(bytes)
+    }
+}
+
+impl Write for &WebSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_frame(OP_TEXT, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut inner: &ServerStream = &self.inner;
+        inner.flush()
+    }
+}